@@ -0,0 +1,255 @@
+// Metallic-roughness BRDF following bevy_pbr::lighting, so the ray tracer and the rasterizer
+// shade the same StandardMaterial alike
+
+const PI: f32 = 3.141592653589793;
+
+struct Material {
+    color: vec4<f32>,
+    // rgb in nits, alpha is the emissive exposure weight
+    emissive: vec4<f32>,
+    metallic: f32,
+    perceptual_roughness: f32,
+    reflectance: f32,
+    specular_transmission: f32,
+    ior: f32,
+    clearcoat: f32,
+    clearcoat_perceptual_roughness: f32,
+    // hits on texels whose alpha is below this are skipped, zero unless alpha is masked
+    alpha_cutoff: f32,
+    // absorption coefficient of the volume behind the surface, per world unit
+    absorption: vec3<f32>,
+    // layers of the texture array, NO_TEXTURE where the material has none
+    base_color_texture: u32,
+    emissive_texture: u32,
+    metallic_roughness_texture: u32,
+    normal_map_texture: u32,
+    flags: u32,
+    uv_transform: mat3x3<f32>,
+}
+
+const NO_TEXTURE: u32 = 0xffffffffu;
+const FLIP_NORMAL_MAP_Y: u32 = 1u;
+
+fn perceptual_roughness_to_roughness(perceptual_roughness: f32) -> f32 {
+    // same clamp as bevy to avoid precision problems on very smooth surfaces
+    let clamped = clamp(perceptual_roughness, 0.089, 1.0);
+    return clamped * clamped;
+}
+
+fn d_ggx(roughness: f32, n_dot_h: f32) -> f32 {
+    let one_minus_n_dot_h_squared = 1.0 - n_dot_h * n_dot_h;
+    let a = n_dot_h * roughness;
+    let k = roughness / (one_minus_n_dot_h_squared + a * a);
+    return k * k * (1.0 / PI);
+}
+
+fn v_smith_ggx_correlated(roughness: f32, n_dot_v: f32, n_dot_l: f32) -> f32 {
+    let a2 = roughness * roughness;
+    let lambda_v = n_dot_l * sqrt((n_dot_v - a2 * n_dot_v) * n_dot_v + a2);
+    let lambda_l = n_dot_v * sqrt((n_dot_l - a2 * n_dot_l) * n_dot_l + a2);
+    return 0.5 / (lambda_v + lambda_l);
+}
+
+fn f_schlick_vec(f0: vec3<f32>, f90: f32, v_dot_h: f32) -> vec3<f32> {
+    return f0 + (f90 - f0) * pow(1.0 - v_dot_h, 5.0);
+}
+
+fn f_schlick(f0: f32, f90: f32, v_dot_h: f32) -> f32 {
+    return f0 + (f90 - f0) * pow(1.0 - v_dot_h, 5.0);
+}
+
+fn fresnel(f0: vec3<f32>, l_dot_h: f32) -> vec3<f32> {
+    let f90 = saturate(dot(f0, vec3(50.0 * 0.33)));
+    return f_schlick_vec(f0, f90, l_dot_h);
+}
+
+fn fd_burley(roughness: f32, n_dot_v: f32, n_dot_l: f32, l_dot_h: f32) -> f32 {
+    let f90 = 0.5 + 2.0 * roughness * l_dot_h * l_dot_h;
+    let light_scatter = f_schlick(1.0, f90, n_dot_l);
+    let view_scatter = f_schlick(1.0, f90, n_dot_v);
+    return light_scatter * view_scatter * (1.0 / PI);
+}
+
+// analytic fit of the split sum DFG terms
+fn f_ab(perceptual_roughness: f32, n_dot_v: f32) -> vec2<f32> {
+    let c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+    let r = perceptual_roughness * c0 + c1;
+    let a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+    return vec2(-1.04, 1.04) * a004 + r.zw;
+}
+
+fn diffuse_color(material: Material) -> vec3<f32> {
+    return material.color.rgb * (1.0 - material.metallic) * (1.0 - material.specular_transmission);
+}
+
+fn specular_f0(material: Material) -> vec3<f32> {
+    let dielectric = 0.16 * material.reflectance * material.reflectance;
+    return dielectric * (1.0 - material.metallic) + material.color.rgb * material.metallic;
+}
+
+// reflected radiance towards `v` for unit irradiance arriving along `l`, cosine included
+fn evaluate_brdf(material: Material, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> vec3<f32> {
+    let n_dot_l = dot(n, l);
+    if n_dot_l <= 0.0 {
+        return vec3(0.0);
+    }
+    let n_dot_v = max(dot(n, v), 0.0001);
+    let h = normalize(l + v);
+    let n_dot_h = saturate(dot(n, h));
+    let l_dot_h = saturate(dot(l, h));
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let f0 = specular_f0(material);
+
+    let d = d_ggx(roughness, n_dot_h);
+    let vis = v_smith_ggx_correlated(roughness, n_dot_v, n_dot_l);
+    let f = fresnel(f0, l_dot_h);
+    // energy lost to single scattering is added back like bevy's specular_multiscatter
+    let multiscatter = 1.0 + f0 * (1.0 / f_ab(material.perceptual_roughness, n_dot_v).x - 1.0);
+    let specular = d * vis * f * multiscatter;
+    let diffuse = diffuse_color(material) * fd_burley(roughness, n_dot_v, n_dot_l, l_dot_h);
+    // the clear coat reflects like a dielectric with an ior of 1.5, the layers below get the rest
+    let coat_roughness = perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+    let coat_fresnel = f_schlick(0.04, 1.0, l_dot_h) * material.clearcoat;
+    let coat = d_ggx(coat_roughness, n_dot_h) * v_smith_ggx_correlated(coat_roughness, n_dot_v, n_dot_l) * coat_fresnel;
+    return ((diffuse + specular) * (1.0 - coat_fresnel) + coat) * n_dot_l;
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// rotation taking +z to `n` (Duff et al. 2017)
+fn orthonormal_basis(n: vec3<f32>) -> mat3x3<f32> {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    return mat3x3(
+        vec3(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        vec3(b, s + n.y * n.y * a, -n.y),
+        n,
+    );
+}
+
+fn sample_cosine_hemisphere(u: vec2<f32>) -> vec3<f32> {
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    return vec3(r * cos(phi), r * sin(phi), sqrt(max(1.0 - u.x, 0.0)));
+}
+
+// microfacet normal visible from `v`, both in tangent space (Heitz 2018)
+fn sample_ggx_vndf(v: vec3<f32>, roughness: f32, u: vec2<f32>) -> vec3<f32> {
+    let vh = normalize(vec3(roughness * v.x, roughness * v.y, v.z));
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = select(vec3(1.0, 0.0, 0.0), vec3(-vh.y, vh.x, 0.0) * inverseSqrt(len_sq), len_sq > 0.0);
+    let t2 = cross(vh, t1);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+    let nh = p1 * t1 + p2 * t2 + sqrt(max(1.0 - p1 * p1 - p2 * p2, 0.0)) * vh;
+    return normalize(vec3(roughness * nh.x, roughness * nh.y, max(nh.z, 0.0)));
+}
+
+fn smith_g1(roughness: f32, n_dot_v: f32) -> f32 {
+    let a2 = roughness * roughness;
+    return 2.0 * n_dot_v / (n_dot_v + sqrt(a2 + (1.0 - a2) * n_dot_v * n_dot_v));
+}
+
+// unpolarized fresnel reflectance of a dielectric, `eta` is the incident over the transmitted ior
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    return 0.5 * (rs * rs + rp * rp);
+}
+
+fn transmission_probability(material: Material) -> f32 {
+    return material.specular_transmission * (1.0 - material.metallic);
+}
+
+fn clearcoat_probability(material: Material, n_dot_v: f32) -> f32 {
+    return material.clearcoat * f_schlick(0.04, 1.0, n_dot_v);
+}
+
+fn specular_probability(material: Material, n_dot_v: f32) -> f32 {
+    let specular = luminance(fresnel(specular_f0(material), n_dot_v));
+    let diffuse = luminance(diffuse_color(material));
+    return specular / max(specular + diffuse, 0.0001);
+}
+
+// density of `sample_bsdf` picking `l` through one of the reflection lobes
+fn brdf_pdf(material: Material, n: vec3<f32>, v: vec3<f32>, l: vec3<f32>) -> f32 {
+    let n_dot_l = dot(n, l);
+    if n_dot_l <= 0.0 {
+        return 0.0;
+    }
+    let n_dot_v = max(dot(n, v), 0.0001);
+    let n_dot_h = saturate(dot(n, normalize(l + v)));
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let specular = ggx_reflection_pdf(roughness, n_dot_v, n_dot_h);
+    let diffuse = n_dot_l / PI;
+    let coat_roughness = perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+    let coat = ggx_reflection_pdf(coat_roughness, n_dot_v, n_dot_h);
+    let coat_probability = clearcoat_probability(material, n_dot_v);
+    let base = mix(diffuse, specular, specular_probability(material, n_dot_v));
+    let reflection = 1.0 - transmission_probability(material);
+    return reflection * mix(base, coat, coat_probability);
+}
+
+// density of reflecting a visible GGX normal sample into a direction with half vector `n_dot_h`
+fn ggx_reflection_pdf(roughness: f32, n_dot_v: f32, n_dot_h: f32) -> f32 {
+    return smith_g1(roughness, n_dot_v) * d_ggx(roughness, n_dot_h) / (4.0 * n_dot_v);
+}
+
+struct BsdfSample {
+    direction: vec3<f32>,
+    // bsdf times cosine over pdf
+    weight: vec3<f32>,
+    // zero for transmission, which is treated like a delta distribution
+    pdf: f32,
+}
+
+// picks a direction to continue a path arriving from `v`, with `n` facing `v`
+fn sample_bsdf(material: Material, n: vec3<f32>, v: vec3<f32>, front_face: bool, u: vec3<f32>) -> BsdfSample {
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let basis = orthonormal_basis(n);
+    var v_local = v * basis;
+    v_local = normalize(vec3(v_local.xy, max(v_local.z, 0.0001)));
+
+    let transmission = transmission_probability(material);
+    if u.z < transmission {
+        let h = basis * sample_ggx_vndf(v_local, roughness, u.xy);
+        let eta = select(material.ior, 1.0 / material.ior, front_face);
+        let refracted = refract(-v, h, eta);
+        let reflectance = fresnel_dielectric(saturate(dot(v, h)), eta);
+        if u.z / transmission < reflectance || all(refracted == vec3(0.0)) {
+            return BsdfSample(reflect(-v, h), vec3(1.0), 0.0);
+        }
+        return BsdfSample(normalize(refracted), material.color.rgb, 0.0);
+    }
+
+    let n_dot_v = max(dot(n, v), 0.0001);
+    let u_lobe = (u.z - transmission) / (1.0 - transmission);
+    let coat_probability = clearcoat_probability(material, n_dot_v);
+    let u_base = (u_lobe - coat_probability) / (1.0 - coat_probability);
+    var l: vec3<f32>;
+    if u_lobe < coat_probability {
+        let coat_roughness = perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+        l = reflect(-v, basis * sample_ggx_vndf(v_local, coat_roughness, u.xy));
+    } else if u_base < specular_probability(material, n_dot_v) {
+        l = reflect(-v, basis * sample_ggx_vndf(v_local, roughness, u.xy));
+    } else {
+        l = basis * sample_cosine_hemisphere(u.xy);
+    }
+    let pdf = brdf_pdf(material, n, v, l);
+    if pdf <= 0.0 {
+        return BsdfSample(l, vec3(0.0), 0.0);
+    }
+    return BsdfSample(l, evaluate_brdf(material, n, v, l) / pdf, pdf);
+}
@@ -1,19 +1,138 @@
 #import bevy_render::view::View
 #import bevy_render::globals::Globals
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import "shaders/brdf.wgsl"::{
+    Material, PI, NO_TEXTURE, FLIP_NORMAL_MAP_Y, brdf_pdf, evaluate_brdf, luminance,
+    orthonormal_basis, sample_bsdf, transmission_probability
+}
 
 @group(0) @binding(0) var<uniform> view: View;
 @group(0) @binding(1) var<uniform> globals: Globals;
 @group(0) @binding(2) var motion_vector_prepass_texture: texture_2d<f32>;
+@group(0) @binding(3) var accumulation_texture: texture_2d<f32>;
+@group(0) @binding(4) var environment_equirectangular: texture_2d<f32>;
+@group(0) @binding(5) var environment_cube: texture_cube<f32>;
+@group(0) @binding(6) var<uniform> environment: Environment;
+@group(0) @binding(7) var<storage> environment_cdf: array<f32>;
+@group(0) @binding(8) var<storage> reservoirs_in: array<Reservoir>;
+@group(0) @binding(9) var<storage, read_write> reservoirs_out: array<Reservoir>;
+@group(0) @binding(10) var<storage> gi_reservoirs_in: array<GiReservoir>;
+@group(0) @binding(11) var<storage, read_write> gi_reservoirs_out: array<GiReservoir>;
+@group(0) @binding(12) var depth_prepass_texture: texture_depth_2d;
+@group(0) @binding(13) var normal_prepass_texture: texture_2d<f32>;
+@group(0) @binding(14) var geometry_history_texture: texture_2d<f32>;
+@group(0) @binding(15) var<uniform> previous_view: PreviousView;
 @group(1) @binding(0) var<uniform> frame_count: u32;
 @group(1) @binding(1) var<storage> triangles: array<Triangle>;
 @group(1) @binding(2) var<storage> mesh_info: array<MeshInfo>;
 @group(1) @binding(3) var<storage> vertices: array<Vertex>;
 @group(1) @binding(4) var<storage> materials: array<Material>;
+@group(1) @binding(5) var<storage> bvh_nodes: array<BvhNode>;
+@group(1) @binding(6) var<storage> instances: array<MeshInstance>;
+@group(1) @binding(7) var<storage> tlas_nodes: array<BvhNode>;
+@group(1) @binding(8) var textures: texture_2d_array<f32>;
+@group(1) @binding(9) var texture_sampler: sampler;
+@group(1) @binding(10) var<uniform> settings: RayTracingSettings;
+@group(1) @binding(11) var<storage> lights: array<Light>;
+@group(1) @binding(12) var<storage> emissive_triangles: array<EmissiveTriangle>;
+@group(1) @binding(13) var<uniform> light_info: LightInfo;
+@group(1) @binding(14) var<uniform> gi_settings: RestirGiSettings;
+@group(1) @binding(15) var<storage> sobol_directions: array<u32>;
+@group(1) @binding(16) var<storage> blue_noise: array<u32>;
+
+// `BVH_STACK_SIZE` in bvh.rs, which keeps trees shallow enough for it
+const BVH_STACK_SIZE: u32 = 32u;
+
+struct RayTracingSettings {
+    max_bounces: u32,
+    russian_roulette_depth: u32,
+    restir_candidates: u32,
+    restir_spatial_samples: u32,
+    restir_spatial_radius: f32,
+    restir_max_history: u32,
+    reprojected_max_samples: u32,
+    seed: u32,
+    sampler_kind: u32,
+}
+
+const SAMPLER_PCG: u32 = 0u;
+const SAMPLER_SOBOL: u32 = 1u;
+const SAMPLER_BLUE_NOISE: u32 = 2u;
+const BLUE_NOISE_SIZE: u32 = 64u;
+const SOBOL_DIMENSIONS: u32 = 4u;
+
+struct PreviousView {
+    view_from_world: mat4x4<f32>,
+    clip_from_world: mat4x4<f32>,
+}
+
+struct RestirGiSettings {
+    enabled: u32,
+    spatial_samples: u32,
+    spatial_radius: f32,
+    max_history: u32,
+}
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_SPOT: u32 = 1u;
+const LIGHT_DIRECTIONAL: u32 = 2u;
+
+// color is the luminous intensity of point and spot lights and the illuminance of directional
+// ones, position is the direction towards directional lights
+struct Light {
+    color: vec3<f32>,
+    kind: u32,
+    position: vec3<f32>,
+    radius: f32,
+    spot_direction: vec3<f32>,
+    inverse_square_range: f32,
+    spot_scale: f32,
+    spot_offset: f32,
+}
+
+// an entry of the alias table emissive triangles are picked from in proportion to their power
+struct EmissiveTriangle {
+    instance: u32,
+    triangle: u32,
+    probability: f32,
+    alias_index: u32,
+}
+
+struct LightInfo {
+    light_count: u32,
+    emissive_count: u32,
+    emissive_power: f32,
+}
+
+const ENVIRONMENT_UNIFORM: u32 = 0u;
+const ENVIRONMENT_EQUIRECTANGULAR: u32 = 1u;
+const ENVIRONMENT_CUBE: u32 = 2u;
+const ENVIRONMENT_SKY: u32 = 3u;
+
+// Preetham sky for the current sun, in kcd/m² for a sun of 100000 lux
+struct Sky {
+    // Perez coefficients A to E for luminance and both chromaticities
+    perez: array<vec3<f32>, 5>,
+    zenith: vec3<f32>,
+    sun_direction: vec3<f32>,
+    ground: vec3<f32>,
+}
+
+// radiance seen by rays leaving the scene, `color` scales the image or sky if there is one
+struct Environment {
+    color: vec3<f32>,
+    kind: u32,
+    // grid `environment_cdf` was built over, zero when the environment is sampled uniformly
+    cdf_size: vec2<u32>,
+    sky: Sky,
+}
 
 struct Vertex{
     pos: vec3<f32>,
     norm: vec3<f32>,
+    uv: vec2<f32>,
+    // zero when the mesh has no tangents
+    tangent: vec4<f32>,
 }
 
 struct Triangle{
@@ -24,53 +143,108 @@ struct Triangle{
 struct MeshInfo{
     index: u32,
     count: u32,
-    material: u32,
+    root_node: u32,
     aabb_left_bottom: vec3<f32>,
     aabb_right_top: vec3<f32>,
 }
 
-struct Material {
-    color: vec4<f32>,
+struct MeshInstance {
+    world_from_object: mat4x4<f32>,
+    object_from_world: mat4x4<f32>,
+    mesh: u32,
+    material: u32,
 }
 
-var<private> uv: vec2<f32>;
+// interior nodes have a count of 0 and their children at `first` and `first + 1`
+struct BvhNode {
+    aabb_min: vec3<f32>,
+    first: u32,
+    aabb_max: vec3<f32>,
+    count: u32,
+}
 
 var<private> state: u32 = 1u;
+// what the Sobol and blue noise samplers draw the next number for, set by `seed_pixel`
+var<private> sample_pixel: vec2<u32>;
+var<private> sample_dimension: u32 = 0u;
+var<private> sample_stream: u32 = 0u;
+var<private> sample_seed: u32 = 0u;
+// albedo of the first surface the last path traced from the camera hit, one if it hit none
+var<private> primary_albedo: vec3<f32> = vec3(1.0);
 fn next_random() -> u32{
     state = state * 747796405u + 2891336453u;
     let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
     return (word >> 22u) ^ word;
 }
-fn hash(uv: vec2<f32>) -> u32 {
-    let random_seed = fract(sin(dot(uv, vec2(12.9898, 78.233))) * 43758.5453123);
-    return u32(random_seed * 4294967295.0);
+
+fn hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
 }
 
 fn randint(max: u32) -> u32 {
-    return u32(rand()*f32(max));
+    return min(u32(rand() * f32(max)), max - 1u);
 }
 
-fn rand() -> f32 {
-    return f32(next_random()) / 4294967295.0;
+fn laine_karras_permutation(input: u32, seed: u32) -> u32 {
+    var x = input + seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
 }
 
-fn rand_norm() -> f32 {
-    let theta = 2 * 3.1415926 * rand();
-    let rho = sqrt(-2 * log(rand()));
-    return rho * cos(theta);
+// random permutation of `x` that keeps its stratification (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
 }
 
-fn rand_dir() -> vec3<f32> {
-    return normalize(vec3(rand_norm(), rand_norm(), rand_norm()));
+fn sobol(index: u32, dimension: u32) -> u32 {
+    var result = 0u;
+    for (var bit = 0u; bit < 32u; bit++) {
+        if ((index >> bit) & 1u) != 0u {
+            result ^= sobol_directions[dimension * 32u + bit];
+        }
+    }
+    return result;
 }
 
-fn rand_hemi(norm: vec3<f32>) -> vec3<f32> {
-    let dir = rand_dir();
-    if dot(norm, dir) < 0 {
-        return -dir;
-    } else {
-        return dir;
+fn sobol_sample(dimension: u32) -> u32 {
+    let group_seed = hash(sample_seed ^ (dimension / SOBOL_DIMENSIONS));
+    let index = nested_uniform_scramble(frame_count, group_seed);
+    return nested_uniform_scramble(sobol(index, dimension % SOBOL_DIMENSIONS), hash(group_seed ^ dimension));
+}
+
+fn blue_noise_sample(dimension: u32) -> u32 {
+    let offset = hash(sample_stream ^ dimension);
+    let texel = (sample_pixel + vec2(offset, offset >> 16u)) % BLUE_NOISE_SIZE;
+    let rank = blue_noise[texel.y * BLUE_NOISE_SIZE + texel.x];
+    // ranks are spread over [0, 1) in 32 bit fixed point, then shifted by the golden ratio
+    // every sample
+    let bits = 32u - countTrailingZeros(BLUE_NOISE_SIZE * BLUE_NOISE_SIZE);
+    let value = (rank << bits) + (1u << (bits - 1u));
+    return value + frame_count * 2654435769u;
+}
+
+// uniform in [0, 1), the next dimension of the sampler the settings pick
+fn rand() -> f32 {
+    let dimension = sample_dimension;
+    sample_dimension += 1u;
+    var value: u32;
+    switch settings.sampler_kind {
+        case SAMPLER_SOBOL: {
+            value = sobol_sample(dimension);
+        }
+        case SAMPLER_BLUE_NOISE: {
+            value = blue_noise_sample(dimension);
+        }
+        default: {
+            value = next_random();
+        }
     }
+    return f32(value >> 8u) / 16777216.0;
 }
 
 struct Ray {
@@ -84,35 +258,92 @@ struct HitRecord {
     normal: vec3<f32>,
     t: f32,
     material: u32,
+    uv: vec2<f32>,
+    tangent: vec4<f32>,
 }
 
 fn no_hit() -> HitRecord {
-    return HitRecord(false, vec3(0.), vec3(0.), 0., 0);
+    return HitRecord(false, vec3(0.), vec3(0.), 0., 0, vec2(0.), vec4(0.));
 }
 
+// ids of emissive triangles and the environment, other light ids index `lights`
+const LIGHT_EMISSIVE: u32 = 0x40000000u;
+const LIGHT_ENVIRONMENT: u32 = 0x80000000u;
+const LIGHT_INDEX_MASK: u32 = 0x3fffffffu;
+const NO_LIGHT: u32 = 0xffffffffu;
+
+// a light sample picked out of a stream of candidates, kept per pixel so neighbours and the next
+// frame can reuse it
 struct Reservoir {
-    x: f32, // the current value
-    w: f32, // the current weight
+    uv: vec2<f32>, // random numbers the point on the light was sampled with
+    light: u32, // id of the light the sample is on
     w_total: f32, // cumulative weight
-    num: u32, // number of elements seen
+    w: f32, // contribution weight of the chosen sample
+    num: u32, // number of candidates seen
+    // distance to the surface the reservoir was built for, zero if the pixel saw none
+    depth: f32,
+    normal: u32, // of that surface, packed as snorm8
 }
 
 fn new_reservoir() -> Reservoir {
-    return Reservoir(0.0, 0.0, 0.0, 0);
+    return Reservoir(vec2(0.0), NO_LIGHT, 0.0, 0.0, 0u, 0.0, 0u);
 }
 
-fn update_reservoir(res: Reservoir, sample: f32, weight: f32) -> Reservoir {
-    let w_total = res.w_total + weight;
-    var x = 0.0;
-    var w = 0.0;
-    if (rand() < weight / w_total) {
-        x = sample;
-        w = weight;
-    } else {
-        x = res.x;
-        w = res.w;
+// streams in a sample standing for `count` candidates, returns true if it was picked
+fn update_reservoir(res: ptr<function, Reservoir>, light: u32, uv: vec2<f32>, weight: f32, count: u32) -> bool {
+    (*res).w_total += weight;
+    (*res).num += count;
+    if weight > 0.0 && rand() < weight / (*res).w_total {
+        (*res).light = light;
+        (*res).uv = uv;
+        return true;
     }
-    return Reservoir(x, w, w_total, res.num + 1);
+    return false;
+}
+
+// sets the contribution weight once every candidate was seen, `selected` is the target function
+// of the picked sample
+fn finalize_reservoir(res: ptr<function, Reservoir>, selected: f32) {
+    (*res).w = select(0.0, (*res).w_total / (f32((*res).num) * selected), selected > 0.0);
+}
+
+// a point the path from a pixel's first hit bounced off, for ReSTIR GI
+struct GiReservoir {
+    sample_position: vec3<f32>,
+    w_total: f32, // cumulative weight
+    sample_normal: vec3<f32>, // facing the surface the sample was found from
+    w: f32, // contribution weight of the chosen sample
+    // radiance leaving the sample point towards the surface it was found from
+    radiance: vec3<f32>,
+    num: u32, // number of candidates seen
+    // surface the reservoir was built for, with a depth of zero if the pixel saw none
+    position: vec3<f32>,
+    depth: f32,
+    // indirect light the resampling pass found for the surface
+    indirect: vec3<f32>,
+    normal: u32, // of the surface, packed as snorm8
+}
+
+fn new_gi_reservoir() -> GiReservoir {
+    return GiReservoir(vec3(0.0), 0.0, vec3(0.0), 0.0, vec3(0.0), 0u, vec3(0.0), 0.0, vec3(0.0), 0u);
+}
+
+// streams in the sample of `candidate` standing for `count` candidates, returns true if it was
+// picked
+fn update_gi_reservoir(res: ptr<function, GiReservoir>, candidate: GiReservoir, weight: f32, count: u32) -> bool {
+    (*res).w_total += weight;
+    (*res).num += count;
+    if weight > 0.0 && rand() < weight / (*res).w_total {
+        (*res).sample_position = candidate.sample_position;
+        (*res).sample_normal = candidate.sample_normal;
+        (*res).radiance = candidate.radiance;
+        return true;
+    }
+    return false;
+}
+
+fn finalize_gi_reservoir(res: ptr<function, GiReservoir>, selected: f32) {
+    (*res).w = select(0.0, (*res).w_total / (f32((*res).num) * selected), selected > 0.0);
 }
 
 fn ray_triangle(ray: Ray, tri: Triangle) -> HitRecord {
@@ -134,80 +365,1072 @@ fn ray_triangle(ray: Ray, tri: Triangle) -> HitRecord {
     let v = -dot(edge_ab, dao) * inv_det;
     let w = 1f - u - v;
 
-    return HitRecord(det >=1e-6 && dst >= 0f && u >= 0f && v >= 0f && w >= 0f, ray.origin + ray.direction * dst, 
-                    normalize(vertex_a.norm * w + vertex_b.norm * u + vertex_c.norm * v), dst, 0);
+    // triangles are two sided so paths can leave refractive objects again
+    return HitRecord(abs(det) >= 1e-12 && dst >= 0f && u >= 0f && v >= 0f && w >= 0f, ray.origin + ray.direction * dst, 
+                    normalize(vertex_a.norm * w + vertex_b.norm * u + vertex_c.norm * v), dst, 0,
+                    vertex_a.uv * w + vertex_b.uv * u + vertex_c.uv * v,
+                    vertex_a.tangent * w + vertex_b.tangent * u + vertex_c.tangent * v);
 }
 
-fn ray_aabb(ray: Ray, lb: vec3<f32>, rt: vec3<f32>) -> bool {
-    let ray_inv = 1.0 / ray.direction;
-    let t1 = (lb - ray.origin) * ray_inv;
-    let t2 = (rt - ray.origin) * ray_inv;
+// returns the distance to the box along the ray, or a huge value if it is missed
+fn ray_aabb(ray: Ray, inv_dir: vec3<f32>, lb: vec3<f32>, rt: vec3<f32>) -> f32 {
+    let t1 = (lb - ray.origin) * inv_dir;
+    let t2 = (rt - ray.origin) * inv_dir;
     let tmin = max(max(min(t1.x, t2.x), min(t1.y, t2.y)), min(t1.z, t2.z));
     let tmax = min(min(max(t1.x, t2.x), max(t1.y, t2.y)), max(t1.z, t2.z));
-    return tmax >= tmin;
+    if tmax >= max(tmin, 0.0) {
+        return tmin;
+    }
+    return 3.4e38;
 }
 
-fn hit_triangles(ray: Ray) -> HitRecord {
+// whether a hit on a surface with alpha masked `material` lands on an opaque texel
+fn alpha_test(material: Material, record: HitRecord) -> bool {
+    var alpha = material.color.a;
+    if material.base_color_texture != NO_TEXTURE {
+        alpha *= sample_texture(material.base_color_texture, texture_uv(material, record)).a;
+    }
+    return alpha >= material.alpha_cutoff;
+}
+
+// closest hit against a mesh's bottom-level BVH, the ray is in object space
+fn hit_mesh(ray: Ray, root: u32, material_index: u32, t_max: f32) -> HitRecord {
+    let material = materials[material_index];
+    let masked = material.alpha_cutoff > 0.0;
     var hit = no_hit();
-    let length = i32(arrayLength(&mesh_info));
-    for (var i = 0; i < length; i++) {
-        let mesh = mesh_info[i];
-        if !ray_aabb(ray, mesh.aabb_left_bottom, mesh.aabb_right_top) {
+    hit.t = t_max;
+    let inv_dir = 1.0 / ray.direction;
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_len = 1u;
+    stack[0] = root;
+    while stack_len > 0u {
+        stack_len--;
+        let index = stack[stack_len];
+        let node = bvh_nodes[index];
+        if node.count > 0u {
+            for (var i = node.first; i < node.first + node.count; i++) {
+                let record = ray_triangle(ray, triangles[i]);
+                if record.hit && record.t < hit.t && (!masked || alpha_test(material, record)) {
+                    hit = record;
+                }
+            }
             continue;
         }
-        for (var j = mesh.index; j < mesh.index + mesh.count; j++) {
-            var record = ray_triangle(ray, triangles[j]);
-            record.material = mesh.material;
-            if !record.hit {
-                continue;
-            }
-            if !hit.hit || hit.t > record.t {
-                hit = record;
-            }
+        // children always follow their parent, only the root of an empty tree breaks this
+        if node.first <= index {
+            continue;
+        }
+        let left = bvh_nodes[node.first];
+        let right = bvh_nodes[node.first + 1u];
+        let dist_left = ray_aabb(ray, inv_dir, left.aabb_min, left.aabb_max);
+        let dist_right = ray_aabb(ray, inv_dir, right.aabb_min, right.aabb_max);
+        var near = node.first;
+        var far = node.first + 1u;
+        var dist_near = dist_left;
+        var dist_far = dist_right;
+        if dist_right < dist_left {
+            near = node.first + 1u;
+            far = node.first;
+            dist_near = dist_right;
+            dist_far = dist_left;
+        }
+        // push the far child first so the near one is visited next
+        if dist_far < hit.t && stack_len < BVH_STACK_SIZE {
+            stack[stack_len] = far;
+            stack_len++;
+        }
+        if dist_near < hit.t && stack_len < BVH_STACK_SIZE {
+            stack[stack_len] = near;
+            stack_len++;
         }
     }
     return hit;
 }
 
-@fragment
-fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
-    uv = in.uv * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
-    state = hash(uv); 
-    var origin = view.world_position;
-    var dir = normalize(view.world_from_clip * vec4(uv, 0.0, 1.0)).xyz;
-    let motion_vector = textureLoad(motion_vector_prepass_texture, vec2<i32>(in.position.xy), 0);
-
-    // let samples = 8;
-    // var light = vec3(0.0);
-    // for (var j = 0; j < samples; j++) {
-    //     var col = vec3(1.0);
-    //     var ray = Ray(origin, dir + vec3(rand(), rand(), 0.0) * 0.0015);
-    //     for (var i = 0; i < 4; i++) {
-    //         let record = hit_scene(ray);
-    //         if record.hit {
-    //             ray = Ray(record.point, rand_hemi(record.normal));
-    //             // var light = sample_lights();
-    //             // col += light.col.rgb;
-    //             // let l = normalize(light.pos - record.point);
-    //             // col *= record.color * dot(record.normal, l);
-    //             light += record.light * col;
-    //             col *= record.color;
-    //             // light = record.color;
-    //         } else {
-    //             light += vec3(0.5, 0.71, 0.86) * col;
-    //             // light += vec3(0.3) * col;
-    //             break;
-    //         }
-    //     }
-    // }
-    let record = hit_triangles(Ray(origin, dir));
+fn hit_instance(ray: Ray, instance: MeshInstance, t_max: f32) -> HitRecord {
+    // the direction is left unnormalized so distances stay in world units
+    let object_ray = Ray(
+        (instance.object_from_world * vec4(ray.origin, 1.0)).xyz,
+        (instance.object_from_world * vec4(ray.direction, 0.0)).xyz,
+    );
+    var record = hit_mesh(object_ray, mesh_info[instance.mesh].root_node, instance.material, t_max);
     if record.hit {
-        if record.material >= 100 {
-            return vec4(0.5 + f32(record.material - 100u) / 6.0);
+        record.point = ray.origin + ray.direction * record.t;
+        // multiplying from the left uses the inverse transpose
+        record.normal = normalize((vec4(record.normal, 0.0) * instance.object_from_world).xyz);
+        if record.tangent.w != 0.0 {
+            let tangent = (instance.world_from_object * vec4(record.tangent.xyz, 0.0)).xyz;
+            record.tangent = vec4(normalize(tangent), record.tangent.w);
+        }
+        record.material = instance.material;
+    }
+    return record;
+}
+
+// closest hit closer than `t_max`
+fn hit_triangles(ray: Ray, t_max: f32) -> HitRecord {
+    var hit = no_hit();
+    hit.t = t_max;
+    let inv_dir = 1.0 / ray.direction;
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var stack_len = 1u;
+    stack[0] = 0u;
+    while stack_len > 0u {
+        stack_len--;
+        let index = stack[stack_len];
+        let node = tlas_nodes[index];
+        if node.count > 0u {
+            for (var i = node.first; i < node.first + node.count; i++) {
+                let record = hit_instance(ray, instances[i], hit.t);
+                if record.hit {
+                    hit = record;
+                }
+            }
+            continue;
+        }
+        if node.first <= index {
+            continue;
+        }
+        let left = tlas_nodes[node.first];
+        let right = tlas_nodes[node.first + 1u];
+        let dist_left = ray_aabb(ray, inv_dir, left.aabb_min, left.aabb_max);
+        let dist_right = ray_aabb(ray, inv_dir, right.aabb_min, right.aabb_max);
+        var near = node.first;
+        var far = node.first + 1u;
+        var dist_near = dist_left;
+        var dist_far = dist_right;
+        if dist_right < dist_left {
+            near = node.first + 1u;
+            far = node.first;
+            dist_near = dist_right;
+            dist_far = dist_left;
+        }
+        if dist_far < hit.t && stack_len < BVH_STACK_SIZE {
+            stack[stack_len] = far;
+            stack_len++;
+        }
+        if dist_near < hit.t && stack_len < BVH_STACK_SIZE {
+            stack[stack_len] = near;
+            stack_len++;
+        }
+    }
+    return hit;
+}
+
+fn sample_texture(index: u32, uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(textures, texture_sampler, uv, index, 0.0);
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, color <= vec3(0.04045));
+}
+
+fn texture_uv(material: Material, record: HitRecord) -> vec2<f32> {
+    return (material.uv_transform * vec3(record.uv, 1.0)).xy;
+}
+
+// the material with its textures sampled at the hit, like bevy's pbr_input_from_standard_material
+fn textured_material(base: Material, record: HitRecord) -> Material {
+    var material = base;
+    let uv = texture_uv(material, record);
+    if material.base_color_texture != NO_TEXTURE {
+        let texel = sample_texture(material.base_color_texture, uv);
+        material.color *= vec4(srgb_to_linear(texel.rgb), texel.a);
+    }
+    if material.emissive_texture != NO_TEXTURE {
+        let texel = sample_texture(material.emissive_texture, uv);
+        material.emissive = vec4(material.emissive.rgb * srgb_to_linear(texel.rgb), material.emissive.a);
+    }
+    if material.metallic_roughness_texture != NO_TEXTURE {
+        let texel = sample_texture(material.metallic_roughness_texture, uv);
+        material.metallic *= texel.b;
+        material.perceptual_roughness *= texel.g;
+    }
+    return material;
+}
+
+// the interpolated normal bent by the material's normal map
+fn shading_normal(material: Material, record: HitRecord) -> vec3<f32> {
+    if material.normal_map_texture == NO_TEXTURE || record.tangent.w == 0.0 {
+        return record.normal;
+    }
+    var nt = sample_texture(material.normal_map_texture, texture_uv(material, record)).rgb * 2.0 - 1.0;
+    if (material.flags & FLIP_NORMAL_MAP_Y) != 0u {
+        nt.y = -nt.y;
+    }
+    let n = record.normal;
+    let t = record.tangent.xyz;
+    let b = record.tangent.w * cross(n, t);
+    return normalize(nt.x * t + nt.y * b + nt.z * n);
+}
+
+struct LightSample {
+    direction: vec3<f32>,
+    distance: f32,
+    // exposed irradiance arriving along `direction` over the pdf of picking it
+    irradiance: vec3<f32>,
+    // solid angle density of the sample, zero for punctual lights which bsdf samples can't hit
+    pdf: f32,
+}
+
+// same windowed inverse square falloff as bevy_pbr::lighting::getDistanceAttenuation
+fn distance_attenuation(distance_square: f32, inverse_range_squared: f32) -> f32 {
+    let factor = distance_square * inverse_range_squared;
+    let smooth_factor = saturate(1.0 - factor * factor);
+    return smooth_factor * smooth_factor / max(distance_square, 0.0001);
+}
+
+fn sample_light(light: Light, point: vec3<f32>, u: vec2<f32>) -> LightSample {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return LightSample(light.position, 3.4e38, light.color * view.exposure, 0.0);
+    }
+    let to_light = light.position - point;
+    let distance_square = dot(to_light, to_light);
+    let distance = sqrt(distance_square);
+    let to_center = to_light / distance;
+    var irradiance = light.color * view.exposure * distance_attenuation(distance_square, light.inverse_square_range);
+    if light.kind == LIGHT_SPOT {
+        let attenuation = saturate(dot(-light.spot_direction, to_center) * light.spot_scale + light.spot_offset);
+        irradiance *= attenuation * attenuation;
+    }
+    let sin2_max = light.radius * light.radius / distance_square;
+    if sin2_max < 1e-6 || sin2_max >= 1.0 {
+        // too small to cast soft shadows, or the point is inside the light
+        return LightSample(to_center, distance, irradiance, 0.0);
+    }
+
+    // lights with a radius are spheres, sample the cone they subtend uniformly
+    let cos_max = sqrt(1.0 - sin2_max);
+    let cos_theta = 1.0 - u.x * sin2_max / (1.0 + cos_max);
+    let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    let phi = 2.0 * PI * u.y;
+    let direction = orthonormal_basis(to_center) * vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+    let surface = distance * cos_theta - sqrt(max(light.radius * light.radius - distance_square * sin_theta * sin_theta, 0.0));
+    // radiance of I / (pi r^2) over the cone pdf of 1 / (2 pi (1 - cos_max))
+    return LightSample(direction, surface, irradiance * 2.0 / (1.0 + cos_max), 0.0);
+}
+
+fn has_environment() -> bool {
+    return environment.kind != ENVIRONMENT_UNIFORM || any(environment.color > vec3(0.0));
+}
+
+// next event estimation splits its samples evenly between punctual lights, emissive triangles and
+// the environment, whichever of them the scene has
+fn light_categories() -> f32 {
+    return f32(light_info.light_count > 0u) + f32(light_info.emissive_count > 0u) + f32(has_environment());
+}
+
+fn emissive_selection_probability() -> f32 {
+    return select(0.0, 1.0 / light_categories(), light_info.emissive_count > 0u);
+}
+
+fn environment_selection_probability() -> f32 {
+    return select(0.0, 1.0 / light_categories(), has_environment());
+}
+
+// solid angle density of next event estimation reaching a point on an emitter whose material has
+// the given emissive color, `distance` and `cos_light` are seen from the shaded point
+fn emissive_pdf(emissive: vec3<f32>, distance: f32, cos_light: f32) -> f32 {
+    if light_info.emissive_count == 0u {
+        return 0.0;
+    }
+    let area_pdf = luminance(emissive) / light_info.emissive_power;
+    return emissive_selection_probability() * area_pdf * distance * distance / max(abs(cos_light), 1e-6);
+}
+
+// a point on an emissive triangle
+struct EmitterPoint {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    // exposed emitted radiance
+    radiance: vec3<f32>,
+    // untextured emissive color of the material, which the triangle was picked by
+    factor: vec3<f32>,
+}
+
+// picks an entry of the emissive alias table
+fn pick_emitter() -> u32 {
+    let index = randint(light_info.emissive_count);
+    if rand() >= emissive_triangles[index].probability {
+        return emissive_triangles[index].alias_index;
+    }
+    return index;
+}
+
+// uniform point on an emissive triangle for the random numbers `u`
+fn emitter_point(index: u32, u: vec2<f32>) -> EmitterPoint {
+    let emitter = emissive_triangles[index];
+    let instance = instances[emitter.instance];
+    let tri = triangles[emitter.triangle];
+    let a = vertices[tri.pos_a];
+    let b = vertices[tri.pos_b];
+    let c = vertices[tri.pos_c];
+    let pos_a = (instance.world_from_object * vec4(a.pos, 1.0)).xyz;
+    let pos_b = (instance.world_from_object * vec4(b.pos, 1.0)).xyz;
+    let pos_c = (instance.world_from_object * vec4(c.pos, 1.0)).xyz;
+
+    let r = sqrt(u.x);
+    let weights = vec3(1.0 - r, r * (1.0 - u.y), r * u.y);
+    let position = pos_a * weights.x + pos_b * weights.y + pos_c * weights.z;
+    let normal = normalize(cross(pos_b - pos_a, pos_c - pos_a));
+
+    let material = materials[instance.material];
+    var record = no_hit();
+    record.uv = a.uv * weights.x + b.uv * weights.y + c.uv * weights.z;
+    let emissive = textured_material(material, record).emissive;
+    let radiance = emissive.rgb * mix(1.0, view.exposure, emissive.a);
+    return EmitterPoint(position, normal, radiance, material.emissive.rgb);
+}
+
+fn sample_emissive(point: vec3<f32>) -> LightSample {
+    let emitter = emitter_point(pick_emitter(), vec2(rand(), rand()));
+    let to_light = emitter.position - point;
+    let distance = length(to_light);
+    let direction = to_light / distance;
+    let pdf = emissive_pdf(emitter.factor, distance, dot(emitter.normal, direction));
+    return LightSample(direction, distance, emitter.radiance / pdf, pdf);
+}
+
+// inverse of `equirectangular_direction`
+fn direction_to_uv(direction: vec3<f32>) -> vec2<f32> {
+    return vec2(atan2(direction.z, direction.x) / (2.0 * PI) + 0.5, acos(clamp(direction.y, -1.0, 1.0)) / PI);
+}
+
+fn equirectangular_direction(uv: vec2<f32>) -> vec3<f32> {
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let theta = uv.y * PI;
+    return vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+}
+
+// same as PreethamSky::radiance
+fn sky_radiance(sky: Sky, direction: vec3<f32>) -> vec3<f32> {
+    if direction.y < 0.0 {
+        return sky.ground;
+    }
+    let cos_theta = max(direction.y, 1e-3);
+    let cos_gamma = clamp(dot(direction, sky.sun_direction), -1.0, 1.0);
+    let gamma = acos(cos_gamma);
+    let perez = (1.0 + sky.perez[0] * exp(sky.perez[1] / cos_theta))
+        * (1.0 + sky.perez[2] * exp(sky.perez[3] * gamma) + sky.perez[4] * cos_gamma * cos_gamma);
+    let xy_luminance = sky.zenith * perez;
+    let x = xy_luminance.y;
+    let y = xy_luminance.z;
+    let xyz = vec3(x / y, 1.0, (1.0 - x - y) / y) * xy_luminance.x;
+    let xyz_to_rgb = mat3x3(
+        3.2406, -0.9689, 0.0557,
+        -1.5372, 1.8758, -0.2040,
+        -0.4986, 0.0415, 1.0570,
+    );
+    return max(xyz_to_rgb * xyz, vec3(0.0));
+}
+
+// exposed radiance arriving from the environment along the opposite of `direction`
+fn environment_radiance(direction: vec3<f32>) -> vec3<f32> {
+    var color = environment.color * view.exposure;
+    if environment.kind == ENVIRONMENT_EQUIRECTANGULAR {
+        let size = textureDimensions(environment_equirectangular);
+        let texel = min(vec2<u32>(direction_to_uv(direction) * vec2<f32>(size)), size - 1u);
+        color *= textureLoad(environment_equirectangular, texel, 0).rgb;
+    } else if environment.kind == ENVIRONMENT_CUBE {
+        // bevy's cubemaps are left handed
+        color *= textureSampleLevel(environment_cube, texture_sampler, direction * vec3(1.0, 1.0, -1.0), 0.0).rgb;
+    } else if environment.kind == ENVIRONMENT_SKY {
+        color *= sky_radiance(environment.sky, direction);
+    }
+    return color;
+}
+
+// density of picking a cell of the sampling grid, `row` is the offset of its cdf
+fn cdf_cell_pdf(row: u32, cell: vec2<u32>) -> f32 {
+    let marginal = environment_cdf[cell.y + 1u] - environment_cdf[cell.y];
+    let conditional = environment_cdf[row + cell.x + 1u] - environment_cdf[row + cell.x];
+    return marginal * conditional;
+}
+
+// solid angle density of sampling `direction` from the environment tables alone
+fn environment_pdf(direction: vec3<f32>) -> f32 {
+    let size = environment.cdf_size;
+    if size.x == 0u {
+        return 1.0 / (4.0 * PI);
+    }
+    let sin_theta = sqrt(max(1.0 - direction.y * direction.y, 0.0));
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+    let cell = min(vec2<u32>(direction_to_uv(direction) * vec2<f32>(size)), size - 1u);
+    let row = size.y + 1u + cell.y * (size.x + 1u);
+    // cells are uniform in uv, which covers 2 pi^2 sin(theta) steradians per unit area
+    return cdf_cell_pdf(row, cell) * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+}
+
+// solid angle density of next event estimation picking `direction` towards the environment
+fn environment_light_pdf(direction: vec3<f32>) -> f32 {
+    return environment_selection_probability() * environment_pdf(direction);
+}
+
+// last of the `count` entries of the cdf at `start` that isn't above `value`
+fn search_cdf(start: u32, count: u32, value: f32) -> u32 {
+    var low = 0u;
+    var high = count;
+    while high - low > 1u {
+        let middle = (low + high) / 2u;
+        if environment_cdf[start + middle] <= value {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+fn uniform_sphere(u: vec2<f32>) -> vec3<f32> {
+    let z = 1.0 - 2.0 * u.x;
+    let r = sqrt(max(1.0 - z * z, 0.0));
+    let phi = 2.0 * PI * u.y;
+    return vec3(r * cos(phi), z, r * sin(phi));
+}
+
+// direction the environment tables map the random numbers `u` to
+fn environment_direction(u: vec2<f32>) -> vec3<f32> {
+    let size = environment.cdf_size;
+    if size.x == 0u {
+        return uniform_sphere(u);
+    }
+    let y = search_cdf(0u, size.y, u.y);
+    let row = size.y + 1u + y * (size.x + 1u);
+    let x = search_cdf(row, size.x, u.x);
+    // continue uniformly inside the cell
+    let low = vec2(environment_cdf[row + x], environment_cdf[y]);
+    let high = vec2(environment_cdf[row + x + 1u], environment_cdf[y + 1u]);
+    let offset = saturate((u - low) / max(high - low, vec2(1e-12)));
+    return equirectangular_direction((vec2(f32(x), f32(y)) + offset) / vec2<f32>(size));
+}
+
+fn sample_environment(u: vec2<f32>) -> LightSample {
+    let direction = environment_direction(u);
+    let pdf = environment_light_pdf(direction);
+    if pdf <= 0.0 {
+        return LightSample(direction, 3.4e38, vec3(0.0), 0.0);
+    }
+    return LightSample(direction, 3.4e38, environment_radiance(direction) / pdf, pdf);
+}
+
+// picks an emissive triangle, the environment or a punctual light to sample
+fn sample_next_event(point: vec3<f32>) -> LightSample {
+    let emissive_probability = emissive_selection_probability();
+    let environment_probability = environment_selection_probability();
+    let u = rand();
+    if u < emissive_probability {
+        return sample_emissive(point);
+    }
+    if u < emissive_probability + environment_probability {
+        return sample_environment(vec2(rand(), rand()));
+    }
+    var sample = sample_light(lights[randint(light_info.light_count)], point, vec2(rand(), rand()));
+    sample.irradiance *= f32(light_info.light_count) / (1.0 - emissive_probability - environment_probability);
+    return sample;
+}
+
+// a point on a light seen from a shading point, with its density in the light's own measure:
+// area for spheres and emissive triangles, solid angle for the environment and none for punctual
+// and directional lights
+struct LightPoint {
+    direction: vec3<f32>,
+    distance: f32,
+    // exposed radiance arriving from the point, times the geometry term for area lights
+    irradiance: vec3<f32>,
+    // density next event estimation picks the point with, zero if the light doesn't exist
+    pdf: f32,
+}
+
+// picks the id of a light the same way `sample_next_event` picks a light
+fn pick_light() -> u32 {
+    let emissive_probability = emissive_selection_probability();
+    let u = rand();
+    if u < emissive_probability {
+        return LIGHT_EMISSIVE | pick_emitter();
+    }
+    if u < emissive_probability + environment_selection_probability() {
+        return LIGHT_ENVIRONMENT;
+    }
+    return randint(light_info.light_count);
+}
+
+// point the random numbers `u` map to on a light, unlike `sample_light` the mapping doesn't
+// depend on the shading point so reservoirs can share samples
+fn light_point(light_id: u32, u: vec2<f32>, point: vec3<f32>) -> LightPoint {
+    let none = LightPoint(vec3(0.0, 1.0, 0.0), 0.0, vec3(0.0), 0.0);
+    let index = light_id & LIGHT_INDEX_MASK;
+    if light_id == LIGHT_ENVIRONMENT {
+        if !has_environment() {
+            return none;
+        }
+        let direction = environment_direction(u);
+        return LightPoint(direction, 3.4e38, environment_radiance(direction), environment_light_pdf(direction));
+    }
+    if (light_id & LIGHT_EMISSIVE) != 0u {
+        if index >= light_info.emissive_count {
+            return none;
+        }
+        let emitter = emitter_point(index, u);
+        let to_light = emitter.position - point;
+        let distance_square = dot(to_light, to_light);
+        let direction = to_light / sqrt(distance_square);
+        let geometry = abs(dot(emitter.normal, direction)) / distance_square;
+        let pdf = emissive_selection_probability() * luminance(emitter.factor) / light_info.emissive_power;
+        return LightPoint(direction, sqrt(distance_square), emitter.radiance * geometry, pdf);
+    }
+    if index >= light_info.light_count {
+        return none;
+    }
+    let light = lights[index];
+    let selection = (1.0 - emissive_selection_probability() - environment_selection_probability()) / f32(light_info.light_count);
+    if light.kind == LIGHT_DIRECTIONAL {
+        return LightPoint(light.position, 3.4e38, light.color * view.exposure, selection);
+    }
+    var intensity = light.color * view.exposure;
+    if light.kind == LIGHT_SPOT {
+        let to_center = normalize(light.position - point);
+        let attenuation = saturate(dot(-light.spot_direction, to_center) * light.spot_scale + light.spot_offset);
+        intensity *= attenuation * attenuation;
+    }
+    if light.radius <= 0.0 {
+        let to_light = light.position - point;
+        let distance_square = dot(to_light, to_light);
+        let distance = sqrt(distance_square);
+        let irradiance = intensity * distance_attenuation(distance_square, light.inverse_square_range);
+        return LightPoint(to_light / distance, distance, irradiance, selection);
+    }
+
+    // uniform point on the sphere, the half facing away gets no weight
+    let normal = uniform_sphere(u);
+    let to_light = light.position + normal * light.radius - point;
+    let distance_square = dot(to_light, to_light);
+    let distance = sqrt(distance_square);
+    let direction = to_light / distance;
+    // radiance of I / (pi r^2)
+    let radiance = intensity / (PI * light.radius * light.radius);
+    let irradiance = radiance * max(dot(normal, -direction), 0.0) * distance_attenuation(distance_square, light.inverse_square_range);
+    return LightPoint(direction, distance, irradiance, selection / (4.0 * PI * light.radius * light.radius));
+}
+
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    return pdf * pdf / (pdf * pdf + other * other);
+}
+
+// texel of a pixel of the viewport in the prepass and accumulation textures, which are the size
+// of the render target
+fn target_texel(pixel: vec2<u32>) -> vec2<u32> {
+    return pixel + vec2<u32>(view.viewport.xy);
+}
+
+fn pixel_index(pixel: vec2<u32>) -> u32 {
+    return pixel.x + pixel.y * u32(view.viewport.z);
+}
+
+// The accumulation count only indexes the low discrepancy sequences. They are scrambled anew
+// whenever it restarts, and independent numbers are seeded from the frame index, so neither
+// repeats while the accumulation keeps being reset.
+fn seed_pixel(pixel: vec2<u32>) {
+    let restarted = globals.frame_count - frame_count;
+    sample_pixel = pixel;
+    sample_dimension = 0u;
+    sample_stream = hash(settings.seed ^ hash(restarted));
+    sample_seed = hash(hash(pixel_index(pixel)) ^ sample_stream);
+    state = hash(sample_seed ^ globals.frame_count);
+}
+
+// switches to random numbers independent of the ones drawn so far, for passes that share the
+// camera ray with another
+fn decorrelate_samples(salt: u32) {
+    state = hash(state ^ salt);
+    sample_seed = hash(sample_seed ^ salt);
+    sample_stream = hash(sample_stream ^ salt);
+}
+
+// jittered ray through a pixel of the viewport
+fn camera_ray(pixel: vec2<u32>) -> Ray {
+    let jitter = vec2(rand(), rand());
+    let uv = (vec2<f32>(pixel) + jitter) / view.viewport.zw * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+    let direction = normalize((view.world_from_clip * vec4(uv, 0.0, 1.0)).xyz);
+    return Ray(view.world_position, direction);
+}
+
+fn restir_enabled() -> bool {
+    return settings.restir_candidates > 0u;
+}
+
+// unshadowed contribution of a light point to a surface, which reservoirs resample by
+fn restir_target(record: HitRecord, material: Material, normal: vec3<f32>, v: vec3<f32>, sample: LightPoint) -> f32 {
+    if sample.pdf <= 0.0 || dot(sample.direction, record.normal) <= 0.0 {
+        return 0.0;
+    }
+    return luminance(evaluate_brdf(material, normal, v, sample.direction) * sample.irradiance);
+}
+
+// whether a reservoir was built for a surface close enough to this one to share its sample,
+// given the reservoir's depth and packed normal
+fn similar_surface(reservoir_depth: f32, reservoir_normal: u32, depth: f32, normal: vec3<f32>) -> bool {
+    return reservoir_depth > 0.0 && abs(reservoir_depth - depth) < 0.1 * depth
+        && dot(unpack4x8snorm(reservoir_normal).xyz, normal) > 0.9;
+}
+
+// draws the ReSTIR candidates of a pixel's first hit and merges them with the reservoir the
+// pixel's surface had last frame
+@compute @workgroup_size(8, 8, 1)
+fn restir_candidates(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = id.xy;
+    if any(pixel >= vec2<u32>(view.viewport.zw)) {
+        return;
+    }
+    seed_pixel(pixel);
+    let ray = camera_ray(pixel);
+    // keep the candidates independent of the path the shading pass traces from the same hit
+    decorrelate_samples(0x2545f491u);
+    var reservoir = new_reservoir();
+    var record = hit_triangles(ray, 3.4e38);
+    if !record.hit {
+        reservoirs_out[pixel_index(pixel)] = reservoir;
+        return;
+    }
+    if dot(record.normal, ray.direction) > 0.0 {
+        record.normal = -record.normal;
+    }
+    let material = textured_material(materials[record.material], record);
+    let normal = shading_normal(material, record);
+
+    var selected = 0.0;
+    if light_categories() > 0.0 {
+        for (var i = 0u; i < settings.restir_candidates; i++) {
+            let light = pick_light();
+            let u = vec2(rand(), rand());
+            let sample = light_point(light, u, record.point);
+            let target_function = restir_target(record, material, normal, -ray.direction, sample);
+            if update_reservoir(&reservoir, light, u, target_function / max(sample.pdf, 1e-30), 1u) {
+                selected = target_function;
+            }
+        }
+    }
+
+    // last frame's reservoir, found through the motion vectors
+    let motion_vector = textureLoad(motion_vector_prepass_texture, target_texel(pixel), 0).xy;
+    let previous = vec2<f32>(pixel) + 0.5 - motion_vector * view.viewport.zw;
+    if all(previous >= vec2(0.0)) && all(previous < view.viewport.zw) {
+        let history = reservoirs_in[pixel_index(vec2<u32>(previous))];
+        if similar_surface(history.depth, history.normal, record.t, record.normal) {
+            let count = min(history.num, settings.restir_max_history * settings.restir_candidates);
+            let sample = light_point(history.light, history.uv, record.point);
+            let target_function = restir_target(record, material, normal, -ray.direction, sample);
+            if update_reservoir(&reservoir, history.light, history.uv, target_function * history.w * f32(count), count) {
+                selected = target_function;
+            }
+        }
+    }
+    finalize_reservoir(&reservoir, selected);
+    reservoir.depth = record.t;
+    reservoir.normal = pack4x8snorm(vec4(record.normal, 0.0));
+    reservoirs_out[pixel_index(pixel)] = reservoir;
+}
+
+// resamples the reservoirs of a pixel and its neighbours, then lights the pixel's first hit with
+// the sample that was picked
+fn restir_direct(pixel: vec2<u32>, record: HitRecord, material: Material, normal: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    var reservoir = new_reservoir();
+    var selected = 0.0;
+    let size = vec2<i32>(view.viewport.zw);
+    for (var i = 0u; i <= settings.restir_spatial_samples; i++) {
+        var neighbour = vec2<i32>(pixel);
+        if i > 0u {
+            let offset = (vec2(rand(), rand()) * 2.0 - 1.0) * settings.restir_spatial_radius;
+            neighbour = clamp(neighbour + vec2<i32>(offset), vec2(0), size - 1);
+        }
+        let candidate = reservoirs_in[pixel_index(vec2<u32>(neighbour))];
+        if !similar_surface(candidate.depth, candidate.normal, record.t, record.normal) {
+            continue;
+        }
+        let sample = light_point(candidate.light, candidate.uv, record.point);
+        let target_function = restir_target(record, material, normal, v, sample);
+        if update_reservoir(&reservoir, candidate.light, candidate.uv, target_function * candidate.w * f32(candidate.num), candidate.num) {
+            selected = target_function;
+        }
+    }
+    finalize_reservoir(&reservoir, selected);
+    reservoir.depth = record.t;
+    reservoir.normal = pack4x8snorm(vec4(record.normal, 0.0));
+
+    var radiance = vec3(0.0);
+    if reservoir.w > 0.0 {
+        let sample = light_point(reservoir.light, reservoir.uv, record.point);
+        let shadow_ray = Ray(record.point + record.normal * 1e-4, sample.direction);
+        if hit_triangles(shadow_ray, sample.distance - 2e-4).hit {
+            // an occluded sample isn't worth reusing
+            reservoir.w = 0.0;
+        } else {
+            radiance = evaluate_brdf(material, normal, v, sample.direction) * sample.irradiance * reservoir.w;
+        }
+    }
+    reservoirs_out[pixel_index(pixel)] = reservoir;
+    return radiance;
+}
+
+// ratio of the solid angle densities of a GI reservoir's sample seen from `point` and from the
+// surface the reservoir was built for, which moving the sample between them scales its weight by
+fn gi_jacobian(reservoir: GiReservoir, point: vec3<f32>) -> f32 {
+    let to_new = point - reservoir.sample_position;
+    let to_old = reservoir.position - reservoir.sample_position;
+    let cos_new = abs(dot(reservoir.sample_normal, normalize(to_new)));
+    let cos_old = abs(dot(reservoir.sample_normal, normalize(to_old)));
+    if cos_old <= 0.0 {
+        return 0.0;
+    }
+    let jacobian = cos_new / cos_old * dot(to_old, to_old) / max(dot(to_new, to_new), 1e-12);
+    // extreme ratios come from grazing or nearby sample points and mostly add noise
+    return select(0.0, jacobian, jacobian > 0.1 && jacobian < 10.0);
+}
+
+// light reflected towards the viewer by a GI sample, without visibility
+fn gi_contribution(record: HitRecord, material: Material, normal: vec3<f32>, v: vec3<f32>, sample: GiReservoir) -> vec3<f32> {
+    let direction = normalize(sample.sample_position - record.point);
+    if dot(direction, record.normal) <= 0.0 {
+        return vec3(0.0);
+    }
+    return evaluate_brdf(material, normal, v, direction) * sample.radiance;
+}
+
+// ReSTIR GI resamples the indirect light of surfaces without transmission
+fn gi_eligible(material: Material) -> bool {
+    return gi_settings.enabled != 0u && transmission_probability(material) <= 0.0;
+}
+
+// bounces a path off every pixel's first hit and merges the point it reached with the reservoir
+// the pixel's surface had last frame
+@compute @workgroup_size(8, 8, 1)
+fn restir_gi_candidates(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = id.xy;
+    if any(pixel >= vec2<u32>(view.viewport.zw)) {
+        return;
+    }
+    seed_pixel(pixel);
+    let ray = camera_ray(pixel);
+    decorrelate_samples(0x68e31da4u);
+    var reservoir = new_gi_reservoir();
+    var record = hit_triangles(ray, 3.4e38);
+    let front_face = dot(record.normal, ray.direction) < 0.0;
+    if !front_face {
+        record.normal = -record.normal;
+    }
+    let material = textured_material(materials[record.material], record);
+    if !record.hit || !gi_eligible(material) {
+        gi_reservoirs_out[pixel_index(pixel)] = reservoir;
+        return;
+    }
+    let normal = shading_normal(material, record);
+    let v = -ray.direction;
+
+    var selected = 0.0;
+    let bsdf_sample = sample_bsdf(material, normal, v, front_face, vec3(rand(), rand(), rand()));
+    var weight = 0.0;
+    var candidate = new_gi_reservoir();
+    if bsdf_sample.pdf > 0.0 && dot(bsdf_sample.direction, record.normal) > 0.0 {
+        let bounce_ray = Ray(record.point + record.normal * 1e-4, bsdf_sample.direction);
+        let sample_hit = hit_triangles(bounce_ray, 3.4e38);
+        if sample_hit.hit {
+            candidate.sample_position = sample_hit.point;
+            candidate.sample_normal = select(-sample_hit.normal, sample_hit.normal, dot(sample_hit.normal, bsdf_sample.direction) < 0.0);
+            candidate.radiance = trace_path(bounce_ray, pixel, 1u);
+            let target_function = luminance(gi_contribution(record, material, normal, v, candidate));
+            weight = target_function / bsdf_sample.pdf;
+            selected = target_function;
+        }
+    }
+    update_gi_reservoir(&reservoir, candidate, weight, 1u);
+
+    // last frame's reservoir, found through the motion vectors
+    let motion_vector = textureLoad(motion_vector_prepass_texture, target_texel(pixel), 0).xy;
+    let previous = vec2<f32>(pixel) + 0.5 - motion_vector * view.viewport.zw;
+    if all(previous >= vec2(0.0)) && all(previous < view.viewport.zw) {
+        let history = gi_reservoirs_in[pixel_index(vec2<u32>(previous))];
+        if similar_surface(history.depth, history.normal, record.t, record.normal) {
+            let count = min(history.num, gi_settings.max_history);
+            let target_function = luminance(gi_contribution(record, material, normal, v, history));
+            let history_weight = target_function * history.w * f32(count) * gi_jacobian(history, record.point);
+            if update_gi_reservoir(&reservoir, history, history_weight, count) {
+                selected = target_function;
+            }
+        }
+    }
+    finalize_gi_reservoir(&reservoir, selected);
+    reservoir.position = record.point;
+    reservoir.depth = record.t;
+    reservoir.normal = pack4x8snorm(vec4(record.normal, 0.0));
+    gi_reservoirs_out[pixel_index(pixel)] = reservoir;
+}
+
+// resamples the GI reservoirs of a pixel and its neighbours, then finds the indirect light of the
+// pixel's first hit from the sample that was picked
+@compute @workgroup_size(8, 8, 1)
+fn restir_gi_resample(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = id.xy;
+    if any(pixel >= vec2<u32>(view.viewport.zw)) {
+        return;
+    }
+    seed_pixel(pixel);
+    let ray = camera_ray(pixel);
+    decorrelate_samples(0x1b873593u);
+    var reservoir = new_gi_reservoir();
+    var record = hit_triangles(ray, 3.4e38);
+    if dot(record.normal, ray.direction) > 0.0 {
+        record.normal = -record.normal;
+    }
+    let material = textured_material(materials[record.material], record);
+    if !record.hit || !gi_eligible(material) {
+        gi_reservoirs_out[pixel_index(pixel)] = reservoir;
+        return;
+    }
+    let normal = shading_normal(material, record);
+    let v = -ray.direction;
+
+    var selected = 0.0;
+    let size = vec2<i32>(view.viewport.zw);
+    for (var i = 0u; i <= gi_settings.spatial_samples; i++) {
+        var neighbour = vec2<i32>(pixel);
+        if i > 0u {
+            let offset = (vec2(rand(), rand()) * 2.0 - 1.0) * gi_settings.spatial_radius;
+            neighbour = clamp(neighbour + vec2<i32>(offset), vec2(0), size - 1);
+        }
+        let candidate = gi_reservoirs_in[pixel_index(vec2<u32>(neighbour))];
+        if !similar_surface(candidate.depth, candidate.normal, record.t, record.normal) {
+            continue;
+        }
+        let target_function = luminance(gi_contribution(record, material, normal, v, candidate));
+        let weight = target_function * candidate.w * f32(candidate.num) * gi_jacobian(candidate, record.point);
+        if update_gi_reservoir(&reservoir, candidate, weight, candidate.num) {
+            selected = target_function;
+        }
+    }
+    finalize_gi_reservoir(&reservoir, selected);
+    reservoir.position = record.point;
+    reservoir.depth = record.t;
+    reservoir.normal = pack4x8snorm(vec4(record.normal, 0.0));
+
+    if reservoir.w > 0.0 {
+        let to_sample = reservoir.sample_position - record.point;
+        let distance = length(to_sample);
+        let shadow_ray = Ray(record.point + record.normal * 1e-4, to_sample / distance);
+        if hit_triangles(shadow_ray, distance - 1e-3).hit {
+            // an occluded sample isn't worth reusing
+            reservoir.w = 0.0;
+        } else {
+            reservoir.indirect = gi_contribution(record, material, normal, v, reservoir) * reservoir.w;
+        }
+    }
+    gi_reservoirs_out[pixel_index(pixel)] = reservoir;
+}
+
+// follows a path through the scene, returning the radiance it carries back to the camera
+//
+// Paths starting at a later bounce, like the ones ReSTIR GI traces off a first hit, leave out
+// light at their first hit that light sampling at the bounce before already found.
+fn trace_path(primary: Ray, pixel: vec2<u32>, first_bounce: u32) -> vec3<f32> {
+    var ray = primary;
+    var throughput = vec3(1.0);
+    var radiance = vec3(0.0);
+    // density the last bounce was sampled with, zero for the camera and delta lobes
+    var bsdf_pdf = 0.0;
+    // the last hit sampled every light this ray can find, like ReSTIR does for the first hit
+    var lights_sampled = first_bounce > 0u;
+    if first_bounce == 0u {
+        primary_albedo = vec3(1.0);
+    }
+    for (var bounce = first_bounce; bounce <= settings.max_bounces; bounce++) {
+        var record = hit_triangles(ray, 3.4e38);
+        if !record.hit {
+            var sky = environment_radiance(ray.direction);
+            if lights_sampled {
+                sky = vec3(0.0);
+            } else if bsdf_pdf > 0.0 {
+                sky *= power_heuristic(bsdf_pdf, environment_light_pdf(ray.direction));
+            }
+            radiance += throughput * sky;
+            if bounce == 0u && restir_enabled() {
+                reservoirs_out[pixel_index(pixel)] = new_reservoir();
+            }
+            break;
+        }
+        let front_face = dot(record.normal, ray.direction) < 0.0;
+        if !front_face {
+            record.normal = -record.normal;
+            // the path crossed the volume behind the surface to get here
+            throughput *= exp(-materials[record.material].absorption * record.t);
+        }
+        let material = textured_material(materials[record.material], record);
+        if bounce == 0u {
+            primary_albedo = material.color.rgb;
         }
-        return materials[record.material].color * dot(record.normal, vec3(0f, 1f, 0f));
+        var emitted = material.emissive.rgb * mix(1.0, view.exposure, material.emissive.a);
+        if lights_sampled {
+            emitted = vec3(0.0);
+        } else if bsdf_pdf > 0.0 && any(emitted > vec3(0.0)) {
+            // next event estimation could have found this emitter as well
+            let light_pdf = emissive_pdf(materials[record.material].emissive.rgb, record.t, dot(record.normal, ray.direction));
+            emitted *= power_heuristic(bsdf_pdf, light_pdf);
+        }
+        radiance += throughput * emitted;
+        let normal = shading_normal(material, record);
+
+        // indirect light of the first hit may come from ReSTIR GI, the rest of the path is left out
+        // and light sampling isn't weighted against bsdf samples that won't be taken
+        let gi_indirect = bounce == 0u && gi_eligible(material) && gi_reservoirs_in[pixel_index(pixel)].depth > 0.0;
+        let restir_lit = bounce == 0u && restir_enabled();
+        if restir_lit {
+            radiance += throughput * restir_direct(pixel, record, material, normal, -ray.direction);
+        } else if light_categories() > 0.0 {
+            let light_sample = sample_next_event(record.point);
+            let brdf = evaluate_brdf(material, normal, -ray.direction, light_sample.direction);
+            if any(brdf * light_sample.irradiance > vec3(0.0)) && dot(light_sample.direction, record.normal) > 0.0 {
+                let shadow_ray = Ray(record.point + record.normal * 1e-4, light_sample.direction);
+                if !hit_triangles(shadow_ray, light_sample.distance - 2e-4).hit {
+                    var weight = 1.0;
+                    if light_sample.pdf > 0.0 && !gi_indirect {
+                        weight = power_heuristic(light_sample.pdf, brdf_pdf(material, normal, -ray.direction, light_sample.direction));
+                    }
+                    radiance += throughput * brdf * light_sample.irradiance * weight;
+                }
+            }
+        }
+        if bounce == settings.max_bounces {
+            break;
+        }
+        if gi_indirect {
+            radiance += throughput * gi_reservoirs_in[pixel_index(pixel)].indirect;
+            break;
+        }
+
+        let sample = sample_bsdf(material, normal, -ray.direction, front_face, vec3(rand(), rand(), rand()));
+        throughput *= sample.weight;
+        bsdf_pdf = sample.pdf;
+        lights_sampled = restir_lit && sample.pdf > 0.0;
+        if all(throughput == vec3(0.0)) {
+            break;
+        }
+        if bounce >= settings.russian_roulette_depth {
+            let survival = clamp(max(throughput.r, max(throughput.g, throughput.b)), 0.05, 1.0);
+            if rand() >= survival {
+                break;
+            }
+            throughput /= survival;
+        }
+        // start just off the surface on the side the path continues on
+        let side = select(-1.0, 1.0, dot(sample.direction, record.normal) > 0.0);
+        ray = Ray(record.point + record.normal * side * 1e-4, sample.direction);
+    }
+    return radiance;
+}
+
+// world space normal and view space depth the prepass found at a pixel, a depth of zero where
+// it saw no surface
+fn prepass_geometry(pixel: vec2<u32>) -> vec4<f32> {
+    let depth = textureLoad(depth_prepass_texture, target_texel(pixel), 0);
+    if depth <= 0.0 {
+        return vec4(0.0);
+    }
+    let uv = (vec2<f32>(pixel) + 0.5) / view.viewport.zw * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+    let view_position = view.view_from_clip * vec4(uv, depth, 1.0);
+    let normal = textureLoad(normal_prepass_texture, target_texel(pixel), 0).xyz * 2.0 - 1.0;
+    return vec4(normalize(normal), -view_position.z / view_position.w);
+}
+
+// whether last frame's geometry at a texel is the same surface this pixel sees
+fn same_geometry(previous: vec4<f32>, current: vec4<f32>) -> bool {
+    if current.w <= 0.0 || previous.w <= 0.0 {
+        return current.w <= 0.0 && previous.w <= 0.0;
+    }
+    return abs(previous.w - current.w) < 0.1 * current.w && dot(previous.xyz, current.xyz) > 0.9;
+}
+
+// last frame's average where the surface seen by a pixel was, with the samples it holds in alpha
+//
+// The motion vectors find surfaces, pixels that saw no surface find the sky through the previous
+// view. The four texels around that point are filtered bilinearly, skipping the ones that saw a
+// different surface, and none are left when the surface was disoccluded.
+fn reproject_history(pixel: vec2<u32>, geometry: vec4<f32>) -> vec4<f32> {
+    if frame_count == 0u {
+        return vec4(0.0);
+    }
+    var previous = vec2<f32>(pixel) + 0.5;
+    if geometry.w > 0.0 {
+        previous -= textureLoad(motion_vector_prepass_texture, target_texel(pixel), 0).xy * view.viewport.zw;
     } else {
+        let uv = previous / view.viewport.zw * vec2(2.0, -2.0) + vec2(-1.0, 1.0);
+        let direction = (view.world_from_clip * vec4(uv, 0.0, 1.0)).xyz;
+        let clip = previous_view.clip_from_world * vec4(direction, 0.0);
+        if clip.w <= 0.0 {
+            return vec4(0.0);
+        }
+        previous = (clip.xy / clip.w * vec2(0.5, -0.5) + 0.5) * view.viewport.zw;
+    }
+    let moved = any(abs(previous - (vec2<f32>(pixel) + 0.5)) > vec2(1e-3));
+
+    let corner = previous - 0.5;
+    let base = vec2<i32>(floor(corner));
+    let fraction = corner - floor(corner);
+    var history = vec4(0.0);
+    var total = 0.0;
+    for (var i = 0u; i < 4u; i++) {
+        let offset = vec2(i & 1u, i >> 1u);
+        let texel = base + vec2<i32>(offset);
+        if any(texel < vec2(0)) || any(texel >= vec2<i32>(view.viewport.zw)) {
+            continue;
+        }
+        let stored = target_texel(vec2<u32>(texel));
+        if !same_geometry(textureLoad(geometry_history_texture, stored, 0), geometry) {
+            continue;
+        }
+        let bilinear = select(1.0 - fraction, fraction, offset == vec2(1u));
+        let weight = bilinear.x * bilinear.y;
+        history += textureLoad(accumulation_texture, stored, 0) * weight;
+        total += weight;
+    }
+    if total < 1e-3 {
         return vec4(0.0);
     }
-    // return vec4(light / f32(samples), 1.0);
+    history /= total;
+    if moved {
+        history.a = min(history.a, f32(settings.reprojected_max_samples));
+    }
+    return history;
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // rgb holds the average, alpha how many samples it is made of
+    @location(1) accumulation: vec4<f32>,
+    @location(2) geometry: vec4<f32>,
+    // this frame's sample and the albedo it can be demodulated with, for the denoiser
+    @location(3) radiance: vec4<f32>,
+    @location(4) albedo: vec4<f32>,
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
+    let pixel = vec2<u32>(in.position.xy - view.viewport.xy);
+    seed_pixel(pixel);
+    var radiance = trace_path(camera_ray(pixel), pixel, 0u);
+    // a single broken sample would otherwise stay in the average until the next reset
+    if any(radiance != radiance) || any(abs(radiance) > vec3(3.4e38)) {
+        radiance = vec3(0.0);
+    }
+    let geometry = prepass_geometry(pixel);
+    let history = reproject_history(pixel, geometry);
+    let samples = history.a + 1.0;
+    let average = mix(history.rgb, radiance, 1.0 / samples);
+    return FragmentOutput(
+        vec4(average, 1.0),
+        vec4(average, samples),
+        geometry,
+        vec4(radiance, 1.0),
+        vec4(primary_albedo, 1.0),
+    );
 }
@@ -0,0 +1,248 @@
+// Spatiotemporal variance-guided filtering of the ray tracer's samples, after Schied et al. 2017
+
+#import bevy_render::view::View
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+struct SvgfSettings {
+    temporal: u32,
+    variance: u32,
+    alpha: f32,
+    moments_alpha: f32,
+    phi_color: f32,
+    phi_normal: f32,
+    phi_depth: f32,
+}
+
+@group(0) @binding(0) var<uniform> view: View;
+@group(0) @binding(1) var<uniform> settings: SvgfSettings;
+@group(0) @binding(2) var motion_vector_prepass_texture: texture_2d<f32>;
+@group(0) @binding(3) var radiance_texture: texture_2d<f32>;
+@group(0) @binding(4) var albedo_texture: texture_2d<f32>;
+// world space normal and view space depth each pixel saw last frame and this frame, with a depth
+// of zero where it saw no surface
+@group(0) @binding(5) var previous_geometry_texture: texture_2d<f32>;
+@group(0) @binding(6) var geometry_texture: texture_2d<f32>;
+// distance between the pixels an à-trous pass blends
+@group(0) @binding(7) var<uniform> atrous_step: u32;
+
+// temporal pass, the history holds the illumination with its length in alpha
+@group(1) @binding(0) var history_in: texture_2d<f32>;
+@group(1) @binding(1) var moments_in: texture_2d<f32>;
+@group(1) @binding(2) var history_out: texture_storage_2d<rgba32float, write>;
+@group(1) @binding(3) var moments_out: texture_storage_2d<rgba32float, write>;
+
+// variance and à-trous passes, the filter textures hold the illumination with its variance in
+// alpha
+@group(1) @binding(4) var filter_input: texture_2d<f32>;
+@group(1) @binding(5) var filter_moments: texture_2d<f32>;
+@group(1) @binding(6) var filter_output: texture_storage_2d<rgba32float, write>;
+
+// resolve pass
+@group(1) @binding(7) var filtered: texture_2d<f32>;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// albedo the illumination is divided by, kept away from zero so black surfaces don't blow up
+fn demodulation_albedo(pixel: vec2<u32>) -> vec3<f32> {
+    return max(textureLoad(albedo_texture, pixel, 0).rgb, vec3(1e-3));
+}
+
+// the textures are the size of the render target, like the prepass textures, and the passes
+// only touch the texels of the view's viewport
+fn in_bounds(pixel: vec2<i32>) -> bool {
+    let start = vec2<i32>(view.viewport.xy);
+    return all(pixel >= start) && all(pixel < start + vec2<i32>(view.viewport.zw));
+}
+
+// whether last frame's geometry at a texel is the same surface this pixel sees
+fn same_geometry(previous: vec4<f32>, current: vec4<f32>) -> bool {
+    return previous.w > 0.0 && abs(previous.w - current.w) < 0.1 * current.w
+        && dot(previous.xyz, current.xyz) > 0.9;
+}
+
+// averages this frame's illumination and the moments of its luminance with last frame's,
+// reprojected with the motion vectors and filtered bilinearly over the texels that saw the same
+// surface
+@compute @workgroup_size(8, 8, 1)
+fn temporal(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = id.xy + vec2<u32>(view.viewport.xy);
+    if !in_bounds(vec2<i32>(pixel)) {
+        return;
+    }
+    let illumination = textureLoad(radiance_texture, pixel, 0).rgb / demodulation_albedo(pixel);
+    let brightness = luminance(illumination);
+    let geometry = textureLoad(geometry_texture, pixel, 0);
+
+    var history = vec4(0.0);
+    var moments = vec2(0.0);
+    var total = 0.0;
+    if settings.temporal != 0u && geometry.w > 0.0 {
+        let motion_vector = textureLoad(motion_vector_prepass_texture, pixel, 0).xy;
+        let corner = vec2<f32>(pixel) - motion_vector * view.viewport.zw;
+        let base = vec2<i32>(floor(corner));
+        let fraction = corner - floor(corner);
+        for (var i = 0u; i < 4u; i++) {
+            let offset = vec2(i & 1u, i >> 1u);
+            let texel = base + vec2<i32>(offset);
+            if !in_bounds(texel) || !same_geometry(textureLoad(previous_geometry_texture, texel, 0), geometry) {
+                continue;
+            }
+            let bilinear = select(1.0 - fraction, fraction, offset == vec2(1u));
+            let weight = bilinear.x * bilinear.y;
+            history += textureLoad(history_in, texel, 0) * weight;
+            moments += textureLoad(moments_in, texel, 0).xy * weight;
+            total += weight;
+        }
+    }
+    if total > 1e-3 {
+        history /= total;
+        moments /= total;
+    } else {
+        history = vec4(0.0);
+    }
+
+    // short histories are averaged evenly, long ones exponentially
+    let history_length = history.a + 1.0;
+    let alpha = max(settings.alpha, 1.0 / history_length);
+    let moments_alpha = max(settings.moments_alpha, 1.0 / history_length);
+    let average = mix(history.rgb, illumination, alpha);
+    moments = mix(moments, vec2(brightness, brightness * brightness), moments_alpha);
+    textureStore(history_out, pixel, vec4(average, history_length));
+    textureStore(moments_out, pixel, vec4(moments, 0.0, 0.0));
+}
+
+// weight of a neighbour in the edge-avoiding filters, ignoring luminance
+fn geometry_weight(center: vec4<f32>, neighbour: vec4<f32>, depth_gradient: f32, distance: f32) -> f32 {
+    if neighbour.w <= 0.0 {
+        return 0.0;
+    }
+    let normal = pow(max(dot(center.xyz, neighbour.xyz), 0.0), settings.phi_normal);
+    let depth = exp(-abs(center.w - neighbour.w) / (settings.phi_depth * depth_gradient * distance + 1e-6));
+    return normal * depth;
+}
+
+// largest change of depth towards a neighbouring pixel
+fn depth_gradient(pixel: vec2<i32>, depth: f32) -> f32 {
+    var gradient = 0.0;
+    for (var i = 0u; i < 4u; i++) {
+        let offset = select(vec2(i32(i & 1u) * 2 - 1, 0), vec2(0, i32(i & 1u) * 2 - 1), i >= 2u);
+        let neighbour = pixel + offset;
+        if in_bounds(neighbour) {
+            let neighbour_depth = textureLoad(geometry_texture, neighbour, 0).w;
+            if neighbour_depth > 0.0 {
+                gradient = max(gradient, abs(neighbour_depth - depth));
+            }
+        }
+    }
+    return max(gradient, 1e-3 * depth);
+}
+
+// turns the moments into the variance of the illumination, estimating it from the neighbours
+// where there are too few frames of history to rely on
+@compute @workgroup_size(8, 8, 1)
+fn variance(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy) + vec2<i32>(view.viewport.xy);
+    if !in_bounds(pixel) {
+        return;
+    }
+    let history = textureLoad(filter_input, pixel, 0);
+    let moments = textureLoad(filter_moments, pixel, 0).xy;
+    let geometry = textureLoad(geometry_texture, pixel, 0);
+    var illumination = history.rgb;
+    var variance = max(moments.y - moments.x * moments.x, 0.0);
+    if settings.variance != 0u && history.a < 4.0 && geometry.w > 0.0 {
+        let gradient = depth_gradient(pixel, geometry.w);
+        var sum_illumination = vec3(0.0);
+        var sum_moments = vec2(0.0);
+        var total = 0.0;
+        for (var y = -3; y <= 3; y++) {
+            for (var x = -3; x <= 3; x++) {
+                let offset = vec2(x, y);
+                let neighbour = pixel + offset;
+                if !in_bounds(neighbour) {
+                    continue;
+                }
+                let weight = geometry_weight(geometry, textureLoad(geometry_texture, neighbour, 0), gradient, length(vec2<f32>(offset)));
+                sum_illumination += textureLoad(filter_input, neighbour, 0).rgb * weight;
+                sum_moments += textureLoad(filter_moments, neighbour, 0).xy * weight;
+                total += weight;
+            }
+        }
+        // the pixel itself always has full weight
+        illumination = sum_illumination / total;
+        let spatial_moments = sum_moments / total;
+        // young histories are less reliable than the estimate suggests
+        variance = max(spatial_moments.y - spatial_moments.x * spatial_moments.x, 0.0) * 4.0 / history.a;
+    }
+    textureStore(filter_output, pixel, vec4(illumination, variance));
+}
+
+// variance blurred over the 3x3 pixels around a pixel, steadier to compare luminance against
+fn blurred_variance(pixel: vec2<i32>) -> f32 {
+    var kernel = array(0.25, 0.5, 0.25);
+    var variance = 0.0;
+    var total = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let neighbour = pixel + vec2(x, y);
+            if in_bounds(neighbour) {
+                let weight = kernel[x + 1] * kernel[y + 1];
+                variance += textureLoad(filter_input, neighbour, 0).a * weight;
+                total += weight;
+            }
+        }
+    }
+    return variance / total;
+}
+
+// one pass of the edge-avoiding à-trous wavelet filter, blending pixels `atrous_step` apart
+// with a 5x5 B3 spline kernel
+@compute @workgroup_size(8, 8, 1)
+fn atrous(@builtin(global_invocation_id) id: vec3<u32>) {
+    let pixel = vec2<i32>(id.xy) + vec2<i32>(view.viewport.xy);
+    if !in_bounds(pixel) {
+        return;
+    }
+    let center = textureLoad(filter_input, pixel, 0);
+    let geometry = textureLoad(geometry_texture, pixel, 0);
+    if geometry.w <= 0.0 {
+        textureStore(filter_output, pixel, center);
+        return;
+    }
+    var kernel = array(1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+    let center_luminance = luminance(center.rgb);
+    let deviation = sqrt(blurred_variance(pixel));
+    let gradient = depth_gradient(pixel, geometry.w);
+    var illumination = vec3(0.0);
+    var variance = 0.0;
+    var total = 0.0;
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let offset = vec2(x, y) * i32(atrous_step);
+            let neighbour = pixel + offset;
+            if !in_bounds(neighbour) {
+                continue;
+            }
+            let sample = textureLoad(filter_input, neighbour, 0);
+            let brightness = exp(-abs(center_luminance - luminance(sample.rgb)) / (settings.phi_color * deviation + 1e-6));
+            var weight = kernel[x + 2] * kernel[y + 2] * brightness;
+            if any(offset != vec2(0)) {
+                weight *= geometry_weight(geometry, textureLoad(geometry_texture, neighbour, 0), gradient, length(vec2<f32>(offset)));
+            }
+            illumination += sample.rgb * weight;
+            variance += sample.a * weight * weight;
+            total += weight;
+        }
+    }
+    textureStore(filter_output, pixel, vec4(illumination / total, variance / (total * total)));
+}
+
+// multiplies the albedo back into the filtered illumination
+@fragment
+fn resolve(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<u32>(in.position.xy);
+    let illumination = textureLoad(filtered, pixel, 0).rgb;
+    return vec4(illumination * demodulation_albedo(pixel), 1.0);
+}
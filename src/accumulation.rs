@@ -0,0 +1,138 @@
+use bevy::{
+    ecs::entity::EntityHashMap,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::RenderSubGraph,
+        render_resource::{
+            Extent3d, Texture, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            TextureView, TextureViewDescriptor,
+        },
+        renderer::RenderDevice,
+    },
+};
+
+use crate::ray_tracing::{RayTracingGraph, RayTracingInfo};
+
+pub const ACCUMULATION_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+pub const GEOMETRY_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+pub const RADIANCE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+pub const ALBEDO_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// The running average of the samples traced for each pixel of a view, with how many samples
+/// it holds in alpha
+///
+/// The shader reads the previous frame's average from one texture and writes the new one to the
+/// other, they swap every frame. Moving views reproject last frame's average with the motion
+/// vectors, the normals and depths the pixels saw are kept in `geometry` alongside it so history
+/// of a different surface can be thrown away.
+///
+/// The denoiser reads this frame's sample from `radiance` and the albedo of the first surface
+/// each pixel hit from `albedo`.
+pub struct Accumulation {
+    pub textures: [Texture; 2],
+    pub views: [TextureView; 2],
+    pub geometry: [Texture; 2],
+    pub geometry_views: [TextureView; 2],
+    pub radiance: TextureView,
+    pub albedo: TextureView,
+    pub size: UVec2,
+    /// Frames rendered to the view, which of each pair of textures is written alternates with it
+    pub frame: u32,
+    exposure: f32,
+}
+
+impl Accumulation {
+    fn new(render_device: &RenderDevice, size: UVec2) -> Self {
+        let texture = |label, format| {
+            render_device.create_texture(&TextureDescriptor {
+                label: Some(label),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+        };
+        let textures = [0, 1].map(|_| texture("ray_tracing_accumulation", ACCUMULATION_FORMAT));
+        let geometry = [0, 1].map(|_| texture("ray_tracing_geometry_history", GEOMETRY_FORMAT));
+        let views = [0, 1].map(|i| textures[i].create_view(&TextureViewDescriptor::default()));
+        let geometry_views =
+            [0, 1].map(|i| geometry[i].create_view(&TextureViewDescriptor::default()));
+        Self {
+            textures,
+            views,
+            geometry,
+            geometry_views,
+            radiance: texture("ray_tracing_radiance", RADIANCE_FORMAT)
+                .create_view(&TextureViewDescriptor::default()),
+            albedo: texture("ray_tracing_albedo", ALBEDO_FORMAT)
+                .create_view(&TextureViewDescriptor::default()),
+            size,
+            frame: 0,
+            exposure: f32::NAN,
+        }
+    }
+
+    /// Index of the texture of each pair this frame writes to, the other one holds last frame's
+    pub fn write_index(&self) -> usize {
+        (self.frame & 1) as usize
+    }
+
+    /// The texture holding last frame's average and the one this frame writes to
+    pub fn read_write(&self) -> (&TextureView, &TextureView) {
+        let write = self.write_index();
+        (&self.views[1 - write], &self.views[write])
+    }
+
+    /// Same as [`Self::read_write`] for the geometry the averages were traced for
+    pub fn geometry_read_write(&self) -> (&TextureView, &TextureView) {
+        let write = self.write_index();
+        (&self.geometry_views[1 - write], &self.geometry_views[write])
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct AccumulationCache(pub EntityHashMap<Accumulation>);
+
+/// Keeps an accumulation target per ray traced view and restarts accumulating when one of them
+/// resizes or changes exposure
+///
+/// The textures are the size of the render target rather than of the viewport, they are color
+/// attachments of the pass that writes the target. Views that move keep accumulating, the shader
+/// reprojects their history.
+pub fn prepare_accumulation(
+    mut cache: ResMut<AccumulationCache>,
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    views: Query<(Entity, &ExtractedCamera)>,
+    render_device: Res<RenderDevice>,
+) {
+    let mut accumulations = EntityHashMap::default();
+    for (entity, camera) in &views {
+        if camera.render_graph != RayTracingGraph.intern() {
+            continue;
+        }
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        let mut accumulation = match cache.0.remove(&entity) {
+            Some(mut accumulation) if accumulation.size == size => {
+                accumulation.frame = accumulation.frame.wrapping_add(1);
+                accumulation
+            }
+            _ => Accumulation::new(&render_device, size),
+        };
+        if accumulation.exposure != camera.exposure {
+            accumulation.exposure = camera.exposure;
+            ray_tracing_info.reset_accumulation();
+        }
+        accumulations.insert(entity, accumulation);
+    }
+    cache.0 = accumulations;
+}
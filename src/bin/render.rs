@@ -0,0 +1,275 @@
+//! Renders a still with the ray tracer and writes it to a file, without opening a window
+//!
+//! Writes OpenEXR with linear radiance for `.exr` outputs and a tonemapped image otherwise. Runs
+//! on software Vulkan drivers, e.g. lavapipe with
+//! `VK_ICD_FILENAMES=/usr/share/vulkan/icd.d/lvp_icd.x86_64.json WGPU_BACKEND=vulkan`.
+
+use std::{path::PathBuf, str::FromStr};
+
+use bevy::{
+    core_pipeline::tonemapping::{DebandDither, Tonemapping},
+    prelude::*,
+    render::camera::{CameraRenderGraph, RenderTarget},
+    scene::SceneInstance,
+};
+use ray_tracing::{
+    demo::spawn_demo_scene,
+    gltf::RayTracedGltf,
+    obj::ObjPlugin,
+    offline::{headless_plugins, is_hdr_output, OfflineRender, OfflineRenderPlugin},
+    pbrt::PbrtPlugin,
+    ray_tracing::{RayTracingGraph, RayTracingPlugin, RayTracingSettings},
+    sampler::SamplerKind,
+    sky::PhysicalSky,
+};
+
+const USAGE: &str = "usage: render [options] <output.png|output.exr>
+
+options:
+    --width <pixels>       default 1280
+    --height <pixels>      default 720
+    --spp <samples>        samples per pixel, default 64
+    --camera <x,y,z>       camera position, default 0,3,5
+    --look-at <x,y,z>      point the camera looks at, default 0,0,0
+    --fov <degrees>        vertical field of view, default 45
+    --seed <number>        seed of the random numbers, default 0
+    --sampler <kind>       pcg, sobol or blue-noise, default pcg
+    --scene <asset path>   glTF, OBJ or pbrt scene to render, e.g. models/scene.glb#Scene0 or
+                           models/CornellBox.obj, instead of the demo scene
+    --gltf-camera          view the scene through its glTF or pbrt camera instead of --camera,
+                           --look-at and --fov";
+
+struct Args {
+    size: UVec2,
+    samples: u32,
+    camera: Vec3,
+    look_at: Vec3,
+    fov: f32,
+    scene: Option<String>,
+    gltf_camera: bool,
+    seed: u32,
+    sampler: SamplerKind,
+    output: PathBuf,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut size = UVec2::new(1280, 720);
+        let mut samples = 64;
+        let mut camera = Vec3::new(0.0, 3.0, 5.0);
+        let mut look_at = Vec3::ZERO;
+        let mut fov = 45.0;
+        let mut scene = None;
+        let mut gltf_camera = false;
+        let mut seed = 0;
+        let mut sampler = SamplerKind::default();
+        let mut output = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+            match arg.as_str() {
+                "--width" => size.x = parse(&value()?)?,
+                "--height" => size.y = parse(&value()?)?,
+                "--spp" => samples = parse(&value()?)?,
+                "--camera" => camera = parse_vec3(&value()?)?,
+                "--look-at" => look_at = parse_vec3(&value()?)?,
+                "--fov" => fov = parse(&value()?)?,
+                "--scene" => scene = Some(value()?),
+                "--gltf-camera" => gltf_camera = true,
+                "--seed" => seed = parse(&value()?)?,
+                "--sampler" => sampler = parse_sampler(&value()?)?,
+                "-h" | "--help" => return Err(String::new()),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ if output.is_none() => output = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {arg}")),
+            }
+        }
+        if size.min_element() == 0 || samples == 0 {
+            return Err("size and samples must be positive".to_string());
+        }
+        if gltf_camera && scene.is_none() {
+            return Err("--gltf-camera needs a --scene".to_string());
+        }
+        Ok(Self {
+            size,
+            samples,
+            camera,
+            look_at,
+            fov,
+            scene,
+            gltf_camera,
+            seed,
+            sampler,
+            output: output.ok_or("missing output path")?,
+        })
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {value}"))
+}
+
+fn parse_vec3(value: &str) -> Result<Vec3, String> {
+    let components = value
+        .split(',')
+        .map(|component| parse(component.trim()))
+        .collect::<Result<Vec<f32>, _>>()?;
+    match components[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("expected x,y,z, got {value}")),
+    }
+}
+
+fn parse_sampler(value: &str) -> Result<SamplerKind, String> {
+    match value {
+        "pcg" => Ok(SamplerKind::Pcg),
+        "sobol" => Ok(SamplerKind::Sobol),
+        "blue-noise" => Ok(SamplerKind::BlueNoise),
+        _ => Err(format!("unknown sampler {value}")),
+    }
+}
+
+#[derive(Resource)]
+struct Scene {
+    path: Option<String>,
+    gltf_camera: bool,
+}
+
+#[derive(Resource)]
+struct View {
+    camera: Vec3,
+    look_at: Vec3,
+    fov: f32,
+}
+
+fn main() -> AppExit {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            if !error.is_empty() {
+                eprintln!("{error}\n");
+            }
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let mut app = App::new();
+    app.add_plugins((
+        headless_plugins(),
+        RayTracingPlugin,
+        ObjPlugin,
+        PbrtPlugin,
+        OfflineRenderPlugin,
+    ));
+    let image = app
+        .world_mut()
+        .resource_mut::<Assets<Image>>()
+        .add(OfflineRender::target(
+            args.size,
+            is_hdr_output(&args.output),
+        ));
+    app.insert_resource(OfflineRender {
+        image,
+        samples: args.samples,
+        output: args.output,
+        ready: false,
+    })
+    .insert_resource(RayTracingSettings {
+        seed: args.seed,
+        sampler: args.sampler,
+        ..default()
+    })
+    .insert_resource(Scene {
+        path: args.scene,
+        gltf_camera: args.gltf_camera,
+    })
+    .insert_resource(View {
+        camera: args.camera,
+        look_at: args.look_at,
+        fov: args.fov,
+    })
+    .add_systems(Startup, setup)
+    .add_systems(Update, wait_for_scene)
+    .run()
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+    request: Res<OfflineRender>,
+    scene: Res<Scene>,
+    view: Res<View>,
+) {
+    // EXR keeps the linear radiance, everything else is tonemapped
+    let (tonemapping, deband_dither) = if is_hdr_output(&request.output) {
+        (Tonemapping::None, DebandDither::Disabled)
+    } else {
+        (Tonemapping::default(), DebandDither::default())
+    };
+    let camera = commands
+        .spawn((
+            Camera3dBundle {
+                camera: Camera {
+                    hdr: true,
+                    target: RenderTarget::Image(request.image.clone()),
+                    ..default()
+                },
+                camera_render_graph: CameraRenderGraph::new(RayTracingGraph),
+                projection: PerspectiveProjection {
+                    fov: view.fov.to_radians(),
+                    ..default()
+                }
+                .into(),
+                transform: Transform::from_translation(view.camera)
+                    .looking_at(view.look_at, Vec3::Y),
+                tonemapping,
+                deband_dither,
+                ..default()
+            },
+            PhysicalSky::default(),
+        ))
+        .id();
+    match &scene.path {
+        Some(path) => {
+            commands.spawn((
+                SceneBundle {
+                    scene: asset_server.load(path.clone()),
+                    ..default()
+                },
+                RayTracedGltf {
+                    camera: scene.gltf_camera.then_some(camera),
+                },
+            ));
+        }
+        None => {
+            spawn_demo_scene(&mut commands, &mut meshes, &mut materials);
+            // the sun of the camera's sky
+            commands.spawn(DirectionalLightBundle {
+                transform: Transform::from_xyz(1.0, 2.0, 1.5).looking_at(Vec3::ZERO, Vec3::Y),
+                ..default()
+            });
+        }
+    }
+}
+
+/// Starts counting samples once the scene finished loading and was spawned
+fn wait_for_scene(
+    mut request: ResMut<OfflineRender>,
+    scene: Res<Scene>,
+    instances: Query<&SceneInstance>,
+    scene_spawner: Res<SceneSpawner>,
+) {
+    if request.ready {
+        return;
+    }
+    let spawned = match scene.path {
+        Some(_) => instances
+            .iter()
+            .any(|instance| scene_spawner.instance_is_ready(**instance)),
+        None => true,
+    };
+    if spawned {
+        request.ready = true;
+    }
+}
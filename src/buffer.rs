@@ -0,0 +1,140 @@
+use std::{num::NonZeroU64, ops::Range};
+
+use bevy::render::{
+    render_resource::{
+        encase::{internal::WriteInto, StorageBuffer},
+        BindingResource, Buffer, BufferBinding, BufferDescriptor, BufferUsages, ShaderSize,
+        ShaderType,
+    },
+    renderer::{RenderDevice, RenderQueue},
+};
+
+/// A CPU side array mirrored into a GPU storage buffer that persists between frames
+///
+/// Only the elements touched since the last [`GpuArray::write_buffer`] are uploaded, the buffer
+/// itself is only reallocated when the array outgrows it.
+pub struct GpuArray<T> {
+    data: Vec<T>,
+    dirty: Option<Range<usize>>,
+    buffer: Option<Buffer>,
+    capacity: usize,
+    /// Number of elements covered by the binding last handed out
+    bound_len: usize,
+    label: &'static str,
+}
+
+impl<T: ShaderType + ShaderSize + WriteInto + Clone> GpuArray<T> {
+    pub fn new(label: &'static str) -> Self {
+        Self {
+            data: Vec::new(),
+            dirty: None,
+            buffer: None,
+            capacity: 0,
+            bound_len: 0,
+            label,
+        }
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Sets the element at `index`, which may be one past the end to push it
+    pub fn set(&mut self, index: usize, value: T) {
+        if index == self.data.len() {
+            self.data.push(value);
+        } else {
+            self.data[index] = value;
+        }
+        self.mark_dirty(index..index + 1);
+    }
+
+    /// Overwrites the elements starting at `start`
+    pub fn write(&mut self, start: usize, values: impl IntoIterator<Item = T>) {
+        let mut end = start;
+        for value in values {
+            self.data[end] = value;
+            end += 1;
+        }
+        self.mark_dirty(start..end);
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = T>) {
+        let start = self.data.len();
+        self.data.extend(values);
+        self.mark_dirty(start..self.data.len());
+    }
+
+    pub fn replace(&mut self, data: Vec<T>) {
+        self.data = data;
+        self.mark_dirty(0..self.data.len());
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.dirty = None;
+    }
+
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => dirty.start.min(range.start)..dirty.end.max(range.end),
+            None => range,
+        });
+    }
+
+    fn stride() -> u64 {
+        T::SHADER_SIZE.get()
+    }
+
+    /// Uploads the changed elements, returns true if bind groups using the array have to be
+    /// recreated because the buffer was reallocated or the array changed length
+    pub fn write_buffer(&mut self, device: &RenderDevice, queue: &RenderQueue) -> bool {
+        let reallocated = self.buffer.is_none() || self.data.len() > self.capacity;
+        if reallocated {
+            self.capacity = self.data.len().max(1).next_power_of_two();
+            self.buffer = Some(device.create_buffer(&BufferDescriptor {
+                label: Some(self.label),
+                size: self.capacity as u64 * Self::stride(),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }));
+            self.mark_dirty(0..self.data.len());
+        }
+        if let (Some(range), Some(buffer)) = (self.dirty.take(), &self.buffer) {
+            let mut bytes = StorageBuffer::new(Vec::<u8>::new());
+            bytes.write(&self.data[range.clone()]).unwrap();
+            queue.write_buffer(
+                buffer,
+                range.start as u64 * Self::stride(),
+                &bytes.into_inner(),
+            );
+        }
+        let resized = self.bound_len != self.data.len().max(1);
+        self.bound_len = self.data.len().max(1);
+        reallocated || resized
+    }
+
+    pub fn buffer(&self) -> Option<&Buffer> {
+        self.buffer.as_ref()
+    }
+
+    /// Binds exactly the live elements, but always at least one so the binding is never empty
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
+        Some(BindingResource::Buffer(BufferBinding {
+            buffer: self.buffer.as_ref()?,
+            offset: 0,
+            size: NonZeroU64::new(self.data.len().max(1) as u64 * Self::stride()),
+        }))
+    }
+}
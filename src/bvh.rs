@@ -1 +1,322 @@
+use bevy::{
+    math::{
+        bounding::{Aabb3d, BoundingVolume},
+        Vec3A,
+    },
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
 
+/// Number of centroid bins tried per axis when looking for a split
+const BINS: usize = 12;
+/// Cost of visiting an interior node, relative to intersecting a single primitive
+const TRAVERSAL_COST: f32 = 1.0;
+/// Entries of the traversal stack in the shader, `BVH_STACK_SIZE` in `ray_tracing.wgsl`
+pub const BVH_STACK_SIZE: u32 = 32;
+/// Deepest level a node may sit at, so traversal never drops a child for lack of stack space
+const MAX_DEPTH: u32 = BVH_STACK_SIZE - 1;
+
+/// Controls when acceleration structures are refit in place instead of rebuilt
+#[derive(Resource, ExtractResource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct BvhSettings {
+    /// A refit BVH is rebuilt once its SAH cost grows past this multiple of its cost when built
+    pub rebuild_threshold: f32,
+}
+
+impl Default for BvhSettings {
+    fn default() -> Self {
+        Self {
+            rebuild_threshold: 1.5,
+        }
+    }
+}
+
+/// A node of a flattened bounding volume hierarchy
+///
+/// Interior nodes have a `count` of zero and store the index of their left child in `first`,
+/// the right child is always stored right after it. Leaves store the range
+/// `first..first + count` of [`Bvh::indices`].
+#[derive(Reflect, Default, Debug, Clone, Copy, ShaderType)]
+pub struct BvhNode {
+    pub aabb_min: Vec3,
+    pub first: u32,
+    pub aabb_max: Vec3,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn aabb(&self) -> Aabb3d {
+        Aabb3d {
+            min: self.aabb_min.into(),
+            max: self.aabb_max.into(),
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// Bounding volume hierarchy built with the surface area heuristic
+#[derive(Default, Debug, Clone)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// Primitive indices in the order the leaves reference them
+    pub indices: Vec<u32>,
+    /// SAH cost right after the last full build
+    pub build_cost: f32,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over primitives with the given bounds
+    ///
+    /// An empty input produces a single childless root whose `first` points at itself, which
+    /// traversal skips since children always come after their parent.
+    pub fn build(bounds: &[Aabb3d]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len() as u32).collect(),
+            build_cost: 0.0,
+        };
+        bvh.nodes.push(BvhNode {
+            count: bounds.len() as u32,
+            ..default()
+        });
+        if bounds.is_empty() {
+            return bvh;
+        }
+        let centroids: Vec<Vec3A> = bounds.iter().map(BoundingVolume::center).collect();
+        bvh.subdivide(0, 0, bounds, &centroids);
+        bvh.build_cost = bvh.cost();
+        bvh
+    }
+
+    /// Recomputes the node bounds bottom-up while keeping the tree topology
+    ///
+    /// `bounds` are given in leaf order, that is already permuted by [`Bvh::indices`].
+    pub fn refit(&mut self, bounds: &[Aabb3d]) {
+        // children are always stored after their parent
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            let aabb = if node.is_leaf() {
+                union(
+                    bounds[node.first as usize..(node.first + node.count) as usize]
+                        .iter()
+                        .copied(),
+                )
+            } else if node.first as usize > index {
+                let left = self.nodes[node.first as usize].aabb();
+                left.merge(&self.nodes[node.first as usize + 1].aabb())
+            } else {
+                continue;
+            };
+            self.nodes[index].aabb_min = aabb.min.into();
+            self.nodes[index].aabb_max = aabb.max.into();
+        }
+    }
+
+    /// Expected cost of tracing a ray through the tree according to the surface area heuristic
+    pub fn cost(&self) -> f32 {
+        let root_area = self.nodes[0].aabb().visible_area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.is_leaf() {
+                    node.count as f32
+                } else {
+                    TRAVERSAL_COST
+                };
+                cost * node.aabb().visible_area() / root_area
+            })
+            .sum()
+    }
+
+    /// Whether refitting degraded the tree enough that it should be rebuilt
+    pub fn needs_rebuild(&self, settings: &BvhSettings) -> bool {
+        self.cost() > self.build_cost * settings.rebuild_threshold
+    }
+
+    /// Nodes as they are laid out once stored at `node_offset` in a shared array, with leaves
+    /// referencing primitives stored from `primitive_offset`
+    pub fn flattened(
+        &self,
+        node_offset: u32,
+        primitive_offset: u32,
+    ) -> impl Iterator<Item = BvhNode> + '_ {
+        self.nodes.iter().map(move |node| BvhNode {
+            first: node.first
+                + if node.is_leaf() {
+                    primitive_offset
+                } else {
+                    node_offset
+                },
+            ..*node
+        })
+    }
+
+    /// Splits the node at `index`, which sits at `depth`, recursively
+    ///
+    /// Nodes at [`MAX_DEPTH`] stay leaves however many primitives they hold.
+    fn subdivide(&mut self, index: usize, depth: u32, bounds: &[Aabb3d], centroids: &[Vec3A]) {
+        let node = self.nodes[index];
+        let range = node.first as usize..(node.first + node.count) as usize;
+        let aabb = union(
+            self.indices[range.clone()]
+                .iter()
+                .map(|&i| bounds[i as usize]),
+        );
+        self.nodes[index].aabb_min = aabb.min.into();
+        self.nodes[index].aabb_max = aabb.max.into();
+        if node.count <= 1 || depth >= MAX_DEPTH {
+            return;
+        }
+
+        let Some((axis, split)) = self.find_split(range.clone(), &aabb, bounds, centroids) else {
+            return;
+        };
+        let indices = &mut self.indices[range.clone()];
+        let mut left_count = 0;
+        for i in 0..indices.len() {
+            if centroids[indices[i] as usize][axis] < split {
+                indices.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+        if left_count == 0 || left_count == indices.len() {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            first: range.start as u32,
+            count: left_count as u32,
+            ..default()
+        });
+        self.nodes.push(BvhNode {
+            first: (range.start + left_count) as u32,
+            count: (range.len() - left_count) as u32,
+            ..default()
+        });
+        self.nodes[index].first = left as u32;
+        self.nodes[index].count = 0;
+        self.subdivide(left, depth + 1, bounds, centroids);
+        self.subdivide(left + 1, depth + 1, bounds, centroids);
+    }
+
+    /// Finds the cheapest binned split, or `None` if keeping the node as a leaf is cheaper
+    fn find_split(
+        &self,
+        range: std::ops::Range<usize>,
+        aabb: &Aabb3d,
+        bounds: &[Aabb3d],
+        centroids: &[Vec3A],
+    ) -> Option<(usize, f32)> {
+        let indices = &self.indices[range];
+        let centroid_bounds = union(indices.iter().map(|&i| Aabb3d {
+            min: centroids[i as usize],
+            max: centroids[i as usize],
+        }));
+        let parent_area = aabb.visible_area();
+        let mut best: Option<(usize, f32)> = None;
+        let mut best_cost = indices.len() as f32;
+
+        for axis in [0, 1, 2] {
+            let min = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - min;
+            if extent <= f32::EPSILON {
+                continue;
+            }
+            let scale = BINS as f32 / extent;
+            let mut bins = [(0u32, EMPTY); BINS];
+            for &i in indices {
+                let bin = (((centroids[i as usize][axis] - min) * scale) as usize).min(BINS - 1);
+                bins[bin].0 += 1;
+                bins[bin].1 = bins[bin].1.merge(&bounds[i as usize]);
+            }
+
+            let mut right_costs = [0.0; BINS];
+            let (mut count, mut aabb) = (0, EMPTY);
+            for bin in (1..BINS).rev() {
+                count += bins[bin].0;
+                aabb = aabb.merge(&bins[bin].1);
+                right_costs[bin] = count as f32 * area(count, &aabb);
+            }
+            let (mut count, mut aabb) = (0, EMPTY);
+            for bin in 1..BINS {
+                count += bins[bin - 1].0;
+                aabb = aabb.merge(&bins[bin - 1].1);
+                let cost = TRAVERSAL_COST
+                    + (count as f32 * area(count, &aabb) + right_costs[bin]) / parent_area;
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((axis, min + bin as f32 / scale));
+                }
+            }
+        }
+        best
+    }
+}
+
+const EMPTY: Aabb3d = Aabb3d {
+    min: Vec3A::INFINITY,
+    max: Vec3A::NEG_INFINITY,
+};
+
+fn union(iter: impl Iterator<Item = Aabb3d>) -> Aabb3d {
+    iter.fold(EMPTY, |a, b| a.merge(&b))
+}
+
+fn area(count: u32, aabb: &Aabb3d) -> f32 {
+    if count == 0 {
+        0.0
+    } else {
+        aabb.visible_area()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth(bvh: &Bvh, index: usize) -> u32 {
+        let node = bvh.nodes[index];
+        if node.is_leaf() || node.first as usize <= index {
+            0
+        } else {
+            let left = depth(bvh, node.first as usize);
+            let right = depth(bvh, node.first as usize + 1);
+            1 + left.max(right)
+        }
+    }
+
+    #[test]
+    fn depth_is_capped_for_colinear_centroids() {
+        // geometrically spaced boxes on a line make every split peel off a single primitive
+        let bounds: Vec<Aabb3d> = (0..150)
+            .map(|i| {
+                let x = 1.7f32.powi(i);
+                Aabb3d {
+                    min: Vec3A::new(x, 0.0, 0.0),
+                    max: Vec3A::new(x + 0.1, 0.1, 0.1),
+                }
+            })
+            .collect();
+        let bvh = Bvh::build(&bounds);
+        assert!(depth(&bvh, 0) <= MAX_DEPTH);
+
+        let mut leaves: Vec<u32> = bvh
+            .nodes
+            .iter()
+            .filter(|node| node.is_leaf())
+            .flat_map(|node| {
+                bvh.indices[node.first as usize..(node.first + node.count) as usize].to_vec()
+            })
+            .collect();
+        leaves.sort();
+        assert_eq!(leaves, (0..150).collect::<Vec<_>>());
+    }
+}
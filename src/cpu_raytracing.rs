@@ -0,0 +1,1214 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    ecs::system::RunSystemOnce,
+    prelude::*,
+    render::{
+        camera::{CameraProjection, Exposure},
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        MainWorld,
+    },
+    tasks::{ComputeTaskPool, ParallelSliceMut, TaskPool},
+};
+
+use crate::{
+    bvh::{BvhNode, BvhSettings},
+    environment::{
+        cube_texel, extract_environments, texel_color, EnvironmentCache, EnvironmentKind,
+        ExtractedEnvironment,
+    },
+    extract::{prepare_meshinfo, RemovedInstances, SceneCache},
+    light::{extract_lights, GpuLight, GpuLightInfo},
+    ray_tracing::{
+        GpuRayTracingSettings, MeshInstance, RayTracingInfo, RayTracingSettings, SimpleMaterial,
+        Triangle,
+    },
+    sampler::{PixelSampler, SamplerKind},
+    sky::PreethamSky,
+    texture::{NO_TEXTURE, TEXTURE_SIZE},
+};
+
+/// Runs an extract system of the render app against `main_world`
+fn run_extract<M>(
+    main_world: &mut World,
+    render_world: &mut World,
+    system: impl IntoSystem<(), (), M>,
+) {
+    let mut extracted = MainWorld::default();
+    std::mem::swap(&mut *extracted, main_world);
+    render_world.insert_resource(extracted);
+    render_world.run_system_once(system);
+    let mut extracted = render_world.remove_resource::<MainWorld>().unwrap();
+    std::mem::swap(&mut *extracted, main_world);
+}
+
+/// Builds the scene data the shader traces from a world's meshes, materials and lights, without
+/// a render app or GPU
+///
+/// Transforms and visibility have to be propagated already. Instances whose assets haven't loaded
+/// are left out.
+pub fn extract_scene(main_world: &mut World) -> RayTracingInfo {
+    main_world.init_resource::<RemovedInstances>();
+    main_world.init_resource::<BvhSettings>();
+    let mut render_world = World::new();
+    render_world.init_resource::<SceneCache>();
+    render_world.init_resource::<RayTracingInfo>();
+    run_extract(main_world, &mut render_world, prepare_meshinfo);
+    run_extract(main_world, &mut render_world, extract_lights);
+    let mut info = render_world.remove_resource::<RayTracingInfo>().unwrap();
+    info.textures.resample();
+    if let Some(settings) = main_world.get_resource::<RayTracingSettings>() {
+        info.settings.set(settings.into());
+    }
+    info
+}
+
+/// What rays leaving the scene see, without the importance sampling tables of the GPU
+pub struct CpuEnvironment {
+    kind: EnvironmentKind,
+    color: Vec3,
+    sky: PreethamSky,
+    image: Option<Image>,
+}
+
+impl CpuEnvironment {
+    /// Same radiance in cd/m² in every direction
+    pub fn uniform(color: Vec3) -> Self {
+        Self {
+            kind: EnvironmentKind::Uniform,
+            color,
+            sky: default(),
+            image: None,
+        }
+    }
+
+    fn exists(&self) -> bool {
+        self.kind != EnvironmentKind::Uniform || self.color.cmpgt(Vec3::ZERO).any()
+    }
+
+    /// Unexposed radiance arriving along the opposite of `direction`, like
+    /// `environment_radiance` in the shader
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let factor = match (self.kind, &self.image) {
+            (EnvironmentKind::Sky, _) => self.sky.radiance(direction),
+            (EnvironmentKind::Equirectangular, Some(image)) => {
+                let size = image.size();
+                let texel = (direction_to_uv(direction) * size.as_vec2())
+                    .as_uvec2()
+                    .min(size - 1);
+                texel_color(image, 0, texel).unwrap_or_default()
+            }
+            (EnvironmentKind::Cube, Some(image)) => {
+                let (face, texel) = cube_texel(direction, image.size().x);
+                texel_color(image, face, texel).unwrap_or_default()
+            }
+            _ => Vec3::ONE,
+        };
+        self.color * factor
+    }
+}
+
+/// A camera as the shader sees it
+pub struct CpuView {
+    pub size: UVec2,
+    pub world_from_clip: Mat4,
+    pub position: Vec3,
+    pub exposure: f32,
+    pub environment: CpuEnvironment,
+}
+
+impl CpuView {
+    /// Reads a ray traced camera rendering `size` pixels, its environment is picked the same way
+    /// the render app picks it
+    pub fn extract(main_world: &mut World, camera: Entity, size: UVec2) -> Option<Self> {
+        let transform = *main_world.get::<GlobalTransform>(camera)?;
+        let mut projection = main_world.get::<Projection>(camera)?.clone();
+        projection.update(size.x as f32, size.y as f32);
+        let exposure = main_world
+            .get::<Exposure>(camera)
+            .cloned()
+            .unwrap_or_default()
+            .exposure();
+
+        main_world.init_resource::<AmbientLight>();
+        let mut render_world = World::new();
+        render_world.init_resource::<EnvironmentCache>();
+        run_extract(main_world, &mut render_world, extract_environments);
+        let extracted = render_world.get::<ExtractedEnvironment>(camera)?;
+        let image = extracted
+            .image
+            .and_then(|id| main_world.resource::<Assets<Image>>().get(id))
+            .cloned();
+        Some(Self {
+            size,
+            world_from_clip: transform.compute_matrix() * projection.get_clip_from_view().inverse(),
+            position: transform.translation(),
+            exposure,
+            environment: CpuEnvironment {
+                kind: extracted.kind,
+                color: extracted.color,
+                sky: extracted.sky.clone(),
+                image,
+            },
+        })
+    }
+}
+
+/// Path traces `samples` per pixel of a view on every core, returning the average exposed
+/// radiance as an [`TextureFormat::Rgba32Float`] image
+///
+/// Follows the shader's paths with next event estimation at every bounce and without ReSTIR, so
+/// both converge to the same image. The environment is sampled uniformly.
+pub fn render(info: &RayTracingInfo, view: &CpuView, samples: u32) -> Image {
+    let tracer = Tracer::new(info, view);
+    let size = view.size;
+    let mut pixels = vec![Vec4::ZERO; (size.x * size.y) as usize];
+    let task_pool = ComputeTaskPool::get_or_init(TaskPool::default);
+    pixels.par_chunk_map_mut(task_pool, size.x as usize, |y, row| {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let position = UVec2::new(x as u32, y as u32);
+            let mut sum = Vec3::ZERO;
+            for sample in 0..samples {
+                let mut rng = PixelSampler::new(
+                    SamplerKind::from_id(tracer.settings.sampler_kind),
+                    tracer.settings.seed,
+                    position,
+                    position.x + position.y * size.x,
+                    sample,
+                    sample,
+                );
+                let radiance = tracer.trace_path(tracer.camera_ray(position, &mut rng), &mut rng);
+                // like the shader, broken samples are dropped
+                if radiance.is_finite() {
+                    sum += radiance;
+                }
+            }
+            *pixel = (sum / samples.max(1) as f32).extend(1.0);
+        }
+    });
+    Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        pixels
+            .iter()
+            .flat_map(|pixel| pixel.to_array())
+            .flat_map(f32::to_le_bytes)
+            .collect(),
+        TextureFormat::Rgba32Float,
+        RenderAssetUsages::default(),
+    )
+}
+
+#[derive(Clone, Copy)]
+struct Ray {
+    origin: Vec3,
+    direction: Vec3,
+}
+
+#[derive(Clone, Copy, Default)]
+struct HitRecord {
+    point: Vec3,
+    normal: Vec3,
+    t: f32,
+    material: u32,
+    uv: Vec2,
+    tangent: Vec4,
+}
+
+/// A material with its textures sampled at a hit, like `Material` in the shader
+#[derive(Clone)]
+struct Surface {
+    color: Vec3,
+    /// Alpha holds the emissive exposure weight
+    emissive: Vec4,
+    metallic: f32,
+    perceptual_roughness: f32,
+    reflectance: f32,
+    specular_transmission: f32,
+    ior: f32,
+    clearcoat: f32,
+    clearcoat_perceptual_roughness: f32,
+}
+
+struct LightSample {
+    direction: Vec3,
+    distance: f32,
+    /// Exposed irradiance arriving along `direction` over the pdf of picking it
+    irradiance: Vec3,
+    /// Solid angle density of the sample, zero for punctual lights
+    pdf: f32,
+}
+
+struct EmitterPoint {
+    position: Vec3,
+    normal: Vec3,
+    radiance: Vec3,
+    /// Untextured emissive color the triangle was picked by
+    factor: Vec3,
+}
+
+struct Tracer<'a> {
+    info: &'a RayTracingInfo,
+    view: &'a CpuView,
+    settings: &'a GpuRayTracingSettings,
+    light_info: &'a GpuLightInfo,
+}
+
+impl<'a> Tracer<'a> {
+    fn new(info: &'a RayTracingInfo, view: &'a CpuView) -> Self {
+        Self {
+            info,
+            view,
+            settings: info.settings.get(),
+            light_info: info.light_info.get(),
+        }
+    }
+
+    fn camera_ray(&self, pixel: UVec2, rng: &mut PixelSampler) -> Ray {
+        let jitter = rng.rand2();
+        let uv = (pixel.as_vec2() + jitter) / self.view.size.as_vec2() * Vec2::new(2.0, -2.0)
+            + Vec2::new(-1.0, 1.0);
+        let direction = (self.view.world_from_clip * Vec4::new(uv.x, uv.y, 0.0, 1.0))
+            .truncate()
+            .normalize();
+        Ray {
+            origin: self.view.position,
+            direction,
+        }
+    }
+
+    fn ray_triangle(&self, ray: &Ray, tri: &Triangle) -> Option<HitRecord> {
+        let vertices = self.info.vertices.data();
+        let [a, b, c] = tri.indices.map(|i| &vertices[i as usize]);
+        let edge_ab = b.position - a.position;
+        let edge_ac = c.position - a.position;
+        let norm = edge_ab.cross(edge_ac);
+        let ao = ray.origin - a.position;
+        let dao = ao.cross(ray.direction);
+        let det = -ray.direction.dot(norm);
+        let inv_det = 1.0 / det;
+        let dst = ao.dot(norm) * inv_det;
+        let u = edge_ac.dot(dao) * inv_det;
+        let v = -edge_ab.dot(dao) * inv_det;
+        let w = 1.0 - u - v;
+        // triangles are two sided so paths can leave refractive objects again
+        if !(det.abs() >= 1e-12 && dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0) {
+            return None;
+        }
+        Some(HitRecord {
+            point: ray.origin + ray.direction * dst,
+            normal: (a.normal * w + b.normal * u + c.normal * v).normalize(),
+            t: dst,
+            material: 0,
+            uv: a.uv * w + b.uv * u + c.uv * v,
+            tangent: a.tangent * w + b.tangent * u + c.tangent * v,
+        })
+    }
+
+    /// Closest leaf primitive hit closer than `t_max` in a BVH whose root is at `root`
+    fn traverse(
+        ray: &Ray,
+        nodes: &[BvhNode],
+        root: u32,
+        t_max: f32,
+        mut hit_leaf: impl FnMut(u32, f32) -> Option<HitRecord>,
+    ) -> Option<HitRecord> {
+        let inv_dir = ray.direction.recip();
+        let mut hit: Option<HitRecord> = None;
+        let mut t = t_max;
+        let mut stack = vec![root];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index as usize];
+            if node.is_leaf() {
+                for i in node.first..node.first + node.count {
+                    if let Some(record) = hit_leaf(i, t) {
+                        if record.t < t {
+                            t = record.t;
+                            hit = Some(record);
+                        }
+                    }
+                }
+                continue;
+            }
+            // children always follow their parent, only the root of an empty tree breaks this
+            if node.first <= index {
+                continue;
+            }
+            let [left, right] = [node.first, node.first + 1].map(|child| {
+                let child_node = &nodes[child as usize];
+                (
+                    child,
+                    ray_aabb(ray, inv_dir, child_node.aabb_min, child_node.aabb_max),
+                )
+            });
+            let (near, far) = if right.1 < left.1 {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            // push the far child first so the near one is visited next
+            for (child, distance) in [far, near] {
+                if distance < t {
+                    stack.push(child);
+                }
+            }
+        }
+        hit
+    }
+
+    fn hit_instance(&self, ray: &Ray, instance: &MeshInstance, t_max: f32) -> Option<HitRecord> {
+        // the direction is left unnormalized so distances stay in world units
+        let object_ray = Ray {
+            origin: instance.object_from_world.transform_point3(ray.origin),
+            direction: instance.object_from_world.transform_vector3(ray.direction),
+        };
+        let root = self.info.meshes.data()[instance.mesh as usize].root_node;
+        let triangles = self.info.triangles.data();
+        let material = &self.info.materials.data()[instance.material as usize];
+        let mut record =
+            Self::traverse(&object_ray, self.info.nodes.data(), root, t_max, |i, _| {
+                self.ray_triangle(&object_ray, &triangles[i as usize])
+                    .filter(|record| {
+                        material.alpha_cutoff <= 0.0 || self.alpha_test(material, record)
+                    })
+            })?;
+        record.point = ray.origin + ray.direction * record.t;
+        record.normal = instance
+            .object_from_world
+            .transpose()
+            .transform_vector3(record.normal)
+            .normalize();
+        if record.tangent.w != 0.0 {
+            let tangent = instance
+                .world_from_object
+                .transform_vector3(record.tangent.truncate());
+            record.tangent = tangent.normalize().extend(record.tangent.w);
+        }
+        record.material = instance.material;
+        Some(record)
+    }
+
+    /// Whether a hit on a surface with an alpha masked `material` lands on an opaque texel
+    fn alpha_test(&self, material: &SimpleMaterial, record: &HitRecord) -> bool {
+        let mut alpha = material.color.alpha;
+        if material.base_color_texture != NO_TEXTURE {
+            let uv = Self::texture_uv(material, record);
+            alpha *= self.sample_texture(material.base_color_texture, uv).w;
+        }
+        alpha >= material.alpha_cutoff
+    }
+
+    /// Closest hit closer than `t_max`
+    fn hit_triangles(&self, ray: &Ray, t_max: f32) -> Option<HitRecord> {
+        if self.info.instances.is_empty() {
+            return None;
+        }
+        let instances = self.info.instances.data();
+        Self::traverse(ray, self.info.tlas_nodes.data(), 0, t_max, |i, t| {
+            self.hit_instance(ray, &instances[i as usize], t)
+        })
+    }
+
+    /// Bilinear, repeating lookup of a texture layer like the shader's sampler
+    fn sample_texture(&self, index: u32, uv: Vec2) -> Vec4 {
+        let Some(layer) = self.info.textures.layer(index as usize) else {
+            return Vec4::ONE;
+        };
+        let size = TEXTURE_SIZE as i32;
+        let position = uv * TEXTURE_SIZE as f32 - 0.5;
+        let base = position.floor();
+        let fraction = position - base;
+        let texel = |x: i32, y: i32| {
+            let offset = (y.rem_euclid(size) * size + x.rem_euclid(size)) as usize * 4;
+            Vec4::from_array(std::array::from_fn(|i| layer[offset + i] as f32 / 255.0))
+        };
+        let (x, y) = (base.x as i32, base.y as i32);
+        let top = texel(x, y).lerp(texel(x + 1, y), fraction.x);
+        let bottom = texel(x, y + 1).lerp(texel(x + 1, y + 1), fraction.x);
+        top.lerp(bottom, fraction.y)
+    }
+
+    fn texture_uv(material: &SimpleMaterial, record: &HitRecord) -> Vec2 {
+        (material.uv_transform * record.uv.extend(1.0)).truncate()
+    }
+
+    /// The material with its textures sampled at the hit, like `textured_material` in the shader
+    fn textured_material(&self, material: &SimpleMaterial, record: &HitRecord) -> Surface {
+        let uv = Self::texture_uv(material, record);
+        let mut surface = Surface {
+            color: material.color.to_vec3(),
+            emissive: material.emissive.to_vec4(),
+            metallic: material.metallic,
+            perceptual_roughness: material.perceptual_roughness,
+            reflectance: material.reflectance,
+            specular_transmission: material.specular_transmission,
+            ior: material.ior,
+            clearcoat: material.clearcoat,
+            clearcoat_perceptual_roughness: material.clearcoat_perceptual_roughness,
+        };
+        if material.base_color_texture != NO_TEXTURE {
+            let texel = self.sample_texture(material.base_color_texture, uv);
+            surface.color *= srgb_to_linear(texel.truncate());
+        }
+        if material.emissive_texture != NO_TEXTURE {
+            let texel = self.sample_texture(material.emissive_texture, uv);
+            let emissive = surface.emissive.truncate() * srgb_to_linear(texel.truncate());
+            surface.emissive = emissive.extend(surface.emissive.w);
+        }
+        if material.metallic_roughness_texture != NO_TEXTURE {
+            let texel = self.sample_texture(material.metallic_roughness_texture, uv);
+            surface.metallic *= texel.z;
+            surface.perceptual_roughness *= texel.y;
+        }
+        surface
+    }
+
+    /// The interpolated normal bent by the material's normal map
+    fn shading_normal(&self, material: &SimpleMaterial, record: &HitRecord) -> Vec3 {
+        if material.normal_map_texture == NO_TEXTURE || record.tangent.w == 0.0 {
+            return record.normal;
+        }
+        let texel = self.sample_texture(
+            material.normal_map_texture,
+            Self::texture_uv(material, record),
+        );
+        let mut nt = texel.truncate() * 2.0 - 1.0;
+        if material.flags & SimpleMaterial::FLIP_NORMAL_MAP_Y != 0 {
+            nt.y = -nt.y;
+        }
+        let n = record.normal;
+        let t = record.tangent.truncate();
+        let b = record.tangent.w * n.cross(t);
+        (nt.x * t + nt.y * b + nt.z * n).normalize()
+    }
+
+    fn emitted(&self, surface: &Surface) -> Vec3 {
+        surface.emissive.truncate() * (1.0 + (self.view.exposure - 1.0) * surface.emissive.w)
+    }
+
+    fn sample_light(&self, light: &GpuLight, point: Vec3, u: Vec2) -> LightSample {
+        let exposure = self.view.exposure;
+        if light.kind == GpuLight::DIRECTIONAL {
+            return LightSample {
+                direction: light.position,
+                distance: f32::MAX,
+                irradiance: light.color * exposure,
+                pdf: 0.0,
+            };
+        }
+        let to_light = light.position - point;
+        let distance_square = to_light.length_squared();
+        let distance = distance_square.sqrt();
+        let to_center = to_light / distance;
+        let mut irradiance = light.color
+            * exposure
+            * distance_attenuation(distance_square, light.inverse_square_range);
+        if light.kind == GpuLight::SPOT {
+            let attenuation = ((-light.spot_direction).dot(to_center) * light.spot_scale
+                + light.spot_offset)
+                .clamp(0.0, 1.0);
+            irradiance *= attenuation * attenuation;
+        }
+        let sin2_max = light.radius * light.radius / distance_square;
+        if !(1e-6..1.0).contains(&sin2_max) {
+            // too small to cast soft shadows, or the point is inside the light
+            return LightSample {
+                direction: to_center,
+                distance,
+                irradiance,
+                pdf: 0.0,
+            };
+        }
+
+        // lights with a radius are spheres, sample the cone they subtend uniformly
+        let cos_max = (1.0 - sin2_max).sqrt();
+        let cos_theta = 1.0 - u.x * sin2_max / (1.0 + cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let direction = orthonormal_basis(to_center)
+            * Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let surface = distance * cos_theta
+            - (light.radius * light.radius - distance_square * sin_theta * sin_theta)
+                .max(0.0)
+                .sqrt();
+        LightSample {
+            direction,
+            distance: surface,
+            irradiance: irradiance * 2.0 / (1.0 + cos_max),
+            pdf: 0.0,
+        }
+    }
+
+    /// Next event estimation splits its samples evenly between punctual lights, emissive
+    /// triangles and the environment, whichever of them the scene has
+    fn light_categories(&self) -> f32 {
+        (self.light_info.light_count > 0) as u32 as f32
+            + (self.light_info.emissive_count > 0) as u32 as f32
+            + self.view.environment.exists() as u32 as f32
+    }
+
+    fn emissive_selection_probability(&self) -> f32 {
+        if self.light_info.emissive_count > 0 {
+            1.0 / self.light_categories()
+        } else {
+            0.0
+        }
+    }
+
+    fn environment_selection_probability(&self) -> f32 {
+        if self.view.environment.exists() {
+            1.0 / self.light_categories()
+        } else {
+            0.0
+        }
+    }
+
+    fn emissive_pdf(&self, emissive: Vec3, distance: f32, cos_light: f32) -> f32 {
+        if self.light_info.emissive_count == 0 {
+            return 0.0;
+        }
+        let area_pdf = luminance(emissive) / self.light_info.emissive_power;
+        self.emissive_selection_probability() * area_pdf * distance * distance
+            / cos_light.abs().max(1e-6)
+    }
+
+    fn environment_light_pdf(&self) -> f32 {
+        self.environment_selection_probability() / (4.0 * PI)
+    }
+
+    fn pick_emitter(&self, rng: &mut PixelSampler) -> u32 {
+        let emitters = self.info.emissive_triangles.data();
+        let index = rng.randint(self.light_info.emissive_count);
+        let emitter = &emitters[index as usize];
+        if rng.rand() >= emitter.probability {
+            return emitter.alias_index;
+        }
+        index
+    }
+
+    fn emitter_point(&self, index: u32, u: Vec2) -> EmitterPoint {
+        let emitter = &self.info.emissive_triangles.data()[index as usize];
+        let instance = &self.info.instances.data()[emitter.instance as usize];
+        let tri = &self.info.triangles.data()[emitter.triangle as usize];
+        let vertices = self.info.vertices.data();
+        let [a, b, c] = tri.indices.map(|i| &vertices[i as usize]);
+        let [pos_a, pos_b, pos_c] =
+            [a, b, c].map(|vertex| instance.world_from_object.transform_point3(vertex.position));
+
+        let r = u.x.sqrt();
+        let weights = Vec3::new(1.0 - r, r * (1.0 - u.y), r * u.y);
+        let position = pos_a * weights.x + pos_b * weights.y + pos_c * weights.z;
+        let normal = (pos_b - pos_a).cross(pos_c - pos_a).normalize();
+
+        let material = &self.info.materials.data()[instance.material as usize];
+        let record = HitRecord {
+            uv: a.uv * weights.x + b.uv * weights.y + c.uv * weights.z,
+            ..default()
+        };
+        let surface = self.textured_material(material, &record);
+        EmitterPoint {
+            position,
+            normal,
+            radiance: self.emitted(&surface),
+            factor: material.emissive.to_vec3(),
+        }
+    }
+
+    fn sample_emissive(&self, point: Vec3, rng: &mut PixelSampler) -> LightSample {
+        let index = self.pick_emitter(rng);
+        let emitter = self.emitter_point(index, rng.rand2());
+        let to_light = emitter.position - point;
+        let distance = to_light.length();
+        let direction = to_light / distance;
+        let pdf = self.emissive_pdf(emitter.factor, distance, emitter.normal.dot(direction));
+        LightSample {
+            direction,
+            distance,
+            irradiance: emitter.radiance / pdf,
+            pdf,
+        }
+    }
+
+    fn sample_environment(&self, u: Vec2) -> LightSample {
+        let direction = uniform_sphere(u);
+        let pdf = self.environment_light_pdf();
+        LightSample {
+            direction,
+            distance: f32::MAX,
+            irradiance: self.view.environment.radiance(direction) * self.view.exposure / pdf,
+            pdf,
+        }
+    }
+
+    /// Picks an emissive triangle, the environment or a punctual light to sample
+    fn sample_next_event(&self, point: Vec3, rng: &mut PixelSampler) -> LightSample {
+        let emissive_probability = self.emissive_selection_probability();
+        let environment_probability = self.environment_selection_probability();
+        let u = rng.rand();
+        if u < emissive_probability {
+            return self.sample_emissive(point, rng);
+        }
+        if u < emissive_probability + environment_probability {
+            return self.sample_environment(rng.rand2());
+        }
+        let light_count = self.light_info.light_count;
+        let light = &self.info.lights.data()[rng.randint(light_count) as usize];
+        let mut sample = self.sample_light(light, point, rng.rand2());
+        sample.irradiance *=
+            light_count as f32 / (1.0 - emissive_probability - environment_probability);
+        sample
+    }
+
+    fn trace_path(&self, primary: Ray, rng: &mut PixelSampler) -> Vec3 {
+        let mut ray = primary;
+        let mut throughput = Vec3::ONE;
+        let mut radiance = Vec3::ZERO;
+        // density the last bounce was sampled with, zero for the camera and delta lobes
+        let mut bsdf_pdf = 0.0;
+        let materials = self.info.materials.data();
+        for bounce in 0..=self.settings.max_bounces {
+            let Some(mut record) = self.hit_triangles(&ray, f32::MAX) else {
+                let mut sky = self.view.environment.radiance(ray.direction) * self.view.exposure;
+                if bsdf_pdf > 0.0 {
+                    sky *= power_heuristic(bsdf_pdf, self.environment_light_pdf());
+                }
+                radiance += throughput * sky;
+                break;
+            };
+            let front_face = record.normal.dot(ray.direction) < 0.0;
+            let base = &materials[record.material as usize];
+            if !front_face {
+                record.normal = -record.normal;
+                // the path crossed the volume behind the surface to get here
+                throughput *= (-base.absorption * record.t).exp();
+            }
+            let material = self.textured_material(base, &record);
+            let mut emitted = self.emitted(&material);
+            if bsdf_pdf > 0.0 && emitted.cmpgt(Vec3::ZERO).any() {
+                // next event estimation could have found this emitter as well
+                let light_pdf = self.emissive_pdf(
+                    base.emissive.to_vec3(),
+                    record.t,
+                    record.normal.dot(ray.direction),
+                );
+                emitted *= power_heuristic(bsdf_pdf, light_pdf);
+            }
+            radiance += throughput * emitted;
+            let normal = self.shading_normal(base, &record);
+            let v = -ray.direction;
+
+            if self.light_categories() > 0.0 {
+                let light_sample = self.sample_next_event(record.point, rng);
+                let brdf = evaluate_brdf(&material, normal, v, light_sample.direction);
+                if (brdf * light_sample.irradiance).cmpgt(Vec3::ZERO).any()
+                    && light_sample.direction.dot(record.normal) > 0.0
+                {
+                    let shadow_ray = Ray {
+                        origin: record.point + record.normal * 1e-4,
+                        direction: light_sample.direction,
+                    };
+                    if self
+                        .hit_triangles(&shadow_ray, light_sample.distance - 2e-4)
+                        .is_none()
+                    {
+                        let mut weight = 1.0;
+                        if light_sample.pdf > 0.0 {
+                            weight = power_heuristic(
+                                light_sample.pdf,
+                                brdf_pdf(&material, normal, v, light_sample.direction),
+                            );
+                        }
+                        radiance += throughput * brdf * light_sample.irradiance * weight;
+                    }
+                }
+            }
+            if bounce == self.settings.max_bounces {
+                break;
+            }
+
+            let u = Vec3::new(rng.rand(), rng.rand(), rng.rand());
+            let sample = sample_bsdf(&material, normal, v, front_face, u);
+            throughput *= sample.weight;
+            bsdf_pdf = sample.pdf;
+            if throughput == Vec3::ZERO {
+                break;
+            }
+            if bounce >= self.settings.russian_roulette_depth {
+                let survival = throughput.max_element().clamp(0.05, 1.0);
+                if rng.rand() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+            // start just off the surface on the side the path continues on
+            let side = if sample.direction.dot(record.normal) > 0.0 {
+                1.0
+            } else {
+                -1.0
+            };
+            ray = Ray {
+                origin: record.point + record.normal * side * 1e-4,
+                direction: sample.direction,
+            };
+        }
+        radiance
+    }
+}
+
+/// Distance to the box along the ray, or a huge value if it is missed
+fn ray_aabb(ray: &Ray, inv_dir: Vec3, min: Vec3, max: Vec3) -> f32 {
+    let t1 = (min - ray.origin) * inv_dir;
+    let t2 = (max - ray.origin) * inv_dir;
+    let t_min = t1.min(t2).max_element();
+    let t_max = t1.max(t2).min_element();
+    if t_max >= t_min.max(0.0) {
+        t_min
+    } else {
+        f32::MAX
+    }
+}
+
+/// Same windowed inverse square falloff as bevy_pbr::lighting::getDistanceAttenuation
+fn distance_attenuation(distance_square: f32, inverse_range_squared: f32) -> f32 {
+    let factor = distance_square * inverse_range_squared;
+    let smooth_factor = (1.0 - factor * factor).clamp(0.0, 1.0);
+    smooth_factor * smooth_factor / distance_square.max(0.0001)
+}
+
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    pdf * pdf / (pdf * pdf + other * other)
+}
+
+fn srgb_to_linear(color: Vec3) -> Vec3 {
+    Vec3::from_array(color.to_array().map(|c| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }))
+}
+
+/// Inverse of `equirectangular_direction`
+fn direction_to_uv(direction: Vec3) -> Vec2 {
+    Vec2::new(
+        direction.z.atan2(direction.x) / (2.0 * PI) + 0.5,
+        direction.y.clamp(-1.0, 1.0).acos() / PI,
+    )
+}
+
+fn uniform_sphere(u: Vec2) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3::new(r * phi.cos(), z, r * phi.sin())
+}
+
+// the BSDF below mirrors brdf.wgsl
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+fn perceptual_roughness_to_roughness(perceptual_roughness: f32) -> f32 {
+    let clamped = perceptual_roughness.clamp(0.089, 1.0);
+    clamped * clamped
+}
+
+fn d_ggx(roughness: f32, n_dot_h: f32) -> f32 {
+    let one_minus_n_dot_h_squared = 1.0 - n_dot_h * n_dot_h;
+    let a = n_dot_h * roughness;
+    let k = roughness / (one_minus_n_dot_h_squared + a * a);
+    k * k / PI
+}
+
+fn v_smith_ggx_correlated(roughness: f32, n_dot_v: f32, n_dot_l: f32) -> f32 {
+    let a2 = roughness * roughness;
+    let lambda_v = n_dot_l * ((n_dot_v - a2 * n_dot_v) * n_dot_v + a2).sqrt();
+    let lambda_l = n_dot_v * ((n_dot_l - a2 * n_dot_l) * n_dot_l + a2).sqrt();
+    0.5 / (lambda_v + lambda_l)
+}
+
+fn f_schlick(f0: f32, f90: f32, v_dot_h: f32) -> f32 {
+    f0 + (f90 - f0) * (1.0 - v_dot_h).powi(5)
+}
+
+fn fresnel(f0: Vec3, l_dot_h: f32) -> Vec3 {
+    let f90 = f0.dot(Vec3::splat(50.0 * 0.33)).clamp(0.0, 1.0);
+    f0 + (f90 - f0) * (1.0 - l_dot_h).powi(5)
+}
+
+fn fd_burley(roughness: f32, n_dot_v: f32, n_dot_l: f32, l_dot_h: f32) -> f32 {
+    let f90 = 0.5 + 2.0 * roughness * l_dot_h * l_dot_h;
+    f_schlick(1.0, f90, n_dot_l) * f_schlick(1.0, f90, n_dot_v) / PI
+}
+
+fn f_ab(perceptual_roughness: f32, n_dot_v: f32) -> Vec2 {
+    let c0 = Vec4::new(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = Vec4::new(1.0, 0.0425, 1.04, -0.04);
+    let r = perceptual_roughness * c0 + c1;
+    let a004 = (r.x * r.x).min((-9.28 * n_dot_v).exp2()) * r.x + r.y;
+    Vec2::new(-1.04, 1.04) * a004 + Vec2::new(r.z, r.w)
+}
+
+fn diffuse_color(material: &Surface) -> Vec3 {
+    material.color * (1.0 - material.metallic) * (1.0 - material.specular_transmission)
+}
+
+fn specular_f0(material: &Surface) -> Vec3 {
+    let dielectric = 0.16 * material.reflectance * material.reflectance;
+    dielectric * (1.0 - material.metallic) + material.color * material.metallic
+}
+
+/// Reflected radiance towards `v` for unit irradiance arriving along `l`, cosine included
+fn evaluate_brdf(material: &Surface, n: Vec3, v: Vec3, l: Vec3) -> Vec3 {
+    let n_dot_l = n.dot(l);
+    if n_dot_l <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let n_dot_v = n.dot(v).max(0.0001);
+    let h = (l + v).normalize();
+    let n_dot_h = n.dot(h).clamp(0.0, 1.0);
+    let l_dot_h = l.dot(h).clamp(0.0, 1.0);
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let f0 = specular_f0(material);
+
+    let d = d_ggx(roughness, n_dot_h);
+    let vis = v_smith_ggx_correlated(roughness, n_dot_v, n_dot_l);
+    let f = fresnel(f0, l_dot_h);
+    let multiscatter = 1.0 + f0 * (1.0 / f_ab(material.perceptual_roughness, n_dot_v).x - 1.0);
+    let specular = d * vis * f * multiscatter;
+    let diffuse = diffuse_color(material) * fd_burley(roughness, n_dot_v, n_dot_l, l_dot_h);
+    // the clear coat reflects like a dielectric with an ior of 1.5, the layers below get the rest
+    let coat_roughness = perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+    let coat_fresnel = f_schlick(0.04, 1.0, l_dot_h) * material.clearcoat;
+    let coat = d_ggx(coat_roughness, n_dot_h)
+        * v_smith_ggx_correlated(coat_roughness, n_dot_v, n_dot_l)
+        * coat_fresnel;
+    ((diffuse + specular) * (1.0 - coat_fresnel) + coat) * n_dot_l
+}
+
+/// Rotation taking +z to `n` (Duff et al. 2017)
+fn orthonormal_basis(n: Vec3) -> Mat3 {
+    let s = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    Mat3::from_cols(
+        Vec3::new(1.0 + s * n.x * n.x * a, s * b, -s * n.x),
+        Vec3::new(b, s + n.y * n.y * a, -n.y),
+        n,
+    )
+}
+
+fn sample_cosine_hemisphere(u: Vec2) -> Vec3 {
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3::new(r * phi.cos(), r * phi.sin(), (1.0 - u.x).max(0.0).sqrt())
+}
+
+/// Microfacet normal visible from `v`, both in tangent space (Heitz 2018)
+fn sample_ggx_vndf(v: Vec3, roughness: f32, u: Vec2) -> Vec3 {
+    let vh = Vec3::new(roughness * v.x, roughness * v.y, v.z).normalize();
+    let len_sq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len_sq > 0.0 {
+        Vec3::new(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = vh.cross(t1);
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+    Vec3::new(roughness * nh.x, roughness * nh.y, nh.z.max(0.0)).normalize()
+}
+
+fn smith_g1(roughness: f32, n_dot_v: f32) -> f32 {
+    let a2 = roughness * roughness;
+    2.0 * n_dot_v / (n_dot_v + (a2 + (1.0 - a2) * n_dot_v * n_dot_v).sqrt())
+}
+
+/// Unpolarized fresnel reflectance of a dielectric, `eta` is the incident over the transmitted
+/// ior
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+fn reflect(i: Vec3, n: Vec3) -> Vec3 {
+    i - 2.0 * n.dot(i) * n
+}
+
+/// Same as WGSL's `refract`, zero on total internal reflection
+fn refract(i: Vec3, n: Vec3, eta: f32) -> Vec3 {
+    let n_dot_i = n.dot(i);
+    let k = 1.0 - eta * eta * (1.0 - n_dot_i * n_dot_i);
+    if k < 0.0 {
+        return Vec3::ZERO;
+    }
+    eta * i - (eta * n_dot_i + k.sqrt()) * n
+}
+
+fn transmission_probability(material: &Surface) -> f32 {
+    material.specular_transmission * (1.0 - material.metallic)
+}
+
+fn clearcoat_probability(material: &Surface, n_dot_v: f32) -> f32 {
+    material.clearcoat * f_schlick(0.04, 1.0, n_dot_v)
+}
+
+fn specular_probability(material: &Surface, n_dot_v: f32) -> f32 {
+    let specular = luminance(fresnel(specular_f0(material), n_dot_v));
+    let diffuse = luminance(diffuse_color(material));
+    specular / (specular + diffuse).max(0.0001)
+}
+
+/// Density of [`sample_bsdf`] picking `l` through one of the reflection lobes
+fn brdf_pdf(material: &Surface, n: Vec3, v: Vec3, l: Vec3) -> f32 {
+    let n_dot_l = n.dot(l);
+    if n_dot_l <= 0.0 {
+        return 0.0;
+    }
+    let n_dot_v = n.dot(v).max(0.0001);
+    let n_dot_h = n.dot((l + v).normalize()).clamp(0.0, 1.0);
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let specular = ggx_reflection_pdf(roughness, n_dot_v, n_dot_h);
+    let diffuse = n_dot_l / PI;
+    let coat_roughness = perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+    let coat = ggx_reflection_pdf(coat_roughness, n_dot_v, n_dot_h);
+    let coat_probability = clearcoat_probability(material, n_dot_v);
+    let base = diffuse + (specular - diffuse) * specular_probability(material, n_dot_v);
+    let reflection = 1.0 - transmission_probability(material);
+    reflection * (base + (coat - base) * coat_probability)
+}
+
+/// Density of reflecting a visible GGX normal sample into a direction with half vector `n_dot_h`
+fn ggx_reflection_pdf(roughness: f32, n_dot_v: f32, n_dot_h: f32) -> f32 {
+    smith_g1(roughness, n_dot_v) * d_ggx(roughness, n_dot_h) / (4.0 * n_dot_v)
+}
+
+struct BsdfSample {
+    direction: Vec3,
+    /// Bsdf times cosine over pdf
+    weight: Vec3,
+    /// Zero for transmission, which is treated like a delta distribution
+    pdf: f32,
+}
+
+/// Picks a direction to continue a path arriving from `v`, with `n` facing `v`
+fn sample_bsdf(material: &Surface, n: Vec3, v: Vec3, front_face: bool, u: Vec3) -> BsdfSample {
+    let roughness = perceptual_roughness_to_roughness(material.perceptual_roughness);
+    let basis = orthonormal_basis(n);
+    let v_local = basis.transpose() * v;
+    let v_local = Vec3::new(v_local.x, v_local.y, v_local.z.max(0.0001)).normalize();
+
+    let transmission = transmission_probability(material);
+    if u.z < transmission {
+        let h = basis * sample_ggx_vndf(v_local, roughness, u.truncate());
+        let eta = if front_face {
+            1.0 / material.ior
+        } else {
+            material.ior
+        };
+        let refracted = refract(-v, h, eta);
+        let reflectance = fresnel_dielectric(v.dot(h).clamp(0.0, 1.0), eta);
+        if u.z / transmission < reflectance || refracted == Vec3::ZERO {
+            return BsdfSample {
+                direction: reflect(-v, h),
+                weight: Vec3::ONE,
+                pdf: 0.0,
+            };
+        }
+        return BsdfSample {
+            direction: refracted.normalize(),
+            weight: material.color,
+            pdf: 0.0,
+        };
+    }
+
+    let n_dot_v = n.dot(v).max(0.0001);
+    let u_lobe = (u.z - transmission) / (1.0 - transmission);
+    let coat_probability = clearcoat_probability(material, n_dot_v);
+    let u_base = (u_lobe - coat_probability) / (1.0 - coat_probability);
+    let l = if u_lobe < coat_probability {
+        let coat_roughness =
+            perceptual_roughness_to_roughness(material.clearcoat_perceptual_roughness);
+        reflect(
+            -v,
+            basis * sample_ggx_vndf(v_local, coat_roughness, u.truncate()),
+        )
+    } else if u_base < specular_probability(material, n_dot_v) {
+        reflect(
+            -v,
+            basis * sample_ggx_vndf(v_local, roughness, u.truncate()),
+        )
+    } else {
+        basis * sample_cosine_hemisphere(u.truncate())
+    };
+    let pdf = brdf_pdf(material, n, v, l);
+    if pdf <= 0.0 {
+        return BsdfSample {
+            direction: l,
+            weight: Vec3::ZERO,
+            pdf: 0.0,
+        };
+    }
+    BsdfSample {
+        direction: l,
+        weight: evaluate_brdf(material, n, v, l) / pdf,
+        pdf,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::camera::CameraRenderGraph;
+
+    use super::*;
+    use crate::ray_tracing::RayTracingGraph;
+
+    const SIZE: UVec2 = UVec2::new(32, 32);
+
+    /// A world holding what the extract systems read, lit by a uniform environment of
+    /// `ambient` cd/m²
+    fn scene(ambient: f32) -> World {
+        let mut world = World::new();
+        world.init_resource::<Assets<Mesh>>();
+        world.init_resource::<Assets<StandardMaterial>>();
+        world.init_resource::<Assets<Image>>();
+        world.init_resource::<Events<AssetEvent<Mesh>>>();
+        world.init_resource::<Events<AssetEvent<StandardMaterial>>>();
+        world.init_resource::<Events<AssetEvent<Image>>>();
+        world.insert_resource(AmbientLight {
+            color: Color::WHITE,
+            brightness: ambient,
+        });
+        world
+    }
+
+    fn spawn(world: &mut World, mesh: Mesh, material: StandardMaterial, transform: Transform) {
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(mesh);
+        let material = world
+            .resource_mut::<Assets<StandardMaterial>>()
+            .add(material);
+        world.spawn((mesh, material, GlobalTransform::from(transform)));
+    }
+
+    /// Renders from `position` towards the origin, returning the exposed radiance of every pixel
+    /// and the exposure it was scaled by
+    fn render_from(world: &mut World, position: Vec3, samples: u32) -> (Vec<Vec3>, f32) {
+        let camera = world
+            .spawn((
+                Projection::Perspective(default()),
+                GlobalTransform::from(
+                    Transform::from_translation(position).looking_at(Vec3::ZERO, Vec3::Y),
+                ),
+                CameraRenderGraph::new(RayTracingGraph),
+            ))
+            .id();
+        let info = extract_scene(world);
+        let view = CpuView::extract(world, camera, SIZE).unwrap();
+        let image = render(&info, &view, samples);
+        let pixels = image
+            .data
+            .chunks(16)
+            .map(|texel| {
+                let channel =
+                    |i: usize| f32::from_le_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap());
+                Vec3::new(channel(0), channel(1), channel(2))
+            })
+            .collect();
+        (pixels, view.exposure)
+    }
+
+    /// Average of the pixels within `radius` of the center of the image
+    fn center(pixels: &[Vec3], radius: u32) -> Vec3 {
+        let middle = SIZE.as_ivec2() / 2;
+        let mut sum = Vec3::ZERO;
+        let mut count = 0;
+        for (index, pixel) in pixels.iter().enumerate() {
+            let position = UVec2::new(index as u32 % SIZE.x, index as u32 / SIZE.x).as_ivec2();
+            if (position - middle).abs().max_element() < radius as i32 {
+                sum += *pixel;
+                count += 1;
+            }
+        }
+        sum / count as f32
+    }
+
+    #[test]
+    fn white_furnace() {
+        // lossless spheres under a uniform environment disappear into it
+        let ambient = 1000.0;
+        for material in [
+            StandardMaterial {
+                base_color: Color::WHITE,
+                metallic: 1.0,
+                perceptual_roughness: 0.0,
+                ..default()
+            },
+            StandardMaterial {
+                base_color: Color::WHITE,
+                perceptual_roughness: 0.0,
+                specular_transmission: 1.0,
+                ior: 1.5,
+                ..default()
+            },
+        ] {
+            let mut world = scene(ambient);
+            let sphere = Sphere::new(1.0).mesh().uv(64, 32);
+            spawn(&mut world, sphere, material, Transform::IDENTITY);
+            let (pixels, exposure) = render_from(&mut world, Vec3::Z * 4.0, 128);
+            let expected = Vec3::splat(ambient * exposure);
+            assert!(pixels[0].abs_diff_eq(expected, expected.x * 1e-4));
+            let sphere = center(&pixels, 6);
+            assert!(
+                sphere.abs_diff_eq(expected, expected.x * 0.05),
+                "{sphere} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn emissive_quad() {
+        let emissive = LinearRgba::rgb(2000.0, 1000.0, 500.0);
+        let mut world = scene(0.0);
+        let material = StandardMaterial {
+            base_color: Color::BLACK,
+            emissive,
+            // emissive radiance in cd/m² like the lights, rather than already exposed
+            emissive_exposure_weight: 1.0,
+            ..default()
+        };
+        spawn(
+            &mut world,
+            Rectangle::new(2.0, 2.0).into(),
+            material,
+            Transform::IDENTITY,
+        );
+        // emission is two sided
+        for position in [Vec3::Z * 3.0, Vec3::NEG_Z * 3.0] {
+            let (pixels, exposure) = render_from(&mut world, position, 4);
+            let expected = emissive.to_vec3() * exposure;
+            let quad = center(&pixels, 8);
+            assert!(
+                quad.abs_diff_eq(expected, expected.x * 1e-4),
+                "{quad} != {expected}"
+            );
+            assert_eq!(pixels[0], Vec3::ZERO);
+        }
+    }
+}
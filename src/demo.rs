@@ -0,0 +1,50 @@
+use bevy::prelude::*;
+
+/// Spawns the example scene, a tilted cube and four spheres on a plane lit by a point light
+///
+/// Returns the cube so callers can animate it.
+pub fn spawn_demo_scene(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) -> Entity {
+    let material_blue = materials.add(Color::from(LinearRgba::BLUE));
+    let material_red = materials.add(Color::srgb(1.0, 0.0, 0.0));
+    let cube = commands
+        .spawn(MaterialMeshBundle {
+            mesh: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+            material: material_blue.clone(),
+            transform: Transform::from_rotation(Quat::from_axis_angle(
+                Vec3::X,
+                45.0_f32.to_radians(),
+            )),
+            ..default()
+        })
+        .id();
+    commands.spawn(MaterialMeshBundle {
+        mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(5.0))),
+        material: material_blue,
+        ..default()
+    });
+    for (x, z) in [(1., 1.), (-1., 1.), (1., -1.), (-1., -1.)] {
+        commands.spawn(MaterialMeshBundle {
+            mesh: meshes.add(Sphere::new(0.5).mesh()),
+            material: material_red.clone(),
+            transform: Transform::from_xyz(x, 0.5, z),
+            ..default()
+        });
+    }
+    // high above the scene, with the range to reach it and the intensity to light it from there
+    commands.spawn(PointLightBundle {
+        transform: Transform::from_xyz(0.0, 50.0, 0.0),
+        point_light: PointLight {
+            color: Color::WHITE,
+            intensity: 30_000_000.0,
+            range: 100.0,
+            radius: 1.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    cube
+}
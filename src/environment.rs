@@ -0,0 +1,503 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    core_pipeline::Skybox,
+    ecs::entity::EntityHashMap,
+    pbr::{environment_map::EnvironmentMapLight, light_consts::lux::DIRECT_SUNLIGHT},
+    prelude::*,
+    render::{
+        camera::CameraRenderGraph,
+        render_asset::RenderAssets,
+        render_graph::RenderSubGraph,
+        render_resource::{
+            Buffer, ShaderType, TextureFormat, TextureView, TextureViewDimension, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::{FallbackImage, GpuImage},
+        Extract,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    buffer::GpuArray,
+    ray_tracing::{RayTracingGraph, RayTracingInfo},
+    sky::{PhysicalSky, PreethamSky},
+};
+
+/// Largest grid an environment's importance sampling tables are built over
+const MAX_CDF_SIZE: UVec2 = UVec2::new(512, 256);
+/// Grid the sampling tables of a [`PhysicalSky`] are built over, they are rebuilt whenever the
+/// sun moves
+const SKY_CDF_SIZE: UVec2 = UVec2::new(128, 64);
+
+/// An HDR equirectangular image, like a `.hdr` or `.exr` panorama, lighting everything a ray
+/// traced camera sees
+///
+/// Takes precedence over the camera's [`PhysicalSky`], [`Skybox`] and [`EnvironmentMapLight`].
+/// Without any of them rays leaving the scene see the [`AmbientLight`].
+#[derive(Component, Reflect, Clone)]
+#[reflect(Component)]
+pub struct EquirectangularEnvironment {
+    pub image: Handle<Image>,
+    /// Scale applied to the image so its values end up in cd/m², like [`Skybox::brightness`]
+    pub intensity: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum EnvironmentKind {
+    Uniform = 0,
+    Equirectangular = 1,
+    Cube = 2,
+    Sky = 3,
+}
+
+/// Where the environment of a ray traced view comes from
+#[derive(Component, Clone, PartialEq)]
+pub struct ExtractedEnvironment {
+    pub(crate) kind: EnvironmentKind,
+    pub(crate) image: Option<AssetId<Image>>,
+    /// Radiance in cd/m² of the ambient light, or the scale applied to the image or sky
+    pub(crate) color: Vec3,
+    pub(crate) sky: PreethamSky,
+}
+
+#[derive(ShaderType, Default, Clone, PartialEq)]
+pub struct GpuEnvironment {
+    pub color: Vec3,
+    pub kind: u32,
+    /// Size of the grid the sampling tables were built over, zero when they couldn't be built
+    pub cdf_size: UVec2,
+    pub sky: PreethamSky,
+}
+
+/// Tables to importance sample an environment image by luminance
+///
+/// The image is looked at through an equirectangular grid, whatever its layout. `data` holds the
+/// marginal CDF over the grid's rows followed by the CDF of every row.
+struct EnvironmentCdf {
+    size: UVec2,
+    data: GpuArray<f32>,
+}
+
+impl EnvironmentCdf {
+    /// Builds the tables from the weight of every cell, rows first, they stay empty when all
+    /// weights are zero
+    fn new(weights: &[Vec<f32>]) -> Self {
+        let mut cdf = Self {
+            size: UVec2::ZERO,
+            data: GpuArray::new("ray_tracing_environment_cdf"),
+        };
+        let Some(first_row) = weights.first() else {
+            return cdf;
+        };
+        let size = UVec2::new(first_row.len() as u32, weights.len() as u32);
+        let mut marginal = vec![0.0];
+        let mut rows = Vec::with_capacity(weights.len() * (size.x as usize + 1));
+        for row in weights {
+            let row_sum: f32 = row.iter().sum();
+            marginal.push(marginal.last().unwrap() + row_sum);
+            rows.push(0.0);
+            let mut sum = 0.0;
+            for weight in row {
+                sum += weight;
+                rows.push(if row_sum > 0.0 { sum / row_sum } else { 0.0 });
+            }
+        }
+        let total = *marginal.last().unwrap();
+        if total <= 0.0 {
+            return cdf;
+        }
+        cdf.size = size;
+        cdf.data
+            .replace(marginal.iter().map(|sum| sum / total).chain(rows).collect());
+        cdf
+    }
+
+    fn from_image(image: &Image, kind: EnvironmentKind) -> Self {
+        let image_size = image.size();
+        let size = match kind {
+            EnvironmentKind::Equirectangular => image_size.min(MAX_CDF_SIZE),
+            _ => UVec2::new(image_size.x * 4, image_size.x * 2).min(MAX_CDF_SIZE),
+        };
+        // cells are weighted by the average of every texel they cover so small bright spots
+        // between the texels a single lookup would hit still get sampled
+        let weights = cell_weights(size, |cell, uv| match kind {
+            EnvironmentKind::Equirectangular => {
+                let min = cell * image_size / size;
+                let max = ((cell + 1) * image_size / size).max(min + 1);
+                average((min.y..max.y).flat_map(|y| {
+                    (min.x..max.x).map(move |x| texel_color(image, 0, UVec2::new(x, y)))
+                }))
+            }
+            _ => {
+                let steps = (image_size.x * 4).div_ceil(size.x).max(1);
+                let cell_size = 1.0 / size.as_vec2();
+                average((0..steps * steps).map(|i| {
+                    let offset = (Vec2::new((i % steps) as f32, (i / steps) as f32) + 0.5)
+                        / steps as f32
+                        - 0.5;
+                    let direction = equirectangular_direction(uv + offset * cell_size);
+                    let (face, texel) = cube_texel(direction, image_size.x);
+                    texel_color(image, face, texel)
+                }))
+            }
+        });
+        match weights {
+            Some(weights) => Self::new(&weights),
+            None => {
+                warn!(
+                    "Environment {:?} can't be read, it is sampled uniformly",
+                    image.size()
+                );
+                Self::new(&[])
+            }
+        }
+    }
+
+    fn from_sky(sky: &PreethamSky) -> Self {
+        let weights = cell_weights(SKY_CDF_SIZE, |_, uv| {
+            Some(sky.radiance(equirectangular_direction(uv)))
+        });
+        Self::new(&weights.unwrap_or_default())
+    }
+}
+
+/// Luminance of every cell of a `size` grid weighted by the solid angle it covers, `radiance`
+/// is given a cell and the uv of its center
+fn cell_weights(
+    size: UVec2,
+    radiance: impl Fn(UVec2, Vec2) -> Option<Vec3>,
+) -> Option<Vec<Vec<f32>>> {
+    (0..size.y)
+        .map(|y| {
+            let sin_theta = ((y as f32 + 0.5) / size.y as f32 * PI).sin();
+            (0..size.x)
+                .map(|x| {
+                    let uv = (Vec2::new(x as f32, y as f32) + 0.5) / size.as_vec2();
+                    let color = radiance(UVec2::new(x, y), uv)?;
+                    Some(color.dot(Vec3::new(0.2126, 0.7152, 0.0722)) * sin_theta)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Mean of some texel colors, `None` if any of them can't be read
+fn average(colors: impl Iterator<Item = Option<Vec3>>) -> Option<Vec3> {
+    let mut count = 0;
+    let mut sum = Vec3::ZERO;
+    for color in colors {
+        sum += color?;
+        count += 1;
+    }
+    Some(sum / count.max(1) as f32)
+}
+
+/// Direction an equirectangular uv looks towards, matching `equirectangular_direction` in the
+/// shader
+fn equirectangular_direction(uv: Vec2) -> Vec3 {
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let theta = uv.y * PI;
+    Vec3::new(
+        theta.sin() * phi.cos(),
+        theta.cos(),
+        theta.sin() * phi.sin(),
+    )
+}
+
+/// Face and texel a direction hits on a cubemap, which are left handed like the shader samples
+/// them
+pub(crate) fn cube_texel(direction: Vec3, face_size: u32) -> (u32, UVec2) {
+    let d = direction * Vec3::new(1.0, 1.0, -1.0);
+    let abs = d.abs();
+    let (face, u, v, major) = if abs.x >= abs.y && abs.x >= abs.z {
+        if d.x > 0.0 {
+            (0, -d.z, -d.y, abs.x)
+        } else {
+            (1, d.z, -d.y, abs.x)
+        }
+    } else if abs.y >= abs.z {
+        if d.y > 0.0 {
+            (2, d.x, d.z, abs.y)
+        } else {
+            (3, d.x, -d.z, abs.y)
+        }
+    } else if d.z > 0.0 {
+        (4, d.x, -d.y, abs.z)
+    } else {
+        (5, -d.x, -d.y, abs.z)
+    };
+    let uv = (Vec2::new(u, v) / major * 0.5 + 0.5) * face_size as f32;
+    (face, uv.as_uvec2().min(UVec2::splat(face_size - 1)))
+}
+
+/// Linear color of a texel in the first mip of a layer
+pub(crate) fn texel_color(image: &Image, layer: u32, texel: UVec2) -> Option<Vec3> {
+    let format = image.texture_descriptor.format;
+    let size = image.texture_descriptor.size;
+    let bytes = format.block_copy_size(None)? as usize;
+    let layer_size: usize = (0..image.texture_descriptor.mip_level_count)
+        .map(|level| ((size.width >> level).max(1) * (size.height >> level).max(1)) as usize)
+        .sum::<usize>()
+        * bytes;
+    let offset = layer as usize * layer_size + (texel.y * size.width + texel.x) as usize * bytes;
+    let data = image.data.get(offset..offset + bytes)?;
+    let float = |i: usize| f32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+    let half = |i: usize| f16_to_f32(u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]));
+    Some(match format {
+        TextureFormat::Rgba32Float => Vec3::new(float(0), float(1), float(2)),
+        TextureFormat::Rgba16Float => Vec3::new(half(0), half(1), half(2)),
+        TextureFormat::Rgb9e5Ufloat => {
+            let packed = u32::from_le_bytes(data.try_into().unwrap());
+            let scale = 2f32.powi((packed >> 27) as i32 - 15 - 9);
+            UVec3::new(
+                packed & 0x1ff,
+                (packed >> 9) & 0x1ff,
+                (packed >> 18) & 0x1ff,
+            )
+            .as_vec3()
+                * scale
+        }
+        TextureFormat::Rgba8UnormSrgb => {
+            LinearRgba::from(Srgba::rgb_u8(data[0], data[1], data[2])).to_vec3()
+        }
+        TextureFormat::Rgba8Unorm => {
+            Vec3::new(data[0] as f32, data[1] as f32, data[2] as f32) / 255.0
+        }
+        _ => return None,
+    })
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 => f32::INFINITY,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Environment resources of a ray traced view
+#[derive(Component)]
+pub struct ViewEnvironment {
+    pub equirectangular: TextureView,
+    pub cube: TextureView,
+    pub uniform: Buffer,
+    pub cdf: Buffer,
+}
+
+#[derive(Resource, Default)]
+pub struct EnvironmentCache {
+    cdfs: HashMap<AssetId<Image>, EnvironmentCdf>,
+    /// Tables of the sky each view last saw
+    sky_cdfs: EntityHashMap<(PreethamSky, EnvironmentCdf)>,
+    uniforms: EntityHashMap<UniformBuffer<GpuEnvironment>>,
+    /// Bound in place of the tables of environments that have none
+    empty_cdf: Option<GpuArray<f32>>,
+}
+
+#[allow(clippy::type_complexity)]
+pub fn extract_environments(
+    mut commands: Commands,
+    mut cache: ResMut<EnvironmentCache>,
+    cameras: Extract<
+        Query<(
+            Entity,
+            &CameraRenderGraph,
+            Option<&EquirectangularEnvironment>,
+            Option<&PhysicalSky>,
+            Option<&Skybox>,
+            Option<&EnvironmentMapLight>,
+        )>,
+    >,
+    directional_lights: Extract<Query<(&DirectionalLight, &GlobalTransform, &InheritedVisibility)>>,
+    ambient_light: Extract<Res<AmbientLight>>,
+    images: Extract<Res<Assets<Image>>>,
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
+) {
+    for event in image_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            cache.cdfs.remove(id);
+        }
+    }
+    let sun = directional_lights
+        .iter()
+        .find(|(_, _, visibility)| visibility.get())
+        .map(|(light, transform, _)| (light.illuminance, transform.back()));
+    let mut sky_cdfs = EntityHashMap::default();
+    for (entity, render_graph, equirectangular, physical_sky, skybox, environment_map) in &cameras {
+        if **render_graph != RayTracingGraph.intern() {
+            continue;
+        }
+        if let (None, Some(physical_sky), Some((illuminance, direction))) =
+            (equirectangular, physical_sky, sun)
+        {
+            let sky = PreethamSky::new(physical_sky, direction.into());
+            let cdf = match cache.sky_cdfs.remove(&entity) {
+                Some((cached, cdf)) if cached == sky => cdf,
+                _ => EnvironmentCdf::from_sky(&sky),
+            };
+            sky_cdfs.insert(entity, (sky.clone(), cdf));
+            // the model's radiance is in kcd/m² for a sun of DIRECT_SUNLIGHT
+            let scale = illuminance / DIRECT_SUNLIGHT * 1000.0;
+            commands.get_or_spawn(entity).insert(ExtractedEnvironment {
+                kind: EnvironmentKind::Sky,
+                image: None,
+                color: Vec3::splat(scale),
+                sky,
+            });
+            continue;
+        }
+        let source = if let Some(environment) = equirectangular {
+            Some((
+                EnvironmentKind::Equirectangular,
+                &environment.image,
+                environment.intensity,
+            ))
+        } else if let Some(skybox) = skybox {
+            Some((EnvironmentKind::Cube, &skybox.image, skybox.brightness))
+        } else {
+            environment_map.map(|environment_map| {
+                (
+                    EnvironmentKind::Cube,
+                    &environment_map.specular_map,
+                    environment_map.intensity,
+                )
+            })
+        };
+        let environment = match source.and_then(|(kind, handle, intensity)| {
+            let image = images.get(handle)?;
+            let is_cube = image
+                .texture_view_descriptor
+                .as_ref()
+                .and_then(|view| view.dimension)
+                == Some(TextureViewDimension::Cube);
+            (is_cube == (kind == EnvironmentKind::Cube)).then_some((kind, handle, image, intensity))
+        }) {
+            Some((kind, handle, image, intensity)) => {
+                cache
+                    .cdfs
+                    .entry(handle.id())
+                    .or_insert_with(|| EnvironmentCdf::from_image(image, kind));
+                ExtractedEnvironment {
+                    kind,
+                    image: Some(handle.id()),
+                    color: Vec3::splat(intensity),
+                    sky: default(),
+                }
+            }
+            None => ExtractedEnvironment {
+                kind: EnvironmentKind::Uniform,
+                image: None,
+                color: ambient_light.color.to_linear().to_vec3() * ambient_light.brightness,
+                sky: default(),
+            },
+        };
+        commands.get_or_spawn(entity).insert(environment);
+    }
+    cache.sky_cdfs = sky_cdfs;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_environments(
+    mut commands: Commands,
+    mut cache: ResMut<EnvironmentCache>,
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    views: Query<(Entity, &ExtractedEnvironment)>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    fallback_image: Res<FallbackImage>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let cache = &mut *cache;
+    let (device, queue) = (&*render_device, &*render_queue);
+    for cdf in cache.cdfs.values_mut() {
+        cdf.data.write_buffer(device, queue);
+    }
+    for (_, cdf) in cache.sky_cdfs.values_mut() {
+        cdf.data.write_buffer(device, queue);
+    }
+    let empty_cdf = cache.empty_cdf.get_or_insert_with(|| {
+        let mut empty = GpuArray::new("ray_tracing_environment_cdf");
+        empty.write_buffer(device, queue);
+        empty
+    });
+
+    let mut uniforms = EntityHashMap::default();
+    for (entity, environment) in &views {
+        let image = environment.image.and_then(|id| gpu_images.get(id));
+        let cdf = match environment.kind {
+            EnvironmentKind::Sky => cache.sky_cdfs.get(&entity).map(|(_, cdf)| cdf),
+            _ => environment.image.and_then(|id| cache.cdfs.get(&id)),
+        };
+        // environments whose image isn't on the gpu yet stay black
+        let gpu_environment = match (environment.kind, image) {
+            (EnvironmentKind::Uniform | EnvironmentKind::Sky, _) | (_, Some(_)) => GpuEnvironment {
+                color: environment.color,
+                kind: environment.kind as u32,
+                cdf_size: cdf.map_or(UVec2::ZERO, |cdf| cdf.size),
+                sky: environment.sky.clone(),
+            },
+            _ => GpuEnvironment::default(),
+        };
+        let mut uniform = cache.uniforms.remove(&entity).unwrap_or_default();
+        if *uniform.get() != gpu_environment || uniform.buffer().is_none() {
+            uniform.set(gpu_environment);
+            uniform.write_buffer(device, queue);
+            ray_tracing_info.reset_accumulation();
+        }
+        let texture = |kind| match image {
+            Some(image) if environment.kind == kind => &image.texture_view,
+            _ if kind == EnvironmentKind::Cube => &fallback_image.cube.texture_view,
+            _ => &fallback_image.d2.texture_view,
+        };
+        let cdf = cdf
+            .filter(|cdf| cdf.size != UVec2::ZERO)
+            .map_or(&*empty_cdf, |cdf| &cdf.data);
+        commands.entity(entity).insert(ViewEnvironment {
+            equirectangular: texture(EnvironmentKind::Equirectangular).clone(),
+            cube: texture(EnvironmentKind::Cube).clone(),
+            uniform: uniform.buffer().unwrap().clone(),
+            cdf: cdf.buffer().unwrap().clone(),
+        });
+        uniforms.insert(entity, uniform);
+    }
+    cache.uniforms = uniforms;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension},
+    };
+
+    use super::*;
+
+    #[test]
+    fn cells_cover_all_their_texels() {
+        // twice the cdf size, so every cell covers 2×2 texels and only the second is lit
+        let size = MAX_CDF_SIZE * 2;
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 16],
+            TextureFormat::Rgba32Float,
+            RenderAssetUsages::default(),
+        );
+        let texel = ((size.y / 2 + 1) * size.x + 1) as usize * 16;
+        for channel in 0..3 {
+            image.data[texel + channel * 4..texel + channel * 4 + 4]
+                .copy_from_slice(&1.0f32.to_le_bytes());
+        }
+        let cdf = EnvironmentCdf::from_image(&image, EnvironmentKind::Equirectangular);
+        assert_eq!(cdf.size, MAX_CDF_SIZE);
+    }
+}
@@ -0,0 +1,585 @@
+use bevy::{
+    math::bounding::Aabb3d,
+    prelude::*,
+    render::{mesh::VertexAttributeValues, Extract},
+    utils::{hashbrown::hash_map::Entry, HashMap, HashSet},
+};
+use itertools::Itertools;
+
+use crate::{
+    bvh::{Bvh, BvhSettings},
+    light::build_emissive_triangles,
+    ray_tracing::{MeshInfo, MeshInstance, RayTracingInfo, SimpleMaterial, Triangle, Vertex},
+    texture::{can_resample, MAX_TEXTURES, NO_TEXTURE},
+};
+
+type InstanceQuery = (
+    Entity,
+    &'static Handle<Mesh>,
+    &'static Handle<StandardMaterial>,
+    &'static GlobalTransform,
+);
+
+type InstanceChanged = Or<(
+    Changed<GlobalTransform>,
+    Changed<Handle<Mesh>>,
+    Changed<Handle<StandardMaterial>>,
+)>;
+
+/// Entities that stopped being ray traced this frame
+///
+/// Removed components can't be read from the extract schedule so they are collected in the main
+/// world first.
+#[derive(Resource, Default)]
+pub struct RemovedInstances(Vec<Entity>);
+
+pub fn collect_removed_instances(
+    mut removed: ResMut<RemovedInstances>,
+    mut meshes: RemovedComponents<Handle<Mesh>>,
+    mut materials: RemovedComponents<Handle<StandardMaterial>>,
+) {
+    removed.0.clear();
+    removed.0.extend(meshes.read().chain(materials.read()));
+}
+
+/// Render world bookkeeping mapping entities and assets to their slots in [`RayTracingInfo`]
+///
+/// Only what changed since the last frame is extracted and patched. Meshes are only rebuilt
+/// when their topology changes and moving instances refit the top level, until either degrades
+/// past [`BvhSettings::rebuild_threshold`].
+#[derive(Resource, Default)]
+pub struct SceneCache {
+    meshes: HashMap<AssetId<Mesh>, BottomLevel>,
+    free_meshes: Vec<u32>,
+    materials: HashMap<AssetId<StandardMaterial>, u32>,
+    free_materials: Vec<u32>,
+    textures: HashMap<AssetId<Image>, u32>,
+    free_textures: Vec<u32>,
+    instances: HashMap<Entity, MeshInstance>,
+    /// Entities whose mesh, material or textures have not loaded yet
+    pending: HashSet<Entity>,
+    /// Modified materials in use whose textures have not loaded yet
+    pending_materials: HashSet<AssetId<StandardMaterial>>,
+    tlas: Bvh,
+    /// Entities in the order the leaves of `tlas` reference them
+    tlas_entities: Vec<Entity>,
+    tlas_leaves: HashMap<Entity, u32>,
+    /// Triangles left behind in the buffers by meshes that were rebuilt or removed
+    garbage: usize,
+}
+
+impl SceneCache {
+    fn mesh_slot(
+        &mut self,
+        id: AssetId<Mesh>,
+        mesh: &Mesh,
+        ray_tracing_info: &mut RayTracingInfo,
+    ) -> Option<u32> {
+        let blas = match self.meshes.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut blas = BottomLevel::new(mesh)?;
+                blas.slot = self
+                    .free_meshes
+                    .pop()
+                    .unwrap_or(ray_tracing_info.meshes.len() as u32);
+                blas.append(ray_tracing_info);
+                entry.insert(blas)
+            }
+        };
+        Some(blas.slot)
+    }
+
+    fn material_slot(
+        &mut self,
+        id: AssetId<StandardMaterial>,
+        material: &StandardMaterial,
+        images: &Assets<Image>,
+        ray_tracing_info: &mut RayTracingInfo,
+    ) -> u32 {
+        if let Some(&slot) = self.materials.get(&id) {
+            return slot;
+        }
+        let slot = self
+            .free_materials
+            .pop()
+            .unwrap_or(ray_tracing_info.materials.len() as u32);
+        let material = self.simple_material(material, images, ray_tracing_info);
+        ray_tracing_info.materials.set(slot as usize, material);
+        self.materials.insert(id, slot);
+        slot
+    }
+
+    /// Converts a material, packing the textures it references
+    fn simple_material(
+        &mut self,
+        material: &StandardMaterial,
+        images: &Assets<Image>,
+        ray_tracing_info: &mut RayTracingInfo,
+    ) -> SimpleMaterial {
+        let mut texture = |image: &Option<Handle<Image>>| {
+            self.texture_slot(image.as_ref(), images, ray_tracing_info)
+        };
+        SimpleMaterial {
+            base_color_texture: texture(&material.base_color_texture),
+            emissive_texture: texture(&material.emissive_texture),
+            metallic_roughness_texture: texture(&material.metallic_roughness_texture),
+            normal_map_texture: texture(&material.normal_map_texture),
+            ..material.into()
+        }
+    }
+
+    fn texture_slot(
+        &mut self,
+        image: Option<&Handle<Image>>,
+        images: &Assets<Image>,
+        ray_tracing_info: &mut RayTracingInfo,
+    ) -> u32 {
+        let Some(image) = image else {
+            return NO_TEXTURE;
+        };
+        if let Some(&slot) = self.textures.get(&image.id()) {
+            return slot;
+        }
+        let Some(image_asset) = images
+            .get(image)
+            .filter(|image| can_resample(image.texture_descriptor.format))
+        else {
+            warn!(
+                "Texture {:?} has a format the ray tracer can't sample",
+                image.id()
+            );
+            return NO_TEXTURE;
+        };
+        let slot = match self.free_textures.pop() {
+            Some(slot) => slot,
+            None if ray_tracing_info.textures.len() < MAX_TEXTURES => {
+                ray_tracing_info.textures.len() as u32
+            }
+            None => {
+                warn!(
+                    "More than {MAX_TEXTURES} textures, {:?} is ignored",
+                    image.id()
+                );
+                return NO_TEXTURE;
+            }
+        };
+        // resampled when it is uploaded, which doesn't hold up the main world
+        ray_tracing_info
+            .textures
+            .set(slot as usize, image_asset.clone());
+        self.textures.insert(image.id(), slot);
+        slot
+    }
+
+    fn instance_aabb(&self, entity: &Entity, ray_tracing_info: &RayTracingInfo) -> Aabb3d {
+        let instance = &self.instances[entity];
+        let mesh = &ray_tracing_info.meshes.data()[instance.mesh as usize];
+        transform_aabb(mesh.aabb_min, mesh.aabb_max, &instance.world_from_object)
+    }
+
+    fn rebuild_tlas(&mut self, ray_tracing_info: &mut RayTracingInfo) {
+        let entities: Vec<Entity> = self.instances.keys().copied().collect();
+        let bounds: Vec<Aabb3d> = entities
+            .iter()
+            .map(|entity| self.instance_aabb(entity, ray_tracing_info))
+            .collect();
+        self.tlas = Bvh::build(&bounds);
+        self.tlas_entities = self
+            .tlas
+            .indices
+            .iter()
+            .map(|&i| entities[i as usize])
+            .collect();
+        self.tlas_leaves = self
+            .tlas_entities
+            .iter()
+            .enumerate()
+            .map(|(leaf, entity)| (*entity, leaf as u32))
+            .collect();
+        ray_tracing_info.instances.replace(
+            self.tlas_entities
+                .iter()
+                .map(|entity| self.instances[entity].clone())
+                .collect(),
+        );
+        ray_tracing_info.tlas_nodes.replace(self.tlas.nodes.clone());
+    }
+
+    fn refit_tlas(&mut self, ray_tracing_info: &mut RayTracingInfo, settings: &BvhSettings) {
+        let bounds: Vec<Aabb3d> = self
+            .tlas_entities
+            .iter()
+            .map(|entity| self.instance_aabb(entity, ray_tracing_info))
+            .collect();
+        self.tlas.refit(&bounds);
+        if self.tlas.needs_rebuild(settings) {
+            self.rebuild_tlas(ray_tracing_info);
+        } else {
+            ray_tracing_info.tlas_nodes.replace(self.tlas.nodes.clone());
+        }
+    }
+
+    /// Moves every mesh to the front of the geometry buffers, dropping what was left behind
+    fn compact(&mut self, ray_tracing_info: &mut RayTracingInfo) {
+        ray_tracing_info.vertices.clear();
+        ray_tracing_info.triangles.clear();
+        ray_tracing_info.nodes.clear();
+        for blas in self.meshes.values_mut() {
+            blas.append(ray_tracing_info);
+        }
+        self.garbage = 0;
+    }
+}
+
+/// A mesh's object space geometry and its bottom-level BVH
+struct BottomLevel {
+    vertices: Vec<Vertex>,
+    /// Triangles in the order the leaves of `bvh` reference them
+    triangles: Vec<Triangle>,
+    bvh: Bvh,
+    /// Index of the mesh in [`RayTracingInfo::meshes`]
+    slot: u32,
+    first_vertex: u32,
+    first_tri: u32,
+    first_node: u32,
+}
+
+impl BottomLevel {
+    fn new(mesh: &Mesh) -> Option<Self> {
+        let (vertices, triangles) = mesh_geometry(mesh)?;
+        let bvh = Bvh::build(&triangle_bounds(&vertices, &triangles));
+        let triangles = bvh
+            .indices
+            .iter()
+            .map(|&i| triangles[i as usize].clone())
+            .collect();
+        Some(Self {
+            vertices,
+            triangles,
+            bvh,
+            slot: 0,
+            first_vertex: 0,
+            first_tri: 0,
+            first_node: 0,
+        })
+    }
+
+    /// Refits the BVH to moved vertices, returns false if the mesh has to be rebuilt instead
+    fn refit(&mut self, mesh: &Mesh, settings: &BvhSettings) -> bool {
+        let Some((vertices, triangles)) = mesh_geometry(mesh) else {
+            return false;
+        };
+        let same_topology = vertices.len() == self.vertices.len()
+            && triangles.len() == self.triangles.len()
+            && self
+                .bvh
+                .indices
+                .iter()
+                .zip(&self.triangles)
+                .all(|(&i, tri)| triangles[i as usize].indices == tri.indices);
+        if !same_topology {
+            return false;
+        }
+        self.vertices = vertices;
+        self.bvh
+            .refit(&triangle_bounds(&self.vertices, &self.triangles));
+        !self.bvh.needs_rebuild(settings)
+    }
+
+    /// Updates the mesh after its asset changed, returns how many triangles were left behind
+    fn update(
+        &mut self,
+        mesh: &Mesh,
+        ray_tracing_info: &mut RayTracingInfo,
+        settings: &BvhSettings,
+    ) -> usize {
+        if self.refit(mesh, settings) {
+            ray_tracing_info
+                .vertices
+                .write(self.first_vertex as usize, self.vertices.iter().copied());
+            ray_tracing_info.nodes.write(
+                self.first_node as usize,
+                self.bvh.flattened(self.first_node, self.first_tri),
+            );
+            self.write_mesh_info(ray_tracing_info);
+            return 0;
+        }
+        let Some(rebuilt) = BottomLevel::new(mesh) else {
+            return 0;
+        };
+        let garbage = self.triangles.len();
+        *self = BottomLevel {
+            slot: self.slot,
+            ..rebuilt
+        };
+        self.append(ray_tracing_info);
+        garbage
+    }
+
+    /// Appends the geometry to the end of the buffers and points the mesh slot at it
+    fn append(&mut self, ray_tracing_info: &mut RayTracingInfo) {
+        self.first_vertex = ray_tracing_info.vertices.len() as u32;
+        self.first_tri = ray_tracing_info.triangles.len() as u32;
+        self.first_node = ray_tracing_info.nodes.len() as u32;
+        ray_tracing_info
+            .vertices
+            .extend(self.vertices.iter().copied());
+        ray_tracing_info
+            .triangles
+            .extend(self.triangles.iter().map(|tri| Triangle {
+                indices: tri.indices.map(|i| i + self.first_vertex),
+            }));
+        ray_tracing_info
+            .nodes
+            .extend(self.bvh.flattened(self.first_node, self.first_tri));
+        self.write_mesh_info(ray_tracing_info);
+    }
+
+    fn write_mesh_info(&self, ray_tracing_info: &mut RayTracingInfo) {
+        let aabb = self.bvh.nodes[0].aabb();
+        ray_tracing_info.meshes.set(
+            self.slot as usize,
+            MeshInfo {
+                first_tri: self.first_tri,
+                tri_count: self.triangles.len() as u32,
+                root_node: self.first_node,
+                aabb_min: aabb.min.into(),
+                aabb_max: aabb.max.into(),
+            },
+        );
+    }
+}
+
+fn mesh_geometry(mesh: &Mesh) -> Option<(Vec<Vertex>, Vec<Triangle>)> {
+    let (Some(pos), Some(norm), Some(indices)) = (
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION),
+        mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
+        mesh.indices(),
+    ) else {
+        println!("Mesh missing attribute");
+        return None;
+    };
+    let pos = pos.as_float3().unwrap();
+    let norm = norm.as_float3().unwrap();
+    let uv = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+        Some(VertexAttributeValues::Float32x2(uv)) => uv.as_slice(),
+        _ => &[],
+    };
+    let tangent = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+        Some(VertexAttributeValues::Float32x4(tangent)) => tangent.as_slice(),
+        _ => &[],
+    };
+    let vertices = (0..pos.len())
+        .map(|i| Vertex {
+            position: pos[i].into(),
+            normal: norm[i].into(),
+            uv: uv.get(i).copied().unwrap_or_default().into(),
+            tangent: tangent.get(i).copied().unwrap_or_default().into(),
+        })
+        .collect();
+    let triangles: Vec<Triangle> = indices
+        .iter()
+        .map(|i| i as u32)
+        .tuples::<(_, _, _)>()
+        .map(|(a, b, c)| Triangle { indices: [a, b, c] })
+        .collect();
+    if triangles.is_empty() {
+        return None;
+    }
+    Some((vertices, triangles))
+}
+
+fn triangle_bounds(vertices: &[Vertex], triangles: &[Triangle]) -> Vec<Aabb3d> {
+    triangles
+        .iter()
+        .map(|tri| {
+            Aabb3d::from_point_cloud(
+                Vec3::ZERO,
+                Quat::IDENTITY,
+                tri.indices.iter().map(|&i| vertices[i as usize].position),
+            )
+        })
+        .collect()
+}
+
+fn textures_loaded(material: &StandardMaterial, images: &Assets<Image>) -> bool {
+    [
+        &material.base_color_texture,
+        &material.emissive_texture,
+        &material.metallic_roughness_texture,
+        &material.normal_map_texture,
+    ]
+    .into_iter()
+    .flatten()
+    .all(|image| images.contains(image))
+}
+
+fn transform_aabb(min: Vec3, max: Vec3, transform: &Mat4) -> Aabb3d {
+    let corners = (0..8).map(|i| {
+        let corner = Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+        transform.transform_point3(corner)
+    });
+    Aabb3d::from_point_cloud(Vec3::ZERO, Quat::IDENTITY, corners)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_meshinfo(
+    mut cache: ResMut<SceneCache>,
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    changed: Extract<Query<InstanceQuery, InstanceChanged>>,
+    instances: Extract<Query<InstanceQuery>>,
+    removed: Extract<Res<RemovedInstances>>,
+    mut mesh_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    mut material_events: Extract<EventReader<AssetEvent<StandardMaterial>>>,
+    mut image_events: Extract<EventReader<AssetEvent<Image>>>,
+    mesh_assets: Extract<Res<Assets<Mesh>>>,
+    material_assets: Extract<Res<Assets<StandardMaterial>>>,
+    image_assets: Extract<Res<Assets<Image>>>,
+    bvh_settings: Extract<Res<BvhSettings>>,
+) {
+    let cache = &mut *cache;
+    let info = &mut *ray_tracing_info;
+
+    for event in image_events.read() {
+        match event {
+            AssetEvent::Modified { id } => {
+                if let (Some(&slot), Some(image)) = (
+                    cache.textures.get(id),
+                    image_assets
+                        .get(*id)
+                        .filter(|image| can_resample(image.texture_descriptor.format)),
+                ) {
+                    info.textures.set(slot as usize, image.clone());
+                    info.reset_accumulation();
+                }
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                if let Some(slot) = cache.textures.remove(id) {
+                    cache.free_textures.push(slot);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut modified_materials = std::mem::take(&mut cache.pending_materials);
+    for event in material_events.read() {
+        match event {
+            AssetEvent::Modified { id } => {
+                modified_materials.insert(*id);
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                modified_materials.remove(id);
+                if let Some(slot) = cache.materials.remove(id) {
+                    cache.free_materials.push(slot);
+                }
+            }
+            _ => {}
+        }
+    }
+    // like new instances, modified materials wait for their textures instead of losing them
+    let mut materials_changed = false;
+    for id in modified_materials {
+        let (Some(&slot), Some(material)) = (cache.materials.get(&id), material_assets.get(id))
+        else {
+            continue;
+        };
+        if !textures_loaded(material, &image_assets) {
+            cache.pending_materials.insert(id);
+            continue;
+        }
+        let material = cache.simple_material(material, &image_assets, info);
+        info.materials.set(slot as usize, material);
+        info.reset_accumulation();
+        materials_changed = true;
+    }
+
+    let mut refit_tlas = false;
+    let mut rebuild_tlas = false;
+    // moving instances keep their history, the motion vectors reproject it
+    let mut topology_changed = false;
+    for event in mesh_events.read() {
+        match event {
+            AssetEvent::Modified { id } => {
+                if let (Some(blas), Some(mesh)) = (cache.meshes.get_mut(id), mesh_assets.get(*id)) {
+                    cache.garbage += blas.update(mesh, info, &bvh_settings);
+                    refit_tlas = true;
+                    topology_changed = true;
+                }
+            }
+            AssetEvent::Removed { id } | AssetEvent::Unused { id } => {
+                if let Some(blas) = cache.meshes.remove(id) {
+                    cache.free_meshes.push(blas.slot);
+                    cache.garbage += blas.triangles.len();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for entity in &removed.0 {
+        cache.pending.remove(entity);
+        if cache.instances.remove(entity).is_some() {
+            rebuild_tlas = true;
+            topology_changed = true;
+        }
+    }
+
+    let pending = std::mem::take(&mut cache.pending);
+    let retried = pending
+        .iter()
+        .filter_map(|entity| instances.get(*entity).ok());
+    for (entity, mesh, material, transform) in changed.iter().chain(retried) {
+        let (Some(mesh_asset), Some(material_asset)) =
+            (mesh_assets.get(mesh), material_assets.get(material))
+        else {
+            cache.pending.insert(entity);
+            continue;
+        };
+        if !textures_loaded(material_asset, &image_assets) {
+            cache.pending.insert(entity);
+            continue;
+        }
+        let Some(mesh) = cache.mesh_slot(mesh.id(), mesh_asset, info) else {
+            continue;
+        };
+        let material = cache.material_slot(material.id(), material_asset, &image_assets, info);
+        let world_from_object = transform.compute_matrix();
+        let instance = MeshInstance {
+            world_from_object,
+            object_from_world: world_from_object.inverse(),
+            mesh,
+            material,
+        };
+        match (cache.tlas_leaves.get(&entity), cache.instances.get(&entity)) {
+            (Some(&leaf), Some(previous)) => {
+                topology_changed |= previous.mesh != mesh || previous.material != material;
+                info.instances.set(leaf as usize, instance.clone());
+                refit_tlas = true;
+            }
+            _ => {
+                rebuild_tlas = true;
+                topology_changed = true;
+            }
+        }
+        cache.instances.insert(entity, instance);
+    }
+
+    // compaction moves every mesh's triangles, which the emissive triangles point at
+    let compacted = cache.garbage > info.triangles.len() / 2;
+    if compacted {
+        cache.compact(info);
+    }
+    if rebuild_tlas {
+        cache.rebuild_tlas(info);
+    } else if refit_tlas {
+        cache.refit_tlas(info, &bvh_settings);
+    }
+    if topology_changed || compacted {
+        info.reset_accumulation();
+    }
+    if rebuild_tlas || refit_tlas || materials_changed || compacted {
+        build_emissive_triangles(info);
+    }
+}
@@ -0,0 +1,82 @@
+use bevy::{prelude::*, render::camera::CameraRenderGraph, scene::SceneInstanceReady};
+
+use crate::{environment::EquirectangularEnvironment, ray_tracing::RayTracingGraph};
+
+/// Sets up a spawned glTF scene for the ray tracer, insert it next to its [`SceneBundle`]
+///
+/// Bevy's loader maps `KHR_lights_punctual` onto the lights and the emissive strength,
+/// transmission, ior, volume and clearcoat extensions onto the [`StandardMaterial`]s the ray
+/// tracer extracts. Spot lights get their range as radius from it, which is reset as glTF lights
+/// are punctual. The scene's cameras render with the ray tracer unless another camera takes over
+/// their view, along with their [`EquirectangularEnvironment`]. Scenes from the other loaders,
+/// like [`PbrtPlugin`](crate::pbrt::PbrtPlugin)'s, are set up the same way.
+#[derive(Component, Reflect, Default, Clone, Debug)]
+#[reflect(Component)]
+pub struct RayTracedGltf {
+    /// Camera moved to the scene's active camera, whose cameras are then despawned
+    pub camera: Option<Entity>,
+}
+
+/// Applies [`RayTracedGltf`] to the scenes that finished spawning
+#[allow(clippy::too_many_arguments)]
+pub fn setup_gltf_scenes(
+    mut commands: Commands,
+    mut ready: EventReader<SceneInstanceReady>,
+    scenes: Query<&RayTracedGltf>,
+    children: Query<&Children>,
+    parents: Query<&Parent>,
+    transforms: Query<&Transform>,
+    mut spot_lights: Query<&mut SpotLight>,
+    mut cameras: Query<(
+        &mut Camera,
+        &mut CameraRenderGraph,
+        Option<&Projection>,
+        Option<&EquirectangularEnvironment>,
+    )>,
+) {
+    for event in ready.read() {
+        let Ok(scene) = scenes.get(event.parent) else {
+            continue;
+        };
+        let mut active_view = None;
+        for entity in children.iter_descendants(event.parent) {
+            if let Ok(mut light) = spot_lights.get_mut(entity) {
+                light.radius = 0.0;
+            }
+            let Ok((mut camera, mut graph, projection, environment)) = cameras.get_mut(entity)
+            else {
+                continue;
+            };
+            if camera.is_active && active_view.is_none() {
+                // transforms aren't propagated to the new entities yet
+                let transform = parents
+                    .iter_ancestors(entity)
+                    .filter_map(|ancestor| transforms.get(ancestor).ok())
+                    .fold(
+                        transforms.get(entity).copied().unwrap_or_default(),
+                        |child, parent| parent.mul_transform(child),
+                    );
+                active_view = Some((transform, projection.cloned(), environment.cloned()));
+            }
+            if scene.camera.is_some() {
+                commands.entity(entity).despawn_recursive();
+            } else {
+                camera.hdr = true;
+                graph.set(RayTracingGraph);
+            }
+        }
+        let (Some(camera), Some((transform, projection, environment))) =
+            (scene.camera, active_view)
+        else {
+            continue;
+        };
+        let mut camera = commands.entity(camera);
+        camera.insert(transform);
+        if let Some(projection) = projection {
+            camera.insert(projection);
+        }
+        if let Some(environment) = environment {
+            camera.insert(environment);
+        }
+    }
+}
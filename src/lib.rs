@@ -1,11 +1,22 @@
-// pub mod camera;
-// pub mod cpu_raytracing;
+pub mod accumulation;
+pub mod buffer;
+pub mod bvh;
+pub mod cpu_raytracing;
+pub mod demo;
+pub mod environment;
+pub mod extract;
 pub mod fly_cam;
+pub mod gltf;
+pub mod light;
 mod node;
+pub mod obj;
+pub mod offline;
+pub mod pbrt;
 mod pipeline;
+mod ply;
 pub mod ray_tracing;
-// pub mod hittable;
-// pub mod light;
-// pub mod material;
-// pub mod scene;
-// pub mod shape;
+pub mod restir;
+pub mod sampler;
+pub mod sky;
+pub mod svgf;
+pub mod texture;
@@ -0,0 +1,190 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    prelude::*,
+    render::{render_resource::ShaderType, Extract},
+};
+
+use crate::ray_tracing::RayTracingInfo;
+
+/// A punctual light in the units of bevy's rasterizer, before exposure is applied
+#[derive(Reflect, Default, Debug, Clone, PartialEq, ShaderType)]
+pub struct GpuLight {
+    /// Luminous intensity of point and spot lights, illuminance of directional ones
+    pub color: Vec3,
+    pub kind: u32,
+    /// Position of point and spot lights, direction towards directional ones
+    pub position: Vec3,
+    pub radius: f32,
+    /// Direction spot lights shine in
+    pub spot_direction: Vec3,
+    pub inverse_square_range: f32,
+    pub spot_scale: f32,
+    pub spot_offset: f32,
+}
+
+impl GpuLight {
+    pub const POINT: u32 = 0;
+    pub const SPOT: u32 = 1;
+    pub const DIRECTIONAL: u32 = 2;
+
+    fn point(light: &PointLight, transform: &GlobalTransform) -> Self {
+        Self {
+            color: light.color.to_linear().to_vec3() * light.intensity / (4.0 * PI),
+            kind: Self::POINT,
+            position: transform.translation(),
+            radius: light.radius,
+            inverse_square_range: 1.0 / (light.range * light.range),
+            ..default()
+        }
+    }
+
+    fn spot(light: &SpotLight, transform: &GlobalTransform) -> Self {
+        // same precomputed cone falloff as bevy's spot lights
+        let cos_outer = light.outer_angle.cos();
+        let spot_scale = 1.0 / (light.inner_angle.cos() - cos_outer).max(1e-4);
+        Self {
+            color: light.color.to_linear().to_vec3() * light.intensity / (4.0 * PI),
+            kind: Self::SPOT,
+            position: transform.translation(),
+            radius: light.radius,
+            spot_direction: transform.forward().into(),
+            inverse_square_range: 1.0 / (light.range * light.range),
+            spot_scale,
+            spot_offset: -cos_outer * spot_scale,
+        }
+    }
+
+    fn directional(light: &DirectionalLight, transform: &GlobalTransform) -> Self {
+        Self {
+            color: light.color.to_linear().to_vec3() * light.illuminance,
+            kind: Self::DIRECTIONAL,
+            position: transform.back().into(),
+            ..default()
+        }
+    }
+}
+
+/// Collects the visible lights every frame, they are only uploaded when one of them changed
+///
+/// Shadows are always traced, regardless of `shadows_enabled`.
+pub fn extract_lights(
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    point_lights: Extract<Query<(&PointLight, &GlobalTransform, &InheritedVisibility)>>,
+    spot_lights: Extract<Query<(&SpotLight, &GlobalTransform, &InheritedVisibility)>>,
+    directional_lights: Extract<Query<(&DirectionalLight, &GlobalTransform, &InheritedVisibility)>>,
+) {
+    let visible = |visibility: &InheritedVisibility| visibility.get();
+    let lights: Vec<GpuLight> = point_lights
+        .iter()
+        .filter(|(_, _, visibility)| visible(visibility))
+        .map(|(light, transform, _)| GpuLight::point(light, transform))
+        .chain(
+            spot_lights
+                .iter()
+                .filter(|(_, _, visibility)| visible(visibility))
+                .map(|(light, transform, _)| GpuLight::spot(light, transform)),
+        )
+        .chain(
+            directional_lights
+                .iter()
+                .filter(|(_, _, visibility)| visible(visibility))
+                .map(|(light, transform, _)| GpuLight::directional(light, transform)),
+        )
+        .collect();
+    if ray_tracing_info.lights.data() != lights.as_slice() {
+        ray_tracing_info.light_info.get_mut().light_count = lights.len() as u32;
+        ray_tracing_info.lights.replace(lights);
+        ray_tracing_info.reset_accumulation();
+    }
+}
+
+/// How many lights of each kind there are to pick from
+#[derive(Reflect, Default, Debug, Clone, ShaderType)]
+pub struct GpuLightInfo {
+    pub light_count: u32,
+    pub emissive_count: u32,
+    /// Sum of the luminance times world space area of every emissive triangle
+    pub emissive_power: f32,
+}
+
+/// An emissive triangle, stored as an entry of the alias table they are sampled from
+///
+/// Triangles are picked proportionally to their power so the density of any point on an emitter
+/// is its material's emissive luminance over [`GpuLightInfo::emissive_power`].
+#[derive(Reflect, Default, Debug, Clone, ShaderType)]
+pub struct EmissiveTriangle {
+    /// Index into [`RayTracingInfo::instances`]
+    pub instance: u32,
+    /// Index into [`RayTracingInfo::triangles`]
+    pub triangle: u32,
+    /// Probability of keeping this entry rather than switching to `alias_index`
+    pub probability: f32,
+    pub alias_index: u32,
+}
+
+/// Collects the triangles of every instance with an emissive material
+///
+/// Reads the instances in their current order so has to run after the top level was rebuilt.
+pub fn build_emissive_triangles(ray_tracing_info: &mut RayTracingInfo) {
+    let vertices = ray_tracing_info.vertices.data();
+    let triangles = ray_tracing_info.triangles.data();
+    let mut emitters = Vec::new();
+    let mut powers = Vec::new();
+    for (index, instance) in ray_tracing_info.instances.data().iter().enumerate() {
+        let emissive = ray_tracing_info.materials.data()[instance.material as usize].emissive;
+        let luminance = emissive.luminance();
+        if luminance <= 0.0 {
+            continue;
+        }
+        let mesh = &ray_tracing_info.meshes.data()[instance.mesh as usize];
+        for triangle in mesh.first_tri..mesh.first_tri + mesh.tri_count {
+            let [a, b, c] = triangles[triangle as usize].indices.map(|i| {
+                instance
+                    .world_from_object
+                    .transform_point3(vertices[i as usize].position)
+            });
+            let area = 0.5 * (b - a).cross(c - a).length();
+            if area <= 0.0 {
+                continue;
+            }
+            emitters.push(EmissiveTriangle {
+                instance: index as u32,
+                triangle,
+                ..default()
+            });
+            powers.push(luminance * area);
+        }
+    }
+    for (emitter, (probability, alias)) in emitters.iter_mut().zip(alias_table(&powers)) {
+        emitter.probability = probability;
+        emitter.alias_index = alias;
+    }
+    let light_info = ray_tracing_info.light_info.get_mut();
+    light_info.emissive_count = emitters.len() as u32;
+    light_info.emissive_power = powers.iter().sum();
+    ray_tracing_info.emissive_triangles.replace(emitters);
+}
+
+/// Vose's alias method, each entry keeps its own index with the returned probability and
+/// switches to the returned alias otherwise
+fn alias_table(weights: &[f32]) -> Vec<(f32, u32)> {
+    let total: f32 = weights.iter().sum();
+    let mut scaled: Vec<f32> = weights
+        .iter()
+        .map(|weight| weight * weights.len() as f32 / total)
+        .collect();
+    let mut table: Vec<(f32, u32)> = (0..weights.len() as u32).map(|i| (1.0, i)).collect();
+    let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+        (0..weights.len()).partition(|&i| scaled[i] < 1.0);
+    while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+        small.pop();
+        table[less] = (scaled[less], more as u32);
+        scaled[more] -= 1.0 - scaled[less];
+        if scaled[more] < 1.0 {
+            large.pop();
+            small.push(more);
+        }
+    }
+    table
+}
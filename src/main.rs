@@ -1,21 +1,30 @@
 use bevy::{
-    core_pipeline::{core_3d::graph::Core3d, prepass::MotionVectorPrepass},
+    core_pipeline::{bloom::BloomSettings, core_3d::graph::Core3d},
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::*,
     render::camera::CameraRenderGraph,
 };
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use ray_tracing::{
+    demo::spawn_demo_scene,
     fly_cam::{FlyCam, NoCameraPlayerPlugin},
+    gltf::RayTracedGltf,
+    obj::ObjPlugin,
+    pbrt::PbrtPlugin,
     ray_tracing::{RayTracingGraph, RayTracingPlugin},
+    sky::PhysicalSky,
 };
 
+/// Shows the glTF, OBJ or pbrt scene at the asset path given as argument, e.g.
+/// `models/scene.glb#Scene0` or `models/CornellBox.obj`, or the demo scene without one
 fn main() {
     App::new()
         .add_plugins((
             DefaultPlugins,
             NoCameraPlayerPlugin,
             RayTracingPlugin,
+            ObjPlugin,
+            PbrtPlugin,
             WorldInspectorPlugin::default(),
             FrameTimeDiagnosticsPlugin,
             LogDiagnosticsPlugin::default(),
@@ -52,68 +61,44 @@ fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
 ) {
-    let material_blue = materials.add(Color::from(LinearRgba::BLUE));
-    let material_red = materials.add(Color::srgb(1.0, 0.0, 0.0));
-    commands.spawn((
-        Camera3dBundle {
-            camera_render_graph: CameraRenderGraph::new(RayTracingGraph),
-            transform: Transform::from_xyz(0., 3.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-            ..default()
-        },
-        MotionVectorPrepass,
-        FlyCam,
-    ));
-    commands.spawn((
-        MaterialMeshBundle {
-            mesh: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
-            material: material_blue.clone(),
-            transform: Transform::from_rotation(Quat::from_axis_angle(
-                Vec3::X,
-                45.0_f32.to_radians(),
-            )),
+    let camera = commands
+        .spawn((
+            Camera3dBundle {
+                camera: Camera {
+                    hdr: true,
+                    ..default()
+                },
+                camera_render_graph: CameraRenderGraph::new(RayTracingGraph),
+                transform: Transform::from_xyz(0., 3.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+                ..default()
+            },
+            BloomSettings::NATURAL,
+            PhysicalSky::default(),
+            FlyCam,
+        ))
+        .id();
+    if let Some(path) = std::env::args().nth(1) {
+        // the fly cam starts where the scene's camera is
+        commands.spawn((
+            SceneBundle {
+                scene: asset_server.load(path),
+                ..default()
+            },
+            RayTracedGltf {
+                camera: Some(camera),
+            },
+        ));
+    } else {
+        let cube = spawn_demo_scene(&mut commands, &mut meshes, &mut materials);
+        commands.entity(cube).insert(Rotate);
+        // the sun of the camera's sky
+        commands.spawn(DirectionalLightBundle {
+            transform: Transform::from_xyz(1.0, 2.0, 1.5).looking_at(Vec3::ZERO, Vec3::Y),
             ..default()
-        },
-        Rotate,
-    ));
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(5.0))),
-        material: material_blue,
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Sphere::new(0.5).mesh()),
-        material: material_red.clone(),
-        transform: Transform::from_xyz(1., 0.5, 1.),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Sphere::new(0.5).mesh()),
-        material: material_red.clone(),
-        transform: Transform::from_xyz(-1., 0.5, 1.),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Sphere::new(0.5).mesh()),
-        material: material_red.clone(),
-        transform: Transform::from_xyz(1., 0.5, -1.),
-        ..default()
-    });
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Sphere::new(0.5).mesh()),
-        material: material_red.clone(),
-        transform: Transform::from_xyz(-1., 0.5, -1.),
-        ..default()
-    });
-    commands.spawn(PointLightBundle {
-        transform: Transform::from_xyz(0.0, 50.0, 0.0),
-        point_light: PointLight {
-            color: Color::WHITE,
-            radius: 1.0,
-            ..Default::default()
-        },
-        ..Default::default()
-    });
+        });
+    }
 }
 
 #[derive(Component)]
@@ -1,19 +1,116 @@
+use std::sync::{Arc, Mutex};
+
 use bevy::{
-    core_pipeline::prepass::ViewPrepassTextures,
+    core_pipeline::prepass::PreviousViewUniformOffset,
     ecs::query::QueryItem,
     prelude::*,
     render::{
         camera::ExtractedCamera,
-        globals::GlobalsBuffer,
-        render_asset::RenderAssets,
-        render_graph::ViewNode,
-        render_resource::{AsBindGroup, BindGroupEntries, PipelineCache, RenderPassDescriptor},
-        texture::{FallbackImage, GpuImage},
-        view::{ViewTarget, ViewUniformOffset, ViewUniforms},
+        render_graph::{
+            EmptyNode, Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel, SlotInfo,
+            ViewNode,
+        },
+        render_resource::{
+            BindGroup, CachedComputePipelineId, ComputePassDescriptor, LoadOp, Operations,
+            PipelineCache, RenderPassColorAttachment, RenderPassDescriptor, StoreOp,
+        },
+        renderer::RenderContext,
+        view::{ViewTarget, ViewUniformOffset},
     },
 };
 
-use crate::{pipeline::RayTracingPipeline, ray_tracing::RayTracingInfo};
+use crate::{
+    pipeline::RayTracingPipeline,
+    ray_tracing::{RayTracingInfo, RayTracingSettings, RayTracingViewBindGroup},
+    restir::RestirGiSettings,
+};
+
+/// Pixels along each side of a workgroup of the ReSTIR passes
+const RESTIR_WORKGROUP_SIZE: u32 = 8;
+
+/// Runs a ReSTIR compute pass with a thread for every pixel of the view, if its pipeline is ready
+fn dispatch_pixels(
+    render_context: &mut RenderContext,
+    world: &World,
+    pipeline_id: CachedComputePipelineId,
+    camera: &ExtractedCamera,
+    view_bind_group: &BindGroup,
+    view_offsets: &[u32],
+    label: &str,
+) {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let (Some(pipeline), Some(bind_group), Some(size)) = (
+        pipeline_cache.get_compute_pipeline(pipeline_id),
+        &world.resource::<RayTracingInfo>().bind_group,
+        camera.physical_viewport_size,
+    ) else {
+        return;
+    };
+    let mut compute_pass =
+        render_context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor {
+                label: Some(label),
+                timestamp_writes: None,
+            });
+    compute_pass.set_pipeline(pipeline);
+    compute_pass.set_bind_group(0, view_bind_group, view_offsets);
+    compute_pass.set_bind_group(1, bind_group, &[]);
+    let workgroups = (size + RESTIR_WORKGROUP_SIZE - 1) / RESTIR_WORKGROUP_SIZE;
+    compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+}
+
+/// Finds the indirect light of every pixel's first hit with ReSTIR GI, the main pass picks it
+/// up instead of tracing the rest of the path
+#[derive(Default)]
+pub struct RestirGiNode;
+
+impl ViewNode for RestirGiNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static RayTracingViewBindGroup,
+        &'static ViewUniformOffset,
+        &'static PreviousViewUniformOffset,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (camera, view_bind_group, view_uniform_offset, previous_view_uniform_offset): QueryItem<
+            Self::ViewQuery,
+        >,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        if !world.resource::<RestirGiSettings>().enabled {
+            return Ok(());
+        }
+        let pipeline = world.resource::<RayTracingPipeline>();
+        let view_offsets = [
+            view_uniform_offset.offset,
+            previous_view_uniform_offset.offset,
+        ];
+        dispatch_pixels(
+            render_context,
+            world,
+            pipeline.restir_gi_candidates_pipeline_id,
+            camera,
+            &view_bind_group.restir_bind_group,
+            &view_offsets,
+            "ray_tracing_restir_gi_candidates_pass",
+        );
+        dispatch_pixels(
+            render_context,
+            world,
+            pipeline.restir_gi_resample_pipeline_id,
+            camera,
+            &view_bind_group.restir_gi_bind_group,
+            &view_offsets,
+            "ray_tracing_restir_gi_resample_pass",
+        );
+        Ok(())
+    }
+}
 
 #[derive(Default)]
 pub struct RayTracingPassNode;
@@ -22,47 +119,84 @@ impl ViewNode for RayTracingPassNode {
     type ViewQuery = (
         &'static ExtractedCamera,
         &'static ViewTarget,
-        &'static ViewPrepassTextures,
+        &'static RayTracingViewBindGroup,
         &'static ViewUniformOffset,
+        &'static PreviousViewUniformOffset,
     );
 
     fn run(
         &self,
         _graph: &mut bevy::render::render_graph::RenderGraphContext,
         render_context: &mut bevy::render::renderer::RenderContext,
-        (camera, target, view_prepass_textures, view_uniform_offset): QueryItem<Self::ViewQuery>,
+        (camera, target, view_bind_group, view_uniform_offset, previous_view_uniform_offset): QueryItem<
+            Self::ViewQuery,
+        >,
         world: &World,
     ) -> Result<(), bevy::render::render_graph::NodeRunError> {
-        let render_device = render_context.render_device();
-        let view_uniforms = &world.resource::<ViewUniforms>().uniforms;
-        let globals_uniform = world.resource::<GlobalsBuffer>().buffer.binding().unwrap();
-        let motion = view_prepass_textures.motion_vectors_view().unwrap();
         let ray_tracing_pipeline = world.resource::<RayTracingPipeline>();
-        let ray_tracing_info = world.resource::<RayTracingInfo>();
-        let bind_group = ray_tracing_info
-            .as_bind_group(
-                &RayTracingInfo::bind_group_layout(render_device),
-                render_device,
-                world.resource::<RenderAssets<GpuImage>>(),
-                world.resource::<FallbackImage>(),
-            )
-            .unwrap()
-            .bind_group;
+        let Some(bind_group) = &world.resource::<RayTracingInfo>().bind_group else {
+            return Ok(());
+        };
 
         let pipeline_cache = world.resource::<PipelineCache>();
-        let Some(pipeline) = pipeline_cache.get_render_pipeline(ray_tracing_pipeline.pipeline_id)
-        else {
+        let pipeline_id = ray_tracing_pipeline.pipeline_ids[target.is_hdr() as usize];
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id) else {
             return Ok(());
         };
 
-        let global_bind_group = render_device.create_bind_group(
-            "ray_tracing_bind_group",
-            &ray_tracing_pipeline.layout,
-            &BindGroupEntries::sequential((view_uniforms, globals_uniform, motion)),
-        );
+        let view_offsets = [
+            view_uniform_offset.offset,
+            previous_view_uniform_offset.offset,
+        ];
+        if world.resource::<RayTracingSettings>().restir_candidates > 0 {
+            dispatch_pixels(
+                render_context,
+                world,
+                ray_tracing_pipeline.restir_pipeline_id,
+                camera,
+                &view_bind_group.restir_bind_group,
+                &view_offsets,
+                "ray_tracing_restir_pass",
+            );
+        }
+
         let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("ray_tracing_render_pass"),
-            color_attachments: &[Some(target.out_texture_color_attachment(None))],
+            color_attachments: &[
+                Some(target.get_color_attachment()),
+                Some(RenderPassColorAttachment {
+                    view: &view_bind_group.accumulation,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                }),
+                Some(RenderPassColorAttachment {
+                    view: &view_bind_group.geometry,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                }),
+                Some(RenderPassColorAttachment {
+                    view: &view_bind_group.radiance,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                }),
+                Some(RenderPassColorAttachment {
+                    view: &view_bind_group.albedo,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                }),
+            ],
             depth_stencil_attachment: None,
             timestamp_writes: None,
             occlusion_query_set: None,
@@ -72,9 +206,48 @@ impl ViewNode for RayTracingPassNode {
             render_pass.set_camera_viewport(viewport);
         }
         render_pass.set_render_pipeline(pipeline);
-        render_pass.set_bind_group(0, &global_bind_group, &[view_uniform_offset.offset]);
-        render_pass.set_bind_group(1, &bind_group, &[]);
+        render_pass.set_bind_group(0, &view_bind_group.bind_group, &view_offsets);
+        render_pass.set_bind_group(1, bind_group, &[]);
         render_pass.draw(0..3, 0..1);
         Ok(())
     }
 }
+
+/// A node taken out of another render graph and shared with ours, for nodes bevy doesn't export
+///
+/// Both graphs update and run the same node, it is locked while it does.
+pub struct SharedNode(Arc<Mutex<Box<dyn Node>>>);
+
+impl SharedNode {
+    /// Puts the node at `label` behind a lock, leaving a handle in its place and returning another
+    pub fn share(graph: &mut RenderGraph, label: impl RenderLabel) -> Option<Self> {
+        let state = graph.get_node_state_mut(label).ok()?;
+        let node = std::mem::replace(&mut state.node, Box::new(EmptyNode));
+        let shared = Arc::new(Mutex::new(node));
+        state.node = Box::new(Self(shared.clone()));
+        Some(Self(shared))
+    }
+}
+
+impl Node for SharedNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        self.0.lock().unwrap().input()
+    }
+
+    fn output(&self) -> Vec<SlotInfo> {
+        self.0.lock().unwrap().output()
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.0.lock().unwrap().update(world);
+    }
+
+    fn run<'w>(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        self.0.lock().unwrap().run(graph, render_context, world)
+    }
+}
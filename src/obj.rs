@@ -0,0 +1,652 @@
+use std::collections::BTreeSet;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AssetPath, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        texture::ImageLoaderSettings,
+    },
+    utils::HashMap,
+};
+
+/// Loads Wavefront `.obj` files as [`Scene`]s and `.mtl` files as [`MtlLibrary`]s
+///
+/// An OBJ scene holds a [`Mesh`] per material, labeled `Mesh0`, `Mesh1`, ..., and the
+/// [`StandardMaterial`]s of its material libraries, labeled `Material/<name>`. Vertices are used
+/// as they are, without a transform.
+pub struct ObjPlugin;
+
+impl Plugin for ObjPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MtlLibrary>()
+            .register_type::<MtlLibrary>()
+            .register_asset_loader(ObjLoader)
+            .register_asset_loader(MtlLoader);
+    }
+}
+
+/// The materials of an `.mtl` file, each of them is also labeled with its name
+#[derive(Asset, Reflect, Debug, Default)]
+pub struct MtlLibrary {
+    pub materials: HashMap<String, Handle<StandardMaterial>>,
+}
+
+pub struct ObjLoader;
+
+impl AssetLoader for ObjLoader {
+    type Asset = Scene;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Scene, String> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|error| error.to_string())?;
+        let obj = Obj::parse(&String::from_utf8_lossy(&bytes))?;
+        warn_unsupported(load_context.asset_path(), &obj.unsupported);
+
+        let mut materials = HashMap::new();
+        for library in &obj.libraries {
+            let path = resolve(load_context.asset_path(), library)?;
+            let bytes = match load_context.read_asset_bytes(&path).await {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    warn!(
+                        "{}: skipping {library}: {error}",
+                        load_context.path().display()
+                    );
+                    continue;
+                }
+            };
+            let mtl = Mtl::parse(&String::from_utf8_lossy(&bytes))?;
+            warn_unsupported(&path, &mtl.unsupported);
+            for (name, material) in mtl.materials {
+                let normal_mapped = material.normal_texture.is_some();
+                let material = material.standard_material(&path, load_context)?;
+                let handle = load_context.add_labeled_asset(format!("Material/{name}"), material);
+                materials.insert(name, (handle, normal_mapped));
+            }
+        }
+
+        let mut world = World::new();
+        let mut default_material = None;
+        for (index, group) in obj.groups.iter().enumerate() {
+            let (material, normal_mapped) =
+                match group.material.as_ref().and_then(|name| materials.get(name)) {
+                    Some((material, normal_mapped)) => (material.clone(), *normal_mapped),
+                    None => {
+                        if let Some(name) = &group.material {
+                            warn!("{}: unknown material {name}", load_context.path().display());
+                        }
+                        let material = default_material.get_or_insert_with(|| {
+                            load_context.add_labeled_asset(
+                                "Material/default".to_string(),
+                                StandardMaterial::default(),
+                            )
+                        });
+                        (material.clone(), false)
+                    }
+                };
+            let mesh = obj.mesh(group, normal_mapped);
+            let mesh = load_context.add_labeled_asset(format!("Mesh{index}"), mesh);
+            world.spawn(PbrBundle {
+                mesh,
+                material,
+                ..default()
+            });
+        }
+        Ok(Scene::new(world))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["obj"]
+    }
+}
+
+pub struct MtlLoader;
+
+impl AssetLoader for MtlLoader {
+    type Asset = MtlLibrary;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<MtlLibrary, String> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|error| error.to_string())?;
+        let mtl = Mtl::parse(&String::from_utf8_lossy(&bytes))?;
+        let path = load_context.asset_path().clone();
+        warn_unsupported(&path, &mtl.unsupported);
+        let mut library = MtlLibrary::default();
+        for (name, material) in mtl.materials {
+            let material = material.standard_material(&path, load_context)?;
+            let handle = load_context.add_labeled_asset(name.clone(), material);
+            library.materials.insert(name, handle);
+        }
+        Ok(library)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mtl"]
+    }
+}
+
+fn warn_unsupported(path: &AssetPath, statements: &BTreeSet<String>) {
+    if !statements.is_empty() {
+        let statements: Vec<_> = statements.iter().map(String::as_str).collect();
+        warn!(
+            "{path}: ignoring unsupported statements {}",
+            statements.join(", ")
+        );
+    }
+}
+
+/// Asset path of a file referenced from `path`, OBJ files often use Windows separators
+fn resolve(path: &AssetPath, reference: &str) -> Result<AssetPath<'static>, String> {
+    path.resolve_embed(&reference.replace('\\', "/"))
+        .map_err(|error| error.to_string())
+}
+
+/// A corner of a face, indices into the positions, texture coordinates and normals
+type Corner = (usize, Option<usize>, usize);
+
+/// A corner as written in the file, whose normal may be missing
+type FileCorner = (usize, Option<usize>, Option<usize>);
+
+/// Faces drawn with the same material
+struct Group {
+    material: Option<String>,
+    triangles: Vec<[Corner; 3]>,
+}
+
+#[derive(Default)]
+struct Obj {
+    positions: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    /// Normals of the file followed by the face normals of faces without any
+    normals: Vec<Vec3>,
+    groups: Vec<Group>,
+    libraries: Vec<String>,
+    unsupported: BTreeSet<String>,
+}
+
+impl Obj {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut obj = Obj::default();
+        let mut file_normals = Vec::new();
+        let mut face_normals = Vec::new();
+        let mut faces: Vec<(usize, Vec<FileCorner>)> = Vec::new();
+        let mut materials: Vec<Option<String>> = vec![None];
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| format!("line {}: {message}", number + 1);
+            let mut tokens = line.split_whitespace();
+            let Some(keyword) = tokens.next() else {
+                continue;
+            };
+            match keyword {
+                "v" => obj
+                    .positions
+                    .push(parse_vec3(tokens).ok_or_else(|| error("bad vertex"))?),
+                "vt" => {
+                    let uv = parse_floats(tokens).ok_or_else(|| error("bad texture coordinate"))?;
+                    // OBJ puts the origin of textures at the bottom
+                    let u = uv.first().copied().unwrap_or_default();
+                    let v = uv.get(1).copied().unwrap_or_default();
+                    obj.uvs.push(Vec2::new(u, 1.0 - v));
+                }
+                "vn" => file_normals.push(parse_vec3(tokens).ok_or_else(|| error("bad normal"))?),
+                "f" => {
+                    let corners = tokens
+                        .map(|corner| {
+                            let mut indices = corner.split('/');
+                            let mut index = |count: usize| match indices
+                                .next()
+                                .filter(|index| !index.is_empty())
+                            {
+                                Some(index) => resolve_index(index, count).map(Some),
+                                None => Some(None),
+                            };
+                            let position = index(obj.positions.len())?;
+                            let uv = index(obj.uvs.len())?;
+                            let normal = index(file_normals.len())?;
+                            Some((position?, uv, normal))
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| error("bad face"))?;
+                    if corners.len() >= 3 {
+                        faces.push((materials.len() - 1, corners));
+                    }
+                }
+                "usemtl" => materials.push(Some(tokens.collect::<Vec<_>>().join(" "))),
+                "mtllib" => obj.libraries.push(tokens.collect::<Vec<_>>().join(" ")),
+                // objects, groups and smoothing groups don't change how faces are drawn
+                "o" | "g" | "s" => {}
+                _ if keyword.starts_with('#') => {}
+                _ => {
+                    obj.unsupported.insert(keyword.to_string());
+                }
+            }
+        }
+
+        // faces are grouped by the material they use, not by where they are in the file
+        let mut groups: HashMap<Option<&String>, Group> = HashMap::new();
+        let mut order = Vec::new();
+        for (material, corners) in faces {
+            let material = materials[material].as_ref();
+            // faces without normals are shaded flat
+            let normal = if corners.iter().all(|corner| corner.2.is_some()) {
+                None
+            } else {
+                let normal = newell_normal(corners.iter().map(|corner| obj.positions[corner.0]));
+                face_normals.push(normal);
+                Some(face_normals.len() - 1)
+            };
+            let corners: Vec<Corner> = corners
+                .into_iter()
+                .map(|(position, uv, file_normal)| {
+                    let normal = match normal {
+                        Some(face) => file_normals.len() + face,
+                        None => file_normal.unwrap(),
+                    };
+                    (position, uv, normal)
+                })
+                .collect();
+            let group = groups.entry(material).or_insert_with(|| {
+                order.push(material);
+                Group {
+                    material: material.cloned(),
+                    triangles: Vec::new(),
+                }
+            });
+            group
+                .triangles
+                .extend((1..corners.len() - 1).map(|i| [corners[0], corners[i], corners[i + 1]]));
+        }
+        let mut groups: Vec<Group> = order
+            .into_iter()
+            .filter_map(|material| groups.remove(&material))
+            .collect();
+        groups.retain(|group| !group.triangles.is_empty());
+        obj.groups = groups;
+        obj.normals = file_normals;
+        obj.normals.extend(face_normals);
+        Ok(obj)
+    }
+
+    fn mesh(&self, group: &Group, tangents: bool) -> Mesh {
+        let mut vertices = HashMap::new();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+        for corner in group.triangles.iter().flatten() {
+            let index = *vertices.entry(*corner).or_insert_with(|| {
+                let (position, uv, normal) = *corner;
+                positions.push(self.positions[position].to_array());
+                normals.push(self.normals[normal].to_array());
+                uvs.push(uv.map(|uv| self.uvs[uv]).unwrap_or_default().to_array());
+                positions.len() as u32 - 1
+            });
+            indices.push(index);
+        }
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices));
+        if tangents {
+            if let Err(error) = mesh.generate_tangents() {
+                warn!("can't generate tangents for a normal mapped OBJ mesh: {error}");
+            }
+        }
+        mesh
+    }
+}
+
+/// Zero based index of a one based or, if negative, relative OBJ index
+fn resolve_index(index: &str, count: usize) -> Option<usize> {
+    let index: isize = index.parse().ok()?;
+    let index = if index < 0 {
+        count as isize + index
+    } else {
+        index - 1
+    };
+    (0..count as isize)
+        .contains(&index)
+        .then_some(index as usize)
+}
+
+/// Normal of a polygon that isn't quite planar (Newell's method)
+fn newell_normal(positions: impl Iterator<Item = Vec3> + Clone) -> Vec3 {
+    let next = positions.clone().cycle().skip(1);
+    positions
+        .zip(next)
+        .fold(Vec3::ZERO, |normal, (a, b)| {
+            normal + (a - b).cross(a + b) * 0.5
+        })
+        .try_normalize()
+        .unwrap_or(Vec3::Y)
+}
+
+fn parse_floats<'a>(tokens: impl Iterator<Item = &'a str>) -> Option<Vec<f32>> {
+    tokens.map(|token| token.parse().ok()).collect()
+}
+
+fn parse_vec3<'a>(tokens: impl Iterator<Item = &'a str>) -> Option<Vec3> {
+    match parse_floats(tokens)?[..] {
+        [x, y, z, ..] => Some(Vec3::new(x, y, z)),
+        // a single value is a gray color
+        [x] => Some(Vec3::splat(x)),
+        _ => None,
+    }
+}
+
+/// Statements of an MTL material
+#[derive(Default)]
+struct MtlMaterial {
+    diffuse: Option<Vec3>,
+    specular: Option<Vec3>,
+    shininess: Option<f32>,
+    emissive: Option<Vec3>,
+    ior: Option<f32>,
+    dissolve: Option<f32>,
+    diffuse_texture: Option<String>,
+    emissive_texture: Option<String>,
+    normal_texture: Option<String>,
+    alpha_texture: bool,
+}
+
+impl MtlMaterial {
+    /// Maps the Phong style parameters onto bevy's metallic-roughness model
+    ///
+    /// Materials with a specular but without a diffuse color are metals, other materials get a
+    /// reflectance matching their specular color's luminance. The Blinn-Phong exponent `Ns` is
+    /// converted to the GGX roughness with the same highlight width. Dissolved materials transmit
+    /// light, refracting it if they have an index of refraction. `map_Bump` and `bump` are taken
+    /// to be tangent space normal maps like most exporters write them, alpha masks come from the
+    /// diffuse texture.
+    fn standard_material(
+        &self,
+        path: &AssetPath,
+        load_context: &mut LoadContext,
+    ) -> Result<StandardMaterial, String> {
+        let diffuse = self.diffuse.unwrap_or(Vec3::splat(0.8));
+        let metal = diffuse.max_element() < 0.01
+            && self.diffuse_texture.is_none()
+            && self
+                .specular
+                .is_some_and(|specular| specular.max_element() > 0.0);
+        let mut material = StandardMaterial {
+            base_color: Color::linear_rgb(diffuse.x, diffuse.y, diffuse.z),
+            cull_mode: None,
+            double_sided: true,
+            ..default()
+        };
+        if let Some(specular) = self.specular {
+            if metal {
+                material.base_color = Color::linear_rgb(specular.x, specular.y, specular.z);
+                material.metallic = 1.0;
+            } else {
+                let f0 = specular.dot(Vec3::new(0.2126, 0.7152, 0.0722)).min(0.16);
+                material.reflectance = (f0 / 0.16).sqrt();
+            }
+        }
+        if let Some(shininess) = self.shininess {
+            let roughness = (2.0 / (shininess.max(0.0) + 2.0)).sqrt();
+            material.perceptual_roughness = roughness.sqrt();
+        }
+        if let Some(emissive) = self.emissive {
+            material.emissive = LinearRgba::rgb(emissive.x, emissive.y, emissive.z);
+        } else if self.emissive_texture.is_some() {
+            material.emissive = LinearRgba::WHITE;
+        }
+        if let Some(dissolve) = self.dissolve.filter(|&dissolve| dissolve < 1.0) {
+            material.specular_transmission = 1.0 - dissolve.max(0.0);
+            material.ior = self.ior.unwrap_or(1.0);
+        } else if let Some(ior) = self.ior {
+            material.ior = ior;
+        }
+        if self.alpha_texture {
+            material.alpha_mode = AlphaMode::Mask(0.5);
+        }
+        if let Some(texture) = &self.diffuse_texture {
+            material.base_color_texture = Some(load_context.load(resolve(path, texture)?));
+        }
+        if let Some(texture) = &self.emissive_texture {
+            material.emissive_texture = Some(load_context.load(resolve(path, texture)?));
+        }
+        if let Some(texture) = &self.normal_texture {
+            material.normal_map_texture = Some(
+                load_context
+                    .loader()
+                    .with_settings(|settings: &mut ImageLoaderSettings| {
+                        settings.is_srgb = false;
+                    })
+                    .load(resolve(path, texture)?),
+            );
+        }
+        Ok(material)
+    }
+}
+
+#[derive(Default)]
+struct Mtl {
+    materials: Vec<(String, MtlMaterial)>,
+    unsupported: BTreeSet<String>,
+}
+
+impl Mtl {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut mtl = Mtl::default();
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| format!("line {}: {message}", number + 1);
+            let mut tokens = line.split_whitespace();
+            let Some(keyword) = tokens.next() else {
+                continue;
+            };
+            if keyword == "newmtl" {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                mtl.materials.push((name, MtlMaterial::default()));
+                continue;
+            }
+            if keyword.starts_with('#') {
+                continue;
+            }
+            let Some((_, material)) = mtl.materials.last_mut() else {
+                return Err(error("statement before newmtl"));
+            };
+            let color = |tokens| parse_vec3(tokens).ok_or_else(|| error("bad color"));
+            let float = |mut tokens: std::str::SplitWhitespace| {
+                tokens
+                    .next()
+                    .and_then(|token| token.parse::<f32>().ok())
+                    .ok_or_else(|| error("bad number"))
+            };
+            match keyword {
+                "Kd" => material.diffuse = Some(color(tokens)?),
+                "Ks" => material.specular = Some(color(tokens)?),
+                "Ke" => material.emissive = Some(color(tokens)?),
+                "Ns" => material.shininess = Some(float(tokens)?),
+                "Ni" => material.ior = Some(float(tokens)?),
+                "d" => material.dissolve = Some(float(tokens)?),
+                "Tr" => material.dissolve = Some(1.0 - float(tokens)?),
+                "map_Kd" => material.diffuse_texture = Some(texture_path(tokens)),
+                "map_Ke" => material.emissive_texture = Some(texture_path(tokens)),
+                "map_Bump" | "map_bump" | "bump" | "norm" | "map_Kn" => {
+                    material.normal_texture = Some(texture_path(tokens));
+                }
+                "map_d" => material.alpha_texture = true,
+                // ambient light and the illumination model have no place in a path tracer
+                "Ka" | "illum" => {}
+                _ => {
+                    mtl.unsupported.insert(keyword.to_string());
+                }
+            }
+        }
+        Ok(mtl)
+    }
+}
+
+/// File name of a texture statement, after its options
+fn texture_path<'a>(tokens: impl Iterator<Item = &'a str>) -> String {
+    let tokens: Vec<_> = tokens.collect();
+    let mut start = 0;
+    while let Some(option) = tokens.get(start).filter(|token| token.starts_with('-')) {
+        start += 1;
+        match *option {
+            // up to three numbers
+            "-o" | "-s" | "-t" | "-mm" => {
+                let count = if *option == "-mm" { 2 } else { 3 };
+                for _ in 0..count {
+                    if tokens
+                        .get(start)
+                        .is_some_and(|token| token.parse::<f32>().is_ok())
+                    {
+                        start += 1;
+                    }
+                }
+            }
+            _ => start += 1,
+        }
+    }
+    tokens[start.min(tokens.len())..].join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(obj: &Obj, group: &Group) -> Vec<[usize; 3]> {
+        group
+            .triangles
+            .iter()
+            .map(|triangle| triangle.map(|corner| corner.0))
+            .inspect(|triangle| assert!(triangle.iter().all(|&i| i < obj.positions.len())))
+            .collect()
+    }
+
+    #[test]
+    fn relative_indices() {
+        let obj = Obj::parse(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             f -4 -3 -2 -1\n\
+             v 0 0 1\n\
+             f 1 2 -1\n",
+        )
+        .unwrap();
+        assert_eq!(obj.groups.len(), 1);
+        assert_eq!(
+            positions(&obj, &obj.groups[0]),
+            [[0, 1, 2], [0, 2, 3], [0, 1, 4]]
+        );
+        assert!(Obj::parse("v 0 0 0\nf -1 -2 -3\n").is_err());
+        assert!(Obj::parse("v 0 0 0\nf 1 1 2\n").is_err());
+    }
+
+    #[test]
+    fn corners_and_normals() {
+        let obj = Obj::parse(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0.25\n\
+             vn 0 0 -1\n\
+             f 1/1/1 2/2/1 3//1\n\
+             f 1 2 3\n",
+        )
+        .unwrap();
+        let triangles = &obj.groups[0].triangles;
+        assert_eq!(
+            triangles[0],
+            [(0, Some(0), 0), (1, Some(1), 0), (2, None, 0)]
+        );
+        // v is flipped so textures start at the top
+        assert_eq!(obj.uvs[1], Vec2::new(1.0, 0.75));
+        // faces without normals get a flat one after those of the file
+        assert_eq!(triangles[1].map(|corner| corner.2), [1; 3]);
+        assert_eq!(obj.normals, [Vec3::NEG_Z, Vec3::Z]);
+    }
+
+    #[test]
+    fn groups_by_material() {
+        let obj = Obj::parse(
+            "mtllib scene.mtl\n\
+             v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             usemtl b\nf 1 2 3\n\
+             usemtl a\nf 1 2 3\n\
+             usemtl b\nf 3 2 1\n\
+             curv 0 1 1 2\n",
+        )
+        .unwrap();
+        let materials: Vec<_> = obj
+            .groups
+            .iter()
+            .map(|group| group.material.as_deref())
+            .collect();
+        assert_eq!(materials, [Some("b"), Some("a")]);
+        assert_eq!(obj.groups[0].triangles.len(), 2);
+        assert_eq!(obj.libraries, ["scene.mtl"]);
+        assert!(obj.unsupported.contains("curv"));
+    }
+
+    #[test]
+    fn texture_options_are_skipped() {
+        let path = |line: &str| texture_path(line.split_whitespace());
+        assert_eq!(path("wood.png"), "wood.png");
+        assert_eq!(path("-o 0.5 1 0 -bm 0.3 bumps.png"), "bumps.png");
+        assert_eq!(path("-s 2 2 -clamp on my texture.png"), "my texture.png");
+        assert_eq!(path("-mm 0 1 -o 0.5 a.png"), "a.png");
+        assert_eq!(path("-t 1 b.png"), "b.png");
+    }
+
+    #[test]
+    fn mtl_statements() {
+        let mtl = Mtl::parse(
+            "# exported\n\
+             newmtl glass pane\n\
+             Kd 0.1 0.2 0.3\n\
+             Ns 96\n\
+             d 0.25\n\
+             Ka 1 1 1\n\
+             map_Kd -s 2 2 textures\\glass.png\n\
+             map_d glass_alpha.png\n\
+             Pr 0.5\n\
+             newmtl gray\n\
+             Ks 0.5\n",
+        )
+        .unwrap();
+        let [(glass_name, glass), (gray_name, gray)] = &mtl.materials[..] else {
+            panic!("expected two materials");
+        };
+        assert_eq!(glass_name, "glass pane");
+        assert_eq!(glass.diffuse, Some(Vec3::new(0.1, 0.2, 0.3)));
+        assert_eq!(glass.shininess, Some(96.0));
+        assert_eq!(glass.dissolve, Some(0.25));
+        assert_eq!(
+            glass.diffuse_texture.as_deref(),
+            Some("textures\\glass.png")
+        );
+        assert!(glass.alpha_texture);
+        assert_eq!(gray_name, "gray");
+        assert_eq!(gray.specular, Some(Vec3::splat(0.5)));
+        assert_eq!(mtl.unsupported, BTreeSet::from(["Pr".to_string()]));
+        assert!(Mtl::parse("Kd 1 1 1\n").is_err());
+    }
+}
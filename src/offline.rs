@@ -0,0 +1,266 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    app::{PluginGroupBuilder, ScheduleRunnerPlugin},
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_resource::{
+            BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d, ImageCopyBuffer,
+            ImageDataLayout, Maintain, MapMode, PipelineCache, TextureDimension, TextureFormat,
+            TextureUsages,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::GpuImage,
+        Render, RenderApp, RenderSet,
+    },
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use image::{DynamicImage, ImageBuffer};
+
+use crate::{
+    pipeline::RayTracingPipeline,
+    ray_tracing::RayTracingInfo,
+    restir::RestirGiSettings,
+    svgf::{SvgfPipeline, SvgfSettings},
+};
+
+/// Renders a fixed number of samples per pixel into an image, writes it to a file and exits
+///
+/// Render a camera to [`OfflineRender::image`] and insert [`OfflineRender`]. Samples are counted
+/// from the last time the accumulation was reset, once [`OfflineRender::ready`] is set and the
+/// pipelines have compiled.
+pub struct OfflineRenderPlugin;
+
+impl Plugin for OfflineRenderPlugin {
+    fn build(&self, app: &mut App) {
+        let exit = OfflineRenderExit::default();
+        app.insert_resource(exit.clone())
+            .add_plugins(ExtractResourcePlugin::<OfflineRender>::default())
+            .add_systems(Last, exit_when_written);
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(exit)
+                .add_systems(Render, write_render.in_set(RenderSet::Cleanup));
+        }
+    }
+}
+
+/// [`DefaultPlugins`] without windows, updating as fast as possible
+pub fn headless_plugins() -> PluginGroupBuilder {
+    DefaultPlugins
+        .set(WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            close_when_requested: false,
+        })
+        .disable::<WinitPlugin>()
+        .add(ScheduleRunnerPlugin::run_loop(Duration::ZERO))
+}
+
+#[derive(Resource, ExtractResource, Clone)]
+pub struct OfflineRender {
+    /// Target of the rendered camera, from [`OfflineRender::target`]
+    pub image: Handle<Image>,
+    pub samples: u32,
+    /// Written as OpenEXR for float images and as whatever the extension says otherwise
+    pub output: PathBuf,
+    /// Whether the scene is in place, nothing is counted before
+    pub ready: bool,
+}
+
+impl OfflineRender {
+    /// Image to render to, holding linear radiance for HDR output or tonemapped sRGB otherwise
+    pub fn target(size: UVec2, hdr: bool) -> Image {
+        let format = if hdr {
+            TextureFormat::Rgba32Float
+        } else {
+            TextureFormat::Rgba8UnormSrgb
+        };
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &vec![0; format.block_copy_size(None).unwrap() as usize],
+            format,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC
+            | TextureUsages::RENDER_ATTACHMENT;
+        image
+    }
+}
+
+/// Set by the render world once the image was written, or failed to
+#[derive(Resource, Clone, Default)]
+struct OfflineRenderExit(Arc<Mutex<Option<AppExit>>>);
+
+fn exit_when_written(exit: Res<OfflineRenderExit>, mut app_exit: EventWriter<AppExit>) {
+    if let Some(exit) = exit.0.lock().unwrap().take() {
+        app_exit.send(exit);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_render(
+    mut samples: Local<u32>,
+    request: Option<Res<OfflineRender>>,
+    exit: Res<OfflineRenderExit>,
+    info: Res<RayTracingInfo>,
+    ray_tracing_pipeline: Res<RayTracingPipeline>,
+    svgf_pipeline: Res<SvgfPipeline>,
+    (gi_settings, svgf_settings): (Res<RestirGiSettings>, Res<SvgfSettings>),
+    pipeline_cache: Res<PipelineCache>,
+    images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(request) = request else {
+        return;
+    };
+    if *info.count.get() == 0 {
+        *samples = 0;
+    }
+    let Some(image) = images.get(&request.image) else {
+        return;
+    };
+    let mut render_pipelines = ray_tracing_pipeline.pipeline_ids.to_vec();
+    let mut compute_pipelines = vec![ray_tracing_pipeline.restir_pipeline_id];
+    if gi_settings.enabled {
+        compute_pipelines.extend([
+            ray_tracing_pipeline.restir_gi_candidates_pipeline_id,
+            ray_tracing_pipeline.restir_gi_resample_pipeline_id,
+        ]);
+    }
+    if svgf_settings.enabled {
+        render_pipelines.extend(svgf_pipeline.resolve_pipeline_ids);
+        compute_pipelines.extend([
+            svgf_pipeline.temporal_pipeline_id,
+            svgf_pipeline.variance_pipeline_id,
+            svgf_pipeline.atrous_pipeline_id,
+        ]);
+    }
+    let compiled = render_pipelines
+        .into_iter()
+        .all(|id| pipeline_cache.get_render_pipeline(id).is_some())
+        && compute_pipelines
+            .into_iter()
+            .all(|id| pipeline_cache.get_compute_pipeline(id).is_some());
+    if !request.ready || !compiled || *samples >= request.samples {
+        return;
+    }
+    *samples += 1;
+    if *samples < request.samples {
+        return;
+    }
+
+    let result = read_image(&render_device, &render_queue, image)
+        .and_then(|image| write_image(image, &request.output));
+    *exit.0.lock().unwrap() = Some(match result {
+        Ok(()) => {
+            info!("wrote {}", request.output.display());
+            AppExit::Success
+        }
+        Err(error) => {
+            error!("failed to write {}: {error}", request.output.display());
+            AppExit::error()
+        }
+    });
+}
+
+/// Copies the image back from the gpu, blocking until it arrived
+fn read_image(
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+    image: &GpuImage,
+) -> Result<DynamicImage, String> {
+    let size = image.size;
+    let row_bytes = (size.x * image.texture_format.block_copy_size(None).unwrap()) as usize;
+    let padded_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("offline_render_readback"),
+        size: (padded_row_bytes * size.y as usize) as u64,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("offline_render_readback"),
+    });
+    encoder.copy_texture_to_buffer(
+        image.texture.as_image_copy(),
+        ImageCopyBuffer {
+            buffer: &buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes as u32),
+                rows_per_image: None,
+            },
+        },
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+    );
+    render_queue.submit([encoder.finish()]);
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    render_device.map_buffer(&slice, MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    render_device.poll(Maintain::Wait);
+    receiver
+        .recv()
+        .map_err(|error| error.to_string())?
+        .map_err(|error| error.to_string())?;
+    let data: Vec<u8> = slice
+        .get_mapped_range()
+        .chunks(padded_row_bytes)
+        .flat_map(|row| &row[..row_bytes])
+        .copied()
+        .collect();
+    buffer.unmap();
+
+    let (width, height) = (size.x, size.y);
+    match image.texture_format {
+        TextureFormat::Rgba8UnormSrgb => {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        }
+        TextureFormat::Rgba32Float => {
+            let data = data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect();
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba32F)
+        }
+        format => return Err(format!("can't read back {format:?} images")),
+    }
+    .ok_or_else(|| "image data doesn't match its size".to_string())
+}
+
+fn write_image(image: DynamicImage, output: &Path) -> Result<(), String> {
+    let image = match image {
+        // the alpha channel holds nothing useful
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgb8(image.into_rgb8()),
+        image => image,
+    };
+    image.save(output).map_err(|error| error.to_string())
+}
+
+/// Whether `output` is written as OpenEXR, which keeps the HDR radiance
+pub fn is_hdr_output(output: &Path) -> bool {
+    output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"))
+}
@@ -0,0 +1,1387 @@
+use std::{
+    collections::BTreeSet,
+    f32::consts::{FRAC_PI_2, PI},
+    iter::Peekable,
+    vec::IntoIter,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AssetPath, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        camera::Exposure,
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        texture::ImageLoaderSettings,
+    },
+    utils::HashMap,
+};
+
+use crate::{environment::EquirectangularEnvironment, ply};
+
+/// Range of the point lights, pbrt's lights reach everything
+const LIGHT_RANGE: f32 = 1e4;
+/// Rings of the triangulated spheres, they have twice as many segments
+const SPHERE_RINGS: u32 = 32;
+
+/// Loads a subset of pbrt-v4's `.pbrt` scene files as [`Scene`]s
+///
+/// Supports `Camera "perspective"`, `Film`, `Shape "trianglemesh"`, `"plymesh"` and `"sphere"`,
+/// `Material "diffuse"`, `"conductor"`, `"dielectric"` and `"coateddiffuse"`,
+/// `LightSource "point"`, `"distant"` and `"infinite"` and `AreaLightSource "diffuse"`, along with
+/// the transform, attribute, named material, image texture, object instancing and include
+/// directives. Everything else is skipped with a warning.
+///
+/// Geometry is mirrored along z from pbrt's left-handed into bevy's right-handed space and baked
+/// into the meshes. Radiance is scaled by the inverse of bevy's default [`Exposure`], so a camera
+/// with it renders the same values pbrt does. The scene's camera holds the infinite light as an
+/// [`EquirectangularEnvironment`], which is black without one. Film only sets the aspect ratio
+/// the field of view applies to, the render's size is up to the camera's target. Area lights
+/// emit on both sides.
+pub struct PbrtPlugin;
+
+impl Plugin for PbrtPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_asset_loader(PbrtLoader);
+    }
+}
+
+pub struct PbrtLoader;
+
+impl AssetLoader for PbrtLoader {
+    type Asset = Scene;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Scene, String> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|error| error.to_string())?;
+        let mut importer = Importer::new(load_context.asset_path().clone_owned());
+        // shapes before any Material directive are diffuse
+        let params = Params {
+            directive: "Material \"diffuse\"".to_string(),
+            list: Vec::new(),
+        };
+        importer.material("diffuse", params, load_context);
+        importer.include(&bytes)?;
+        while let Some(token) = importer.next() {
+            let result = match token {
+                Token::Word(directive) => importer.directive(&directive, load_context).await,
+                token => Err(format!("expected a directive, got {token:?}")),
+            };
+            result.map_err(|error| format!("line {}: {error}", importer.line))?;
+        }
+        Ok(importer.finish(load_context))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["pbrt"]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Open,
+    Close,
+}
+
+/// Splits a file into tokens along with the line they are on
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '[' => tokens.push((Token::Open, line)),
+            ']' => tokens.push((Token::Close, line)),
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => string.extend(chars.next()),
+                        Some('\n') | None => {
+                            return Err(format!("line {line}: unterminated string"))
+                        }
+                        Some(c) => string.push(c),
+                    }
+                }
+                tokens.push((Token::Str(string), line));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !matches!(c, '[' | ']' | '"' | '#'))
+                {
+                    word.push(c);
+                }
+                tokens.push((Token::Word(word), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A parameter like `"rgb reflectance" [ 0.5 0.5 0.5 ]`
+struct Param {
+    ty: String,
+    name: String,
+    numbers: Vec<f32>,
+    strings: Vec<String>,
+    used: bool,
+}
+
+/// Parameters of a directive, the ones never read are reported as unsupported
+struct Params {
+    directive: String,
+    list: Vec<Param>,
+}
+
+impl Params {
+    fn get(&mut self, name: &str) -> Option<&Param> {
+        let param = self.list.iter_mut().find(|param| param.name == name)?;
+        param.used = true;
+        Some(param)
+    }
+
+    fn float(&mut self, name: &str, default: f32) -> f32 {
+        self.get(name)
+            .and_then(|param| param.numbers.first().copied())
+            .unwrap_or(default)
+    }
+
+    fn numbers(&mut self, name: &str) -> Vec<f32> {
+        self.get(name)
+            .map(|param| param.numbers.clone())
+            .unwrap_or_default()
+    }
+
+    fn vec3(&mut self, name: &str, default: Vec3) -> Vec3 {
+        match self.numbers(name)[..] {
+            [x, y, z] => Vec3::new(x, y, z),
+            _ => default,
+        }
+    }
+
+    fn string(&mut self, name: &str) -> Option<String> {
+        self.get(name)
+            .and_then(|param| param.strings.first().cloned())
+    }
+
+    fn bool(&mut self, name: &str, default: bool) -> bool {
+        self.string(name).map_or(default, |value| value == "true")
+    }
+
+    /// Marks parameters that don't matter to the ray tracer as used
+    fn ignore(&mut self, names: &[&str]) {
+        for param in &mut self.list {
+            param.used |= names.contains(&param.name.as_str());
+        }
+    }
+}
+
+/// The state `AttributeBegin` and `AttributeEnd` save and restore
+#[derive(Clone)]
+struct Attributes {
+    /// pbrt's world from object transform
+    transform: Mat4,
+    /// Index into [`Importer::materials`]
+    material: usize,
+    /// Index and scaled radiance of the area light shapes emit
+    area_light: Option<(usize, Vec3)>,
+    reverse_orientation: bool,
+}
+
+struct Importer {
+    path: AssetPath<'static>,
+    /// Token streams of the file and the files it includes
+    files: Vec<Peekable<IntoIter<(Token, usize)>>>,
+    line: usize,
+    attributes: Attributes,
+    attribute_stack: Vec<Attributes>,
+    transform_stack: Vec<Mat4>,
+    coordinate_systems: HashMap<String, Mat4>,
+    camera_from_world: Mat4,
+    fov: f32,
+    frame_aspect_ratio: Option<f32>,
+    resolution: Vec2,
+    materials: Vec<(StandardMaterial, Handle<StandardMaterial>)>,
+    named_materials: HashMap<String, usize>,
+    /// Materials of area lights, by material and area light
+    emissive_materials: HashMap<(usize, usize), Handle<StandardMaterial>>,
+    area_lights: usize,
+    textures: HashMap<String, Handle<Image>>,
+    /// Object being defined and its shapes
+    object: Option<(String, Vec<PbrBundle>)>,
+    objects: HashMap<String, Vec<PbrBundle>>,
+    environment: Option<(Handle<Image>, f32)>,
+    meshes: usize,
+    world: World,
+    warnings: BTreeSet<String>,
+}
+
+impl Importer {
+    fn new(path: AssetPath<'static>) -> Self {
+        Self {
+            path,
+            files: Vec::new(),
+            line: 0,
+            attributes: Attributes {
+                transform: Mat4::IDENTITY,
+                material: 0,
+                area_light: None,
+                reverse_orientation: false,
+            },
+            attribute_stack: Vec::new(),
+            transform_stack: Vec::new(),
+            coordinate_systems: HashMap::new(),
+            camera_from_world: Mat4::IDENTITY,
+            fov: 90.0,
+            frame_aspect_ratio: None,
+            resolution: Vec2::new(1280.0, 720.0),
+            materials: Vec::new(),
+            named_materials: HashMap::new(),
+            emissive_materials: HashMap::new(),
+            area_lights: 0,
+            textures: HashMap::new(),
+            object: None,
+            objects: HashMap::new(),
+            environment: None,
+            meshes: 0,
+            world: World::new(),
+            warnings: BTreeSet::new(),
+        }
+    }
+
+    fn include(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let tokens = tokenize(&String::from_utf8_lossy(bytes))?;
+        self.files.push(tokens.into_iter().peekable());
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            match self.files.last_mut()?.next() {
+                Some((token, line)) => {
+                    self.line = line;
+                    return Some(token);
+                }
+                None => {
+                    self.files.pop();
+                }
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<&Token> {
+        while self.files.last_mut()?.peek().is_none() {
+            self.files.pop();
+        }
+        self.files.last_mut()?.peek().map(|(token, _)| token)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Str(string)) => Ok(string),
+            token => Err(format!("expected a string, got {token:?}")),
+        }
+    }
+
+    /// `N` numbers, optionally in brackets
+    fn numbers<const N: usize>(&mut self) -> Result<[f32; N], String> {
+        let bracketed = self.peek() == Some(&Token::Open);
+        if bracketed {
+            self.next();
+        }
+        let mut numbers = [0.0; N];
+        for number in &mut numbers {
+            *number = match self.next() {
+                Some(Token::Word(word)) => word
+                    .parse()
+                    .map_err(|_| format!("expected a number, got {word}"))?,
+                token => return Err(format!("expected a number, got {token:?}")),
+            };
+        }
+        if bracketed && self.next() != Some(Token::Close) {
+            return Err("expected ]".to_string());
+        }
+        Ok(numbers)
+    }
+
+    fn params(&mut self, directive: String) -> Result<Params, String> {
+        let mut list = Vec::new();
+        while let Some(Token::Str(_)) = self.peek() {
+            let declaration = self.string()?;
+            let (ty, name) = match declaration.split_whitespace().collect::<Vec<_>>()[..] {
+                [ty, name] => (ty.to_string(), name.to_string()),
+                _ => return Err(format!("bad parameter \"{declaration}\"")),
+            };
+            let values = if self.peek() == Some(&Token::Open) {
+                self.next();
+                let mut values = Vec::new();
+                loop {
+                    match self.next() {
+                        Some(Token::Close) => break,
+                        Some(token) => values.push(token),
+                        None => return Err("expected ]".to_string()),
+                    }
+                }
+                values
+            } else {
+                self.next().into_iter().collect()
+            };
+            let mut param = Param {
+                ty,
+                name,
+                numbers: Vec::new(),
+                strings: Vec::new(),
+                used: false,
+            };
+            for value in values {
+                match value {
+                    Token::Word(word) => match word.parse() {
+                        Ok(number) => param.numbers.push(number),
+                        Err(_) => param.strings.push(word),
+                    },
+                    Token::Str(string) => param.strings.push(string),
+                    token => return Err(format!("bad value {token:?} of \"{declaration}\"")),
+                }
+            }
+            list.push(param);
+        }
+        Ok(Params { directive, list })
+    }
+
+    /// Skips the arguments of a directive that isn't supported
+    fn skip_arguments(&mut self) {
+        while let Some(token) = self.peek() {
+            if let Token::Word(word) = token {
+                if word.parse::<f32>().is_err() && word != "true" && word != "false" {
+                    break;
+                }
+            }
+            self.next();
+        }
+    }
+
+    fn warn(&mut self, warning: String) {
+        self.warnings.insert(warning);
+    }
+
+    fn warn_unused(&mut self, params: Params) {
+        for param in params.list.iter().filter(|param| !param.used) {
+            self.warnings.insert(format!(
+                "{}: unsupported parameter \"{} {}\"",
+                params.directive, param.ty, param.name
+            ));
+        }
+    }
+
+    fn transform(&mut self, matrix: Mat4) {
+        self.attributes.transform *= matrix;
+    }
+
+    /// Runs the directives that only change the transform and attributes, returns whether
+    /// `directive` was one of them
+    fn state_directive(&mut self, directive: &str) -> Result<bool, String> {
+        match directive {
+            "Identity" => self.attributes.transform = Mat4::IDENTITY,
+            "Translate" => {
+                let [x, y, z] = self.numbers()?;
+                self.transform(Mat4::from_translation(Vec3::new(x, y, z)));
+            }
+            "Scale" => {
+                let [x, y, z] = self.numbers()?;
+                self.transform(Mat4::from_scale(Vec3::new(x, y, z)));
+            }
+            "Rotate" => {
+                let [angle, x, y, z] = self.numbers()?;
+                let axis = Vec3::new(x, y, z).try_normalize().unwrap_or(Vec3::Z);
+                self.transform(Mat4::from_axis_angle(axis, angle.to_radians()));
+            }
+            "LookAt" => {
+                let [ex, ey, ez, lx, ly, lz, ux, uy, uz] = self.numbers()?;
+                self.transform(Mat4::look_at_lh(
+                    Vec3::new(ex, ey, ez),
+                    Vec3::new(lx, ly, lz),
+                    Vec3::new(ux, uy, uz),
+                ));
+            }
+            "Transform" => {
+                let matrix: [f32; 16] = self.numbers()?;
+                self.attributes.transform = Mat4::from_cols_array(&matrix);
+            }
+            "ConcatTransform" => {
+                let matrix: [f32; 16] = self.numbers()?;
+                self.transform(Mat4::from_cols_array(&matrix));
+            }
+            "CoordinateSystem" => {
+                let name = self.string()?;
+                self.coordinate_systems
+                    .insert(name, self.attributes.transform);
+            }
+            "CoordSysTransform" => {
+                let name = self.string()?;
+                match self.coordinate_systems.get(&name) {
+                    Some(&transform) => self.attributes.transform = transform,
+                    None => self.warn(format!("unknown coordinate system \"{name}\"")),
+                }
+            }
+            "ReverseOrientation" => {
+                self.attributes.reverse_orientation = !self.attributes.reverse_orientation;
+            }
+            "WorldBegin" => {
+                self.attributes.transform = Mat4::IDENTITY;
+                self.coordinate_systems
+                    .insert("world".to_string(), Mat4::IDENTITY);
+            }
+            "WorldEnd" => {}
+            "AttributeBegin" => self.attribute_stack.push(self.attributes.clone()),
+            "AttributeEnd" => {
+                self.attributes = self
+                    .attribute_stack
+                    .pop()
+                    .ok_or("AttributeEnd without AttributeBegin")?;
+            }
+            "TransformBegin" => self.transform_stack.push(self.attributes.transform),
+            "TransformEnd" => {
+                self.attributes.transform = self
+                    .transform_stack
+                    .pop()
+                    .ok_or("TransformEnd without TransformBegin")?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    async fn directive(
+        &mut self,
+        directive: &str,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<(), String> {
+        if self.state_directive(directive)? {
+            return Ok(());
+        }
+        match directive {
+            "Include" | "Import" => {
+                let file = self.string()?;
+                if file.ends_with(".gz") {
+                    self.warn(format!("skipping compressed file {file}"));
+                    return Ok(());
+                }
+                let path = resolve(&self.path, &file)?;
+                let bytes = load_context
+                    .read_asset_bytes(&path)
+                    .await
+                    .map_err(|error| error.to_string())?;
+                self.include(&bytes)?;
+            }
+            "Camera" => {
+                let ty = self.string()?;
+                let mut params = self.params(format!("Camera \"{ty}\""))?;
+                if ty != "perspective" {
+                    self.warn(format!(
+                        "unsupported camera \"{ty}\", using a perspective one"
+                    ));
+                }
+                self.camera_from_world = self.attributes.transform;
+                self.coordinate_systems
+                    .insert("camera".to_string(), self.camera_from_world.inverse());
+                self.fov = params.float("fov", 90.0);
+                self.frame_aspect_ratio = params
+                    .get("frameaspectratio")
+                    .and_then(|param| param.numbers.first().copied());
+                self.warn_unused(params);
+            }
+            "Film" => {
+                let ty = self.string()?;
+                let mut params = self.params(format!("Film \"{ty}\""))?;
+                self.resolution = Vec2::new(
+                    params.float("xresolution", 1280.0),
+                    params.float("yresolution", 720.0),
+                );
+                params.ignore(&["filename"]);
+                self.warn_unused(params);
+            }
+            "Texture" => {
+                let name = self.string()?;
+                let ty = self.string()?;
+                let class = self.string()?;
+                let params = self.params(format!("Texture \"{class}\""))?;
+                self.texture(name, &ty, &class, params, load_context)?;
+            }
+            "Material" => {
+                let ty = self.string()?;
+                let params = self.params(format!("Material \"{ty}\""))?;
+                self.attributes.material = self.material(&ty, params, load_context);
+            }
+            "MakeNamedMaterial" => {
+                let name = self.string()?;
+                let mut params = self.params(format!("MakeNamedMaterial \"{name}\""))?;
+                let ty = params.string("type").unwrap_or_default();
+                params.directive = format!("Material \"{ty}\"");
+                let material = self.material(&ty, params, load_context);
+                self.named_materials.insert(name, material);
+            }
+            "NamedMaterial" => {
+                let name = self.string()?;
+                match self.named_materials.get(&name) {
+                    Some(&material) => self.attributes.material = material,
+                    None => self.warn(format!("unknown material \"{name}\"")),
+                }
+            }
+            "LightSource" => {
+                let ty = self.string()?;
+                let params = self.params(format!("LightSource \"{ty}\""))?;
+                self.light(&ty, params, load_context).await?;
+            }
+            "AreaLightSource" => {
+                let ty = self.string()?;
+                let mut params = self.params(format!("AreaLightSource \"{ty}\""))?;
+                if ty != "diffuse" {
+                    self.warn(format!("unsupported area light \"{ty}\""));
+                    return Ok(());
+                }
+                let radiance = self.spectrum(&mut params, "L").unwrap_or(Vec3::ONE);
+                let scale = params.float("scale", 1.0) * radiance_scale();
+                params.ignore(&["twosided"]);
+                self.warn_unused(params);
+                self.area_lights += 1;
+                self.attributes.area_light = Some((self.area_lights, radiance * scale));
+            }
+            "Shape" => {
+                let ty = self.string()?;
+                let params = self.params(format!("Shape \"{ty}\""))?;
+                self.shape(&ty, params, load_context).await?;
+            }
+            "ObjectBegin" => {
+                let name = self.string()?;
+                self.attribute_stack.push(self.attributes.clone());
+                self.object = Some((name, Vec::new()));
+            }
+            "ObjectEnd" => {
+                let (name, shapes) = self.object.take().ok_or("ObjectEnd without ObjectBegin")?;
+                self.objects.insert(name, shapes);
+                self.attributes = self
+                    .attribute_stack
+                    .pop()
+                    .ok_or("ObjectEnd without ObjectBegin")?;
+            }
+            "ObjectInstance" => {
+                let name = self.string()?;
+                let Some(shapes) = self.objects.get(&name) else {
+                    self.warn(format!("unknown object \"{name}\""));
+                    return Ok(());
+                };
+                let transform =
+                    Transform::from_matrix(mirror() * self.attributes.transform * mirror());
+                let bundles: Vec<_> = shapes
+                    .iter()
+                    .map(|shape| PbrBundle {
+                        mesh: shape.mesh.clone(),
+                        material: shape.material.clone(),
+                        transform,
+                        ..default()
+                    })
+                    .collect();
+                self.world.spawn_batch(bundles);
+            }
+            "Sampler" | "Integrator" | "PixelFilter" | "Accelerator" => {
+                self.skip_arguments();
+                self.warn(format!(
+                    "ignoring {directive}, the ray tracer's settings are used instead"
+                ));
+            }
+            "ColorSpace" | "Option" | "Attribute" | "MakeNamedMedium" | "MediumInterface"
+            | "TransformTimes" => {
+                self.skip_arguments();
+                self.warn(format!("unsupported directive {directive}"));
+            }
+            "ActiveTransform" => {
+                self.next();
+                self.warn(format!("unsupported directive {directive}"));
+            }
+            _ => {
+                self.skip_arguments();
+                self.warn(format!("unknown directive {directive}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// A color parameter's linear RGB value
+    fn spectrum(&mut self, params: &mut Params, name: &str) -> Option<Vec3> {
+        let directive = params.directive.clone();
+        let param = params.get(name)?;
+        match (param.ty.as_str(), &param.numbers[..], param.strings.first()) {
+            ("rgb", &[r, g, b], _) => Some(Vec3::new(r, g, b)),
+            ("blackbody", &[temperature], _) => Some(blackbody(temperature)),
+            ("float", &[value], _) => Some(Vec3::splat(value)),
+            ("spectrum", _, Some(spectrum)) => {
+                let warning = format!("{directive}: unsupported spectrum \"{spectrum}\"");
+                self.warn(warning);
+                None
+            }
+            _ => {
+                let warning = format!("{directive}: unsupported {} \"{name}\"", param.ty);
+                self.warn(warning);
+                None
+            }
+        }
+    }
+
+    /// A color parameter, which may be an image texture
+    fn color(
+        &mut self,
+        params: &mut Params,
+        name: &str,
+        default: Vec3,
+    ) -> (Vec3, Option<Handle<Image>>) {
+        let Some(param) = params.get(name) else {
+            return (default, None);
+        };
+        if param.ty == "texture" {
+            let texture = param.strings.first().cloned().unwrap_or_default();
+            return match self.textures.get(&texture) {
+                Some(image) => (Vec3::ONE, Some(image.clone())),
+                None => {
+                    self.warn(format!("unknown texture \"{texture}\""));
+                    (default, None)
+                }
+            };
+        }
+        (self.spectrum(params, name).unwrap_or(default), None)
+    }
+
+    fn texture(
+        &mut self,
+        name: String,
+        ty: &str,
+        class: &str,
+        mut params: Params,
+        load_context: &mut LoadContext,
+    ) -> Result<(), String> {
+        if ty != "spectrum" || class != "imagemap" {
+            self.warn(format!("unsupported {ty} texture \"{class}\""));
+            return Ok(());
+        }
+        let Some(filename) = params.string("filename") else {
+            self.warn(format!("texture \"{name}\" has no filename"));
+            return Ok(());
+        };
+        let path = resolve(&self.path, &filename)?;
+        let image = if params.string("encoding").as_deref() == Some("linear") {
+            load_context
+                .loader()
+                .with_settings(|settings: &mut ImageLoaderSettings| settings.is_srgb = false)
+                .load(path)
+        } else {
+            load_context.load(path)
+        };
+        self.textures.insert(name, image);
+        self.warn_unused(params);
+        Ok(())
+    }
+
+    /// Maps pbrt's materials onto the closest [`StandardMaterial`]
+    ///
+    /// Diffuse materials have no specular lobe, conductors get the reflectance at normal incidence
+    /// of their named metal or complex index of refraction and coated diffuse materials use the
+    /// clearcoat. Roughness is remapped the way pbrt does.
+    fn material(&mut self, ty: &str, mut params: Params, load_context: &mut LoadContext) -> usize {
+        let mut material = StandardMaterial {
+            cull_mode: None,
+            double_sided: true,
+            ..default()
+        };
+        match ty {
+            "diffuse" | "coateddiffuse" => {
+                let (color, texture) = self.color(&mut params, "reflectance", Vec3::splat(0.5));
+                material.base_color = Color::linear_rgb(color.x, color.y, color.z);
+                material.base_color_texture = texture;
+                material.perceptual_roughness = 1.0;
+                material.reflectance = 0.0;
+                if ty == "coateddiffuse" {
+                    material.clearcoat = 1.0;
+                    material.clearcoat_perceptual_roughness = roughness(&mut params);
+                }
+            }
+            "conductor" => {
+                let color = if params.get("reflectance").is_some() {
+                    self.color(&mut params, "reflectance", Vec3::ONE).0
+                } else {
+                    self.conductor_reflectance(&mut params)
+                };
+                material.base_color = Color::linear_rgb(color.x, color.y, color.z);
+                material.metallic = 1.0;
+                material.perceptual_roughness = roughness(&mut params);
+            }
+            "dielectric" => {
+                let ior = match params.get("eta") {
+                    Some(param) => match (param.numbers.first(), param.strings.first()) {
+                        (Some(&eta), _) => eta,
+                        (_, Some(glass)) => glass_ior(glass).unwrap_or_else(|| {
+                            let warning = format!("unsupported spectrum \"{glass}\"");
+                            self.warn(warning);
+                            1.5
+                        }),
+                        _ => 1.5,
+                    },
+                    None => 1.5,
+                };
+                let f0 = ((ior - 1.0) / (ior + 1.0)).powi(2);
+                material.base_color = Color::WHITE;
+                material.specular_transmission = 1.0;
+                material.ior = ior;
+                material.reflectance = (f0 / 0.16).sqrt();
+                material.perceptual_roughness = roughness(&mut params);
+            }
+            _ => {
+                self.warn(format!(
+                    "unsupported material \"{ty}\", using a diffuse one"
+                ));
+                material.base_color = Color::linear_rgb(0.5, 0.5, 0.5);
+                material.perceptual_roughness = 1.0;
+                material.reflectance = 0.0;
+                // its parameters mean something else
+                params.list.clear();
+            }
+        }
+        params.ignore(&["type"]);
+        self.warn_unused(params);
+        self.add_material(material, load_context)
+    }
+
+    fn add_material(
+        &mut self,
+        material: StandardMaterial,
+        load_context: &mut LoadContext,
+    ) -> usize {
+        let index = self.materials.len();
+        let handle = load_context.add_labeled_asset(format!("Material{index}"), material.clone());
+        self.materials.push((material, handle));
+        index
+    }
+
+    fn conductor_reflectance(&mut self, params: &mut Params) -> Vec3 {
+        let eta = params
+            .get("eta")
+            .map(|param| (param.numbers.clone(), param.strings.clone()));
+        let k = params.get("k").map(|param| param.numbers.clone());
+        match (eta, k) {
+            (Some((eta, _)), Some(k)) if eta.len() == 3 && k.len() == 3 => {
+                let eta = Vec3::from_slice(&eta);
+                let k = Vec3::from_slice(&k);
+                let k2 = k * k;
+                ((eta - 1.0) * (eta - 1.0) + k2) / ((eta + 1.0) * (eta + 1.0) + k2)
+            }
+            (Some((_, names)), _) if !names.is_empty() => metal_reflectance(&names[0])
+                .unwrap_or_else(|| {
+                    self.warn(format!("unsupported spectrum \"{}\"", names[0]));
+                    metal_reflectance("metal-Cu-eta").unwrap()
+                }),
+            _ => metal_reflectance("metal-Cu-eta").unwrap(),
+        }
+    }
+
+    async fn light(
+        &mut self,
+        ty: &str,
+        mut params: Params,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<(), String> {
+        let scale = params.float("scale", 1.0) * radiance_scale();
+        match ty {
+            "point" => {
+                let intensity = self.spectrum(&mut params, "I").unwrap_or(Vec3::ONE);
+                let power = params.float("power", 0.0);
+                let scale = if power > 0.0 {
+                    scale * power / (4.0 * PI)
+                } else {
+                    scale
+                };
+                let from = params.vec3("from", Vec3::ZERO);
+                let position =
+                    mirror().transform_point3(self.attributes.transform.transform_point3(from));
+                self.world.spawn(PointLightBundle {
+                    point_light: PointLight {
+                        color: Color::linear_rgb(intensity.x, intensity.y, intensity.z),
+                        // the ray tracer divides the lumens by 4π
+                        intensity: 4.0 * PI * scale,
+                        range: LIGHT_RANGE,
+                        radius: 0.0,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(position),
+                    ..default()
+                });
+            }
+            "distant" => {
+                let radiance = self.spectrum(&mut params, "L").unwrap_or(Vec3::ONE);
+                let from = params.vec3("from", Vec3::ZERO);
+                let to = params.vec3("to", Vec3::Z);
+                let to_light = mirror()
+                    .transform_vector3(self.attributes.transform.transform_vector3(from - to))
+                    .try_normalize()
+                    .unwrap_or(Vec3::Y);
+                let up = if to_light.y.abs() > 0.99 {
+                    Vec3::Z
+                } else {
+                    Vec3::Y
+                };
+                self.world.spawn(DirectionalLightBundle {
+                    directional_light: DirectionalLight {
+                        color: Color::linear_rgb(radiance.x, radiance.y, radiance.z),
+                        illuminance: scale,
+                        ..default()
+                    },
+                    transform: Transform::default().looking_to(-to_light, up),
+                    ..default()
+                });
+            }
+            "infinite" => {
+                if self.environment.is_some() {
+                    self.warn("only the first infinite light is used".to_string());
+                    return Ok(());
+                }
+                let light_from_world = Mat3::from_mat4(self.attributes.transform).inverse()
+                    * Mat3::from_mat4(mirror());
+                let image = match params.string("filename") {
+                    Some(filename) => {
+                        let path = resolve(&self.path, &filename)?;
+                        let bytes = load_context
+                            .read_asset_bytes(&path)
+                            .await
+                            .map_err(|error| error.to_string())?;
+                        let image = image::load_from_memory(&bytes)
+                            .map_err(|error| format!("can't read {filename}: {error}"))?;
+                        let linear = matches!(
+                            image.color(),
+                            image::ColorType::Rgb32F | image::ColorType::Rgba32F
+                        );
+                        let image = image.into_rgb32f();
+                        if image.width() != image.height() {
+                            return Err(format!(
+                                "{filename} isn't square, pbrt-v4 needs an equal-area octahedral map"
+                            ));
+                        }
+                        let size = image.width();
+                        equirectangular(UVec2::new(2 * size, size), |direction| {
+                            let uv = equal_area_sphere_to_square(light_from_world * direction);
+                            let texel = (uv * size as f32).as_uvec2().min(UVec2::splat(size - 1));
+                            let [r, g, b] = image.get_pixel(texel.x, texel.y).0;
+                            if linear {
+                                Vec3::new(r, g, b)
+                            } else {
+                                LinearRgba::from(Srgba::rgb(r, g, b)).to_vec3()
+                            }
+                        })
+                    }
+                    None => {
+                        let radiance = self.spectrum(&mut params, "L").unwrap_or(Vec3::ONE);
+                        equirectangular(UVec2::new(2, 1), |_| radiance)
+                    }
+                };
+                let image = load_context.add_labeled_asset("Environment".to_string(), image);
+                self.environment = Some((image, scale));
+            }
+            _ => {
+                self.warn(format!("unsupported light \"{ty}\""));
+                return Ok(());
+            }
+        }
+        self.warn_unused(params);
+        Ok(())
+    }
+
+    async fn shape(
+        &mut self,
+        ty: &str,
+        mut params: Params,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<(), String> {
+        let shape = match ty {
+            "trianglemesh" => {
+                let positions = params.numbers("P");
+                let mut indices: Vec<u32> = params
+                    .numbers("indices")
+                    .iter()
+                    .map(|&index| index as u32)
+                    .collect();
+                if indices.is_empty() && positions.len() == 9 {
+                    indices = vec![0, 1, 2];
+                }
+                let normals = params.numbers("N");
+                let uvs = params.numbers("uv");
+                let count = positions.len() / 3;
+                if indices.iter().any(|&index| index as usize >= count) {
+                    return Err("triangle index out of range".to_string());
+                }
+                ply::PlyMesh {
+                    positions: positions.chunks_exact(3).map(Vec3::from_slice).collect(),
+                    normals: (normals.len() == count * 3)
+                        .then(|| normals.chunks_exact(3).map(Vec3::from_slice).collect()),
+                    uvs: (uvs.len() == count * 2)
+                        .then(|| uvs.chunks_exact(2).map(Vec2::from_slice).collect()),
+                    indices,
+                }
+            }
+            "plymesh" => {
+                let filename = params.string("filename").unwrap_or_default();
+                if filename.ends_with(".gz") {
+                    self.warn(format!("skipping compressed mesh {filename}"));
+                    return Ok(());
+                }
+                let path = resolve(&self.path, &filename)?;
+                let bytes = load_context
+                    .read_asset_bytes(&path)
+                    .await
+                    .map_err(|error| error.to_string())?;
+                ply::parse(&bytes).map_err(|error| format!("{filename}: {error}"))?
+            }
+            "sphere" => sphere(&mut params),
+            _ => {
+                self.warn(format!("unsupported shape \"{ty}\""));
+                return Ok(());
+            }
+        };
+        self.warn_unused(params);
+        if shape.indices.is_empty() {
+            return Ok(());
+        }
+
+        let attributes = self.attributes.clone();
+        let mesh = mesh(shape, attributes.transform, attributes.reverse_orientation);
+        let mesh = load_context.add_labeled_asset(format!("Mesh{}", self.meshes), mesh);
+        self.meshes += 1;
+        let material = match (attributes.area_light, self.object.is_some()) {
+            (Some(_), true) => {
+                self.warn("area lights of object instances aren't supported".to_string());
+                self.materials[attributes.material].1.clone()
+            }
+            (Some((light, radiance)), false) => {
+                let key = (attributes.material, light);
+                match self.emissive_materials.get(&key) {
+                    Some(material) => material.clone(),
+                    None => {
+                        let mut material = self.materials[attributes.material].0.clone();
+                        material.emissive = LinearRgba::rgb(radiance.x, radiance.y, radiance.z);
+                        let index = self.add_material(material, load_context);
+                        let material = self.materials[index].1.clone();
+                        self.emissive_materials.insert(key, material.clone());
+                        material
+                    }
+                }
+            }
+            (None, _) => self.materials[attributes.material].1.clone(),
+        };
+        let bundle = PbrBundle {
+            mesh,
+            material,
+            ..default()
+        };
+        match &mut self.object {
+            Some((_, shapes)) => shapes.push(bundle),
+            None => {
+                self.world.spawn(bundle);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, load_context: &mut LoadContext) -> Scene {
+        // pbrt's field of view spans the shorter side of the image
+        let aspect_ratio = self
+            .frame_aspect_ratio
+            .unwrap_or(self.resolution.x / self.resolution.y);
+        let tan = (self.fov.to_radians() / 2.0).tan();
+        let fov = 2.0 * (tan / aspect_ratio.min(1.0)).atan();
+        let (image, intensity) = self.environment.take().unwrap_or_else(|| {
+            let black = equirectangular(UVec2::new(2, 1), |_| Vec3::ZERO);
+            let image = load_context.add_labeled_asset("Environment".to_string(), black);
+            (image, 1.0)
+        });
+        self.world.spawn((
+            Camera3dBundle {
+                projection: PerspectiveProjection { fov, ..default() }.into(),
+                transform: Transform::from_matrix(
+                    mirror() * self.camera_from_world.inverse() * mirror(),
+                ),
+                ..default()
+            },
+            EquirectangularEnvironment { image, intensity },
+        ));
+        for warning in &self.warnings {
+            warn!("{}: {warning}", self.path);
+        }
+        Scene::new(self.world)
+    }
+}
+
+/// Converts between pbrt's left-handed and bevy's right-handed space
+fn mirror() -> Mat4 {
+    Mat4::from_scale(Vec3::new(1.0, 1.0, -1.0))
+}
+
+/// Scale from pbrt's radiance to cd/m², the inverse of bevy's default exposure
+fn radiance_scale() -> f32 {
+    1.0 / Exposure::default().exposure()
+}
+
+/// Asset path of a file referenced from the scene, relative to its directory
+fn resolve(path: &AssetPath, reference: &str) -> Result<AssetPath<'static>, String> {
+    path.resolve_embed(reference)
+        .map_err(|error| error.to_string())
+}
+
+/// Perceptual roughness of pbrt's `roughness` or `uroughness` and `vroughness`
+fn roughness(params: &mut Params) -> f32 {
+    let roughness = if params.get("roughness").is_some() {
+        params.float("roughness", 0.0)
+    } else {
+        (params.float("uroughness", 0.0) + params.float("vroughness", 0.0)) / 2.0
+    };
+    let alpha = if params.bool("remaproughness", true) {
+        roughness.max(0.0).sqrt()
+    } else {
+        roughness
+    };
+    alpha.max(0.0).sqrt()
+}
+
+/// Reflectance at normal incidence of pbrt's named metals, from `metal-<name>-eta` or `-k`
+fn metal_reflectance(spectrum: &str) -> Option<Vec3> {
+    let metal = spectrum
+        .strip_prefix("metal-")?
+        .trim_end_matches("-eta")
+        .trim_end_matches("-k");
+    Some(match metal {
+        "Ag" => Vec3::new(0.972, 0.960, 0.915),
+        "Al" => Vec3::new(0.913, 0.922, 0.924),
+        "Au" => Vec3::new(1.0, 0.782, 0.344),
+        "Cu" => Vec3::new(0.955, 0.638, 0.538),
+        "CuZn" => Vec3::new(0.910, 0.778, 0.423),
+        _ => return None,
+    })
+}
+
+/// Index of refraction of pbrt's named glasses at 550nm
+fn glass_ior(spectrum: &str) -> Option<f32> {
+    Some(match spectrum {
+        "glass-BK7" => 1.5168,
+        "glass-BAF10" => 1.6700,
+        "glass-FK51A" => 1.4866,
+        "glass-LASF9" => 1.8503,
+        "glass-F5" => 1.6034,
+        "glass-F10" => 1.7283,
+        "glass-F11" => 1.7847,
+        _ => return None,
+    })
+}
+
+/// Linear sRGB color of a blackbody with unit luminance, like pbrt normalizes them
+fn blackbody(temperature: f32) -> Vec3 {
+    // Wyman et al.'s fit of the CIE 1931 color matching functions
+    let lobe = |lambda: f32, mu: f32, below: f32, above: f32| {
+        let sigma = if lambda < mu { below } else { above };
+        (-0.5 * ((lambda - mu) / sigma).powi(2)).exp()
+    };
+    let mut xyz = Vec3::ZERO;
+    for lambda in (360..=830).step_by(5) {
+        let lambda = lambda as f32;
+        let meters = lambda * 1e-9;
+        let radiance = 1.0 / (meters.powi(5) * ((1.4388e-2 / (meters * temperature)).exp() - 1.0));
+        xyz += radiance
+            * Vec3::new(
+                1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+                    - 0.065 * lobe(lambda, 501.1, 20.4, 26.2),
+                0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1),
+                1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8),
+            );
+    }
+    let xyz = xyz / xyz.y;
+    Vec3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    )
+    .max(Vec3::ZERO)
+}
+
+/// pbrt's sphere around the z axis, possibly cut by `zmin`, `zmax` and `phimax`
+fn sphere(params: &mut Params) -> ply::PlyMesh {
+    let radius = params.float("radius", 1.0);
+    let z_min = params.float("zmin", -radius).clamp(-radius, radius);
+    let z_max = params.float("zmax", radius).clamp(-radius, radius);
+    let phi_max = params.float("phimax", 360.0).clamp(0.0, 360.0).to_radians();
+    let theta_min = (z_min.min(z_max) / radius).clamp(-1.0, 1.0).acos();
+    let theta_max = (z_min.max(z_max) / radius).clamp(-1.0, 1.0).acos();
+    let segments = 2 * SPHERE_RINGS;
+    let mut mesh = ply::PlyMesh::default();
+    let (normals, uvs) = (mesh.normals.insert(Vec::new()), mesh.uvs.insert(Vec::new()));
+    for ring in 0..=SPHERE_RINGS {
+        let v = ring as f32 / SPHERE_RINGS as f32;
+        let theta = theta_min + v * (theta_max - theta_min);
+        for segment in 0..=segments {
+            let u = segment as f32 / segments as f32;
+            let phi = u * phi_max;
+            let normal = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                theta.cos(),
+            );
+            mesh.positions.push(normal * radius);
+            normals.push(normal);
+            uvs.push(Vec2::new(u, v));
+        }
+    }
+    for ring in 0..SPHERE_RINGS {
+        for segment in 0..segments {
+            let a = ring * (segments + 1) + segment;
+            let b = a + segments + 1;
+            // counterclockwise seen from outside
+            mesh.indices.extend([a, b, a + 1, a + 1, b, b + 1]);
+        }
+    }
+    mesh
+}
+
+/// Bakes pbrt's object to world transform into a mesh in bevy's space
+///
+/// Triangles face the side pbrt's normals do, flipping them when the transform changes
+/// handedness or the orientation is reversed.
+fn mesh(shape: ply::PlyMesh, transform: Mat4, reverse_orientation: bool) -> Mesh {
+    let world_from_object = mirror() * transform;
+    let normal_from_object = Mat3::from_mat4(world_from_object).inverse().transpose();
+    let flip = (world_from_object.determinant() < 0.0) != reverse_orientation;
+    let positions: Vec<[f32; 3]> = shape
+        .positions
+        .iter()
+        .map(|&position| world_from_object.transform_point3(position).to_array())
+        .collect();
+    let mut indices = shape.indices;
+    if flip {
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_indices(Indices::U32(indices));
+    if let Some(uvs) = shape.uvs {
+        // pbrt puts the origin of textures at the bottom
+        let uvs: Vec<[f32; 2]> = uvs.iter().map(|uv| [uv.x, 1.0 - uv.y]).collect();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    }
+    match shape.normals {
+        Some(normals) => {
+            let normals: Vec<[f32; 3]> = normals
+                .iter()
+                .map(|&normal| (normal_from_object * normal).normalize_or_zero().to_array())
+                .collect();
+            mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        }
+        None => {
+            // triangles without normals are shaded flat, the ray tracer needs indices though
+            mesh.duplicate_vertices();
+            mesh.compute_flat_normals();
+            let count = mesh.count_vertices() as u32;
+            mesh.insert_indices(Indices::U32((0..count).collect()));
+        }
+    }
+    mesh
+}
+
+/// An equirectangular image of the radiance towards every direction
+fn equirectangular(size: UVec2, radiance: impl Fn(Vec3) -> Vec3) -> Image {
+    let mut data = Vec::with_capacity((size.x * size.y * 16) as usize);
+    for y in 0..size.y {
+        let theta = (y as f32 + 0.5) / size.y as f32 * PI;
+        for x in 0..size.x {
+            let phi = ((x as f32 + 0.5) / size.x as f32 - 0.5) * 2.0 * PI;
+            let direction = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let color = radiance(direction).extend(1.0);
+            data.extend(color.to_array().iter().flat_map(|c| c.to_le_bytes()));
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba32Float,
+        RenderAssetUsages::default(),
+    )
+}
+
+/// Where a direction is in pbrt-v4's equal-area octahedral environment maps
+fn equal_area_sphere_to_square(direction: Vec3) -> Vec2 {
+    let direction = direction.normalize_or_zero();
+    let abs = direction.abs();
+    let r = (1.0 - abs.z).max(0.0).sqrt();
+    let a = abs.x.max(abs.y);
+    let b = if a == 0.0 { 0.0 } else { abs.x.min(abs.y) / a };
+    let mut phi = b.atan() / FRAC_PI_2;
+    if abs.x < abs.y {
+        phi = 1.0 - phi;
+    }
+    let mut v = phi * r;
+    let mut u = r - v;
+    if direction.z < 0.0 {
+        (u, v) = (1.0 - v, 1.0 - u);
+    }
+    Vec2::new(
+        0.5 * (u.copysign(direction.x) + 1.0),
+        0.5 * (v.copysign(direction.y) + 1.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn importer(text: &str) -> Importer {
+        let mut importer = Importer::new(AssetPath::from("scene.pbrt"));
+        importer.include(text.as_bytes()).unwrap();
+        importer
+    }
+
+    /// Runs a file of transform and attribute directives
+    fn run(text: &str) -> Result<Importer, String> {
+        let mut importer = importer(text);
+        while let Some(token) = importer.next() {
+            let Token::Word(directive) = token else {
+                return Err(format!("expected a directive, got {token:?}"));
+            };
+            assert!(importer.state_directive(&directive)?, "{directive}");
+        }
+        Ok(importer)
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize(
+            "Shape \"sphere\" # a comment\n\
+             \"float radius\"[2]\n\
+             \"string name\" \"a \\\"b\\\"\"",
+        )
+        .unwrap();
+        let word = |word: &str| Token::Word(word.to_string());
+        let string = |string: &str| Token::Str(string.to_string());
+        assert_eq!(
+            tokens,
+            [
+                (word("Shape"), 1),
+                (string("sphere"), 1),
+                (string("float radius"), 2),
+                (Token::Open, 2),
+                (word("2"), 2),
+                (Token::Close, 2),
+                (string("string name"), 3),
+                (string("a \"b\""), 3),
+            ]
+        );
+        assert!(tokenize("Shape \"sphere\nWorldEnd").is_err());
+    }
+
+    #[test]
+    fn params() {
+        let mut importer = importer(
+            "\"rgb reflectance\" [ 0.5 0.25 1 ] \"string filename\" \"mesh.ply\" \
+             \"bool twosided\" true \"float roughness\" 0.1 Shape",
+        );
+        let mut params = importer.params("Material \"diffuse\"".to_string()).unwrap();
+        assert_eq!(importer.next(), Some(Token::Word("Shape".to_string())));
+        assert_eq!(
+            params.vec3("reflectance", Vec3::ZERO),
+            Vec3::new(0.5, 0.25, 1.0)
+        );
+        assert_eq!(params.string("filename").as_deref(), Some("mesh.ply"));
+        assert!(params.bool("twosided", false));
+        assert_eq!(params.float("eta", 1.5), 1.5);
+        let unused: Vec<_> = params.list.iter().filter(|param| !param.used).collect();
+        assert_eq!(unused.len(), 1);
+        assert_eq!(unused[0].name, "roughness");
+    }
+
+    #[test]
+    fn transform_is_column_major() {
+        let importer = run("Transform [ 1 0 0 0  0 1 0 0  0 0 1 0  2 3 4 1 ]").unwrap();
+        assert_eq!(
+            importer.attributes.transform.transform_point3(Vec3::ZERO),
+            Vec3::new(2.0, 3.0, 4.0)
+        );
+        // later transforms apply to objects first
+        let importer = run("Translate 1 0 0 Scale 2 2 2").unwrap();
+        assert_eq!(
+            importer.attributes.transform.transform_point3(Vec3::X),
+            Vec3::new(3.0, 0.0, 0.0)
+        );
+        let importer =
+            run("Translate 1 0 0 ConcatTransform [ 2 0 0 0 0 2 0 0 0 0 2 0 0 0 0 1 ]").unwrap();
+        assert_eq!(
+            importer.attributes.transform.transform_point3(Vec3::X),
+            Vec3::new(3.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn attribute_stack() {
+        let importer = run("Translate 1 0 0\n\
+             AttributeBegin\n\
+               Translate 0 1 0\n\
+               ReverseOrientation\n\
+               CoordinateSystem \"inner\"\n\
+               TransformBegin\n\
+                 Scale 2 2 2\n\
+               TransformEnd\n\
+             AttributeEnd\n")
+        .unwrap();
+        assert_eq!(
+            importer.attributes.transform,
+            Mat4::from_translation(Vec3::X)
+        );
+        assert!(!importer.attributes.reverse_orientation);
+        assert!(importer.attribute_stack.is_empty());
+        // named coordinate systems outlive the attributes they were declared in
+        assert_eq!(
+            importer.coordinate_systems["inner"],
+            Mat4::from_translation(Vec3::new(1.0, 1.0, 0.0))
+        );
+
+        let importer =
+            run("AttributeBegin ReverseOrientation TransformBegin Translate 1 0 0 TransformEnd")
+                .unwrap();
+        assert_eq!(importer.attributes.transform, Mat4::IDENTITY);
+        assert!(importer.attributes.reverse_orientation);
+        assert_eq!(importer.attribute_stack.len(), 1);
+
+        assert!(run("AttributeBegin AttributeEnd AttributeEnd").is_err());
+        assert!(run("TransformEnd").is_err());
+    }
+}
@@ -1,25 +1,43 @@
 use bevy::{
-    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state, prepass::PreviousViewData,
+    },
     prelude::*,
     render::{
         globals::GlobalsUniform,
         render_resource::{
-            binding_types::{texture_2d, uniform_buffer},
-            AsBindGroup, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
-            ColorTargetState, ColorWrites, FragmentState, MultisampleState, PipelineCache,
-            PrimitiveState, RenderPipelineDescriptor, ShaderStages, TextureFormat,
+            binding_types::{
+                storage_buffer, storage_buffer_read_only, texture_2d, texture_cube,
+                texture_depth_2d, uniform_buffer,
+            },
+            BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, ComputePipelineDescriptor,
+            FragmentState, MultisampleState, PipelineCache, PrimitiveState,
+            RenderPipelineDescriptor, ShaderStages, TextureFormat,
         },
         renderer::RenderDevice,
-        view::ViewUniform,
+        texture::BevyDefault,
+        view::{ViewTarget, ViewUniform},
     },
 };
 
-use crate::ray_tracing::RayTracingInfo;
+use crate::{
+    accumulation::{ACCUMULATION_FORMAT, ALBEDO_FORMAT, GEOMETRY_FORMAT, RADIANCE_FORMAT},
+    environment::GpuEnvironment,
+    ray_tracing::RayTracingInfo,
+    restir::{GiReservoir, Reservoir},
+};
 
 #[derive(Resource)]
 pub struct RayTracingPipeline {
     pub layout: BindGroupLayout,
-    pub pipeline_id: CachedRenderPipelineId,
+    pub info_layout: BindGroupLayout,
+    /// Renders to the main texture of LDR and HDR views
+    pub pipeline_ids: [CachedRenderPipelineId; 2],
+    /// Draws the ReSTIR candidates before the main pass
+    pub restir_pipeline_id: CachedComputePipelineId,
+    pub restir_gi_candidates_pipeline_id: CachedComputePipelineId,
+    pub restir_gi_resample_pipeline_id: CachedComputePipelineId,
 }
 
 impl FromWorld for RayTracingPipeline {
@@ -32,41 +50,111 @@ impl FromWorld for RayTracingPipeline {
         let global_layout = render_device.create_bind_group_layout(
             "gloabl_bind_group_layout",
             &BindGroupLayoutEntries::sequential(
-                ShaderStages::FRAGMENT,
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
                 (
                     uniform_buffer::<ViewUniform>(true),
                     uniform_buffer::<GlobalsUniform>(false),
                     texture_2d(bevy::render::render_resource::TextureSampleType::Float {
                         filterable: true,
                     }),
+                    texture_2d(bevy::render::render_resource::TextureSampleType::Float {
+                        filterable: false,
+                    }),
+                    texture_2d(bevy::render::render_resource::TextureSampleType::Float {
+                        filterable: false,
+                    }),
+                    texture_cube(bevy::render::render_resource::TextureSampleType::Float {
+                        filterable: true,
+                    }),
+                    uniform_buffer::<GpuEnvironment>(false),
+                    storage_buffer_read_only::<f32>(false),
+                    storage_buffer_read_only::<Reservoir>(false),
+                    storage_buffer::<Reservoir>(false),
+                    storage_buffer_read_only::<GiReservoir>(false),
+                    storage_buffer::<GiReservoir>(false),
+                    texture_depth_2d(),
+                    texture_2d(bevy::render::render_resource::TextureSampleType::Float {
+                        filterable: true,
+                    }),
+                    texture_2d(bevy::render::render_resource::TextureSampleType::Float {
+                        filterable: false,
+                    }),
+                    uniform_buffer::<PreviousViewData>(true),
                 ),
             ),
         );
-        let pipeline_id =
-            world
-                .resource_mut::<PipelineCache>()
-                .queue_render_pipeline(RenderPipelineDescriptor {
-                    label: Some("ray_tracing_pipeline".into()),
+        let pipeline_cache = world.resource_mut::<PipelineCache>();
+        let [restir_pipeline_id, restir_gi_candidates_pipeline_id, restir_gi_resample_pipeline_id] =
+            [
+                "restir_candidates",
+                "restir_gi_candidates",
+                "restir_gi_resample",
+            ]
+            .map(|entry_point| {
+                pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some(format!("ray_tracing_{entry_point}_pipeline").into()),
                     layout: vec![global_layout.clone(), layout.clone()],
-                    vertex: fullscreen_shader_vertex_state(),
-                    fragment: Some(FragmentState {
-                        shader,
-                        shader_defs: vec![],
-                        entry_point: "fragment".into(),
-                        targets: vec![Some(ColorTargetState {
-                            format: TextureFormat::Bgra8UnormSrgb,
+                    push_constant_ranges: vec![],
+                    shader: shader.clone(),
+                    shader_defs: vec![],
+                    entry_point: entry_point.into(),
+                })
+            });
+        // the view's main texture is rgba8 unless the camera is hdr
+        let pipeline_ids = [
+            TextureFormat::bevy_default(),
+            ViewTarget::TEXTURE_FORMAT_HDR,
+        ]
+        .map(|format| {
+            pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+                label: Some("ray_tracing_pipeline".into()),
+                layout: vec![global_layout.clone(), layout.clone()],
+                vertex: fullscreen_shader_vertex_state(),
+                fragment: Some(FragmentState {
+                    shader: shader.clone(),
+                    shader_defs: vec![],
+                    entry_point: "fragment".into(),
+                    targets: vec![
+                        Some(ColorTargetState {
+                            format,
                             blend: None,
                             write_mask: ColorWrites::ALL,
-                        })],
-                    }),
-                    push_constant_ranges: vec![],
-                    primitive: PrimitiveState::default(),
-                    depth_stencil: None,
-                    multisample: MultisampleState::default(),
-                });
+                        }),
+                        Some(ColorTargetState {
+                            format: ACCUMULATION_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                        Some(ColorTargetState {
+                            format: GEOMETRY_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                        Some(ColorTargetState {
+                            format: RADIANCE_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                        Some(ColorTargetState {
+                            format: ALBEDO_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        }),
+                    ],
+                }),
+                push_constant_ranges: vec![],
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+            })
+        });
         Self {
             layout: global_layout,
-            pipeline_id,
+            info_layout: layout,
+            pipeline_ids,
+            restir_pipeline_id,
+            restir_gi_candidates_pipeline_id,
+            restir_gi_resample_pipeline_id,
         }
     }
 }
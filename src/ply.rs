@@ -0,0 +1,364 @@
+use bevy::prelude::*;
+
+/// Vertices and triangles of a PLY file, polygons are split into fans
+#[derive(Default, Debug)]
+pub struct PlyMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<Vec2>>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(format!("unknown property type {name}")),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    /// Name, type of the length and type of the items
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Where the values of the body are read from
+struct Body<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Body<'_> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let start = self.position
+                + self.bytes[self.position..]
+                    .iter()
+                    .take_while(|byte| byte.is_ascii_whitespace())
+                    .count();
+            let end = start
+                + self.bytes[start..]
+                    .iter()
+                    .take_while(|byte| !byte.is_ascii_whitespace())
+                    .count();
+            self.position = end;
+            return std::str::from_utf8(&self.bytes[start..end])
+                .ok()
+                .and_then(|token| token.parse().ok())
+                .ok_or_else(|| "bad value".to_string());
+        }
+        let size = scalar.size();
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(
+            self.bytes
+                .get(self.position..self.position + size)
+                .ok_or("unexpected end of file")?,
+        );
+        self.position += size;
+        if self.format == Format::BigEndian {
+            bytes[..size].reverse();
+        }
+        let [a, b, c, d, ..] = bytes;
+        Ok(match scalar {
+            Scalar::I8 => a as i8 as f64,
+            Scalar::U8 => a as f64,
+            Scalar::I16 => i16::from_le_bytes([a, b]) as f64,
+            Scalar::U16 => u16::from_le_bytes([a, b]) as f64,
+            Scalar::I32 => i32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::U32 => u32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::F32 => f32::from_le_bytes([a, b, c, d]) as f64,
+            Scalar::F64 => f64::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// Reads the `vertex` and `face` elements, with positions `x y z`, normals `nx ny nz` and
+/// texture coordinates `u v`, `s t` or `texture_u texture_v`
+pub fn parse(bytes: &[u8]) -> Result<PlyMesh, String> {
+    let header_end = bytes
+        .windows(11)
+        .position(|window| window == b"end_header\n" || window == b"end_header\r")
+        .ok_or("missing end_header")?;
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+    let mut lines = header.lines().map(str::split_whitespace);
+    if lines.next().and_then(|mut line| line.next()) != Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for mut line in lines {
+        match line.next() {
+            Some("format") => {
+                format = Some(match line.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::LittleEndian,
+                    Some("binary_big_endian") => Format::BigEndian,
+                    format => return Err(format!("unknown format {format:?}")),
+                });
+            }
+            Some("element") => {
+                let name = line.next().ok_or("missing element name")?;
+                let count = line
+                    .next()
+                    .and_then(|count| count.parse().ok())
+                    .ok_or("bad element count")?;
+                elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let element = elements.last_mut().ok_or("property before element")?;
+                let words: Vec<_> = line.collect();
+                element.properties.push(match words[..] {
+                    ["list", length, item, name] => Property::List(
+                        name.to_string(),
+                        Scalar::parse(length)?,
+                        Scalar::parse(item)?,
+                    ),
+                    [scalar, name] => Property::Scalar(name.to_string(), Scalar::parse(scalar)?),
+                    _ => return Err("bad property".to_string()),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut body = Body {
+        format: format.ok_or("missing format")?,
+        bytes,
+        position: header_end + "end_header".len(),
+    };
+    // the header ends with a single line break, which may be \r\n
+    if bytes.get(body.position) == Some(&b'\r') {
+        body.position += 1;
+    }
+    body.position += 1;
+
+    let mut mesh = PlyMesh::default();
+    for element in &elements {
+        let names: Vec<&str> = element
+            .properties
+            .iter()
+            .map(|property| match property {
+                Property::Scalar(name, _) | Property::List(name, _, _) => name.as_str(),
+            })
+            .collect();
+        let find = |candidates: &[&str]| {
+            candidates
+                .iter()
+                .find_map(|name| names.iter().position(|property| property == name))
+        };
+        let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+        let uv = [
+            find(&["u", "s", "texture_u", "texture_s"]),
+            find(&["v", "t", "texture_v", "texture_t"]),
+        ];
+        let has_normals = normal.iter().all(Option::is_some);
+        let has_uvs = uv.iter().all(Option::is_some);
+        let vertex_indices = find(&["vertex_indices", "vertex_index"]);
+        if element.name == "vertex" {
+            if has_normals {
+                mesh.normals = Some(Vec::with_capacity(element.count));
+            }
+            if has_uvs {
+                mesh.uvs = Some(Vec::with_capacity(element.count));
+            }
+        }
+
+        let mut scalars = vec![0.0; element.properties.len()];
+        let mut list = Vec::new();
+        for _ in 0..element.count {
+            for (index, property) in element.properties.iter().enumerate() {
+                match *property {
+                    Property::Scalar(_, scalar) => scalars[index] = body.read(scalar)? as f32,
+                    Property::List(_, length, item) => {
+                        let length = body.read(length)? as usize;
+                        let items = (0..length)
+                            .map(|_| body.read(item).map(|value| value as u32))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        if Some(index) == vertex_indices {
+                            list = items;
+                        }
+                    }
+                }
+            }
+            let get = |indices: &[Option<usize>]| -> Vec<f32> {
+                indices
+                    .iter()
+                    .map(|index| index.map_or(0.0, |index| scalars[index]))
+                    .collect()
+            };
+            match element.name.as_str() {
+                "vertex" => {
+                    mesh.positions.push(Vec3::from_slice(&get(&position)));
+                    if let Some(normals) = &mut mesh.normals {
+                        normals.push(Vec3::from_slice(&get(&normal)));
+                    }
+                    if let Some(uvs) = &mut mesh.uvs {
+                        uvs.push(Vec2::from_slice(&get(&uv)));
+                    }
+                }
+                "face" if list.len() >= 3 => {
+                    for i in 1..list.len() - 1 {
+                        mesh.indices.extend([list[0], list[i], list[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if mesh
+        .indices
+        .iter()
+        .any(|&index| index as usize >= mesh.positions.len())
+    {
+        return Err("face index out of range".to_string());
+    }
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: [Vec3; 4] = [
+        Vec3::ZERO,
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+    ];
+
+    fn header(format: &str, vertex_properties: &str) -> String {
+        format!(
+            "ply\nformat {format} 1.0\ncomment a square\nelement vertex 4\n{vertex_properties}\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n"
+        )
+    }
+
+    #[test]
+    fn ascii() {
+        let text = header(
+            "ascii",
+            "property float x\nproperty float y\nproperty float z\n\
+             property float s\nproperty float t\n",
+        ) + "0 0 0 0 0\n1 0 0 1 0\n1 1 0 1 1\n0 1 0 0 1\n4 0 1 2 3\n";
+        let mesh = parse(text.as_bytes()).unwrap();
+        assert_eq!(mesh.positions, SQUARE);
+        assert_eq!(mesh.normals, None);
+        assert_eq!(
+            mesh.uvs.unwrap(),
+            SQUARE.map(|position| position.truncate())
+        );
+        // quads are split into fans
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+
+        let crlf = parse(text.replace('\n', "\r\n").as_bytes()).unwrap();
+        assert_eq!(crlf.positions, SQUARE);
+        assert_eq!(crlf.indices, mesh.indices);
+    }
+
+    #[test]
+    fn binary() {
+        let properties = "property double x\nproperty double y\nproperty double z\n\
+                          property short nx\nproperty short ny\nproperty short nz\n";
+        let body = |big_endian: bool| {
+            let mut bytes = Vec::new();
+            for position in SQUARE {
+                for value in position.to_array() {
+                    let value = value as f64;
+                    bytes.extend(if big_endian {
+                        value.to_be_bytes()
+                    } else {
+                        value.to_le_bytes()
+                    });
+                }
+                for value in [0i16, 0, -1] {
+                    bytes.extend(if big_endian {
+                        value.to_be_bytes()
+                    } else {
+                        value.to_le_bytes()
+                    });
+                }
+            }
+            bytes.push(4);
+            for index in [0i32, 1, 2, 3] {
+                bytes.extend(if big_endian {
+                    index.to_be_bytes()
+                } else {
+                    index.to_le_bytes()
+                });
+            }
+            bytes
+        };
+        for (format, big_endian) in [("binary_little_endian", false), ("binary_big_endian", true)] {
+            let mut bytes = header(format, properties).into_bytes();
+            bytes.extend(body(big_endian));
+            let mesh = parse(&bytes).unwrap();
+            assert_eq!(mesh.positions, SQUARE, "{format}");
+            assert_eq!(mesh.normals.unwrap(), [Vec3::NEG_Z; 4], "{format}");
+            assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3], "{format}");
+
+            bytes.pop();
+            assert!(parse(&bytes).is_err(), "{format} truncated");
+        }
+    }
+
+    #[test]
+    fn bad_files() {
+        let properties = "property float x\nproperty float y\nproperty float z\n";
+        let vertices = "0 0 0\n1 0 0\n1 1 0\n0 1 0\n";
+        let out_of_range = header("ascii", properties) + vertices + "3 0 1 4\n";
+        assert!(parse(out_of_range.as_bytes()).is_err());
+        let no_format = header("ascii", properties).replace("format ascii 1.0\n", "");
+        assert!(parse((no_format + vertices + "3 0 1 2\n").as_bytes()).is_err());
+        assert!(parse(b"ply\nformat ascii 1.0\n").is_err());
+        assert!(parse(b"obj\nend_header\n").is_err());
+    }
+}
@@ -1,25 +1,79 @@
 use bevy::{
-    core_pipeline::prepass::node::PrepassNode,
-    math::bounding::Bounded3d,
+    app::SpawnScene,
+    core_pipeline::{
+        contrast_adaptive_sharpening::CASNode,
+        core_3d::graph::{Core3d, Node3d},
+        fxaa::FxaaNode,
+        prepass::{
+            node::PrepassNode, DepthPrepass, MotionVectorPrepass, NormalPrepass,
+            PreviousViewUniforms, ViewPrepassTextures,
+        },
+        smaa::SmaaNode,
+        tonemapping::TonemappingNode,
+        upscaling::UpscalingNode,
+    },
+    ecs::entity::EntityHashMap,
     prelude::*,
     render::{
-        extract_resource::ExtractResource,
-        primitives::Aabb,
-        render_graph::{RenderGraphApp, RenderLabel, RenderSubGraph, ViewNodeRunner},
-        render_resource::{AsBindGroup, ShaderType},
-        Extract, RenderApp,
+        camera::{CameraRenderGraph, ExtractedCamera},
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        globals::GlobalsBuffer,
+        render_graph::{
+            EmptyNode, RenderGraph, RenderGraphApp, RenderLabel, RenderSubGraph,
+            RunGraphOnViewNode, ViewNodeRunner,
+        },
+        render_resource::{
+            binding_types::{sampler, storage_buffer_read_only, texture_2d_array, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BufferId,
+            SamplerBindingType, ShaderStages, ShaderType, TextureSampleType, TextureView,
+            TextureViewId, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        view::ViewUniforms,
+        Render, RenderApp, RenderSet,
+    },
+    scene::scene_spawner_system,
+    ui::{
+        graph::{NodeUi, SubGraphUi},
+        UiPassNode,
     },
 };
-use itertools::Itertools;
 
-use crate::{node::RayTracingPassNode, pipeline::RayTracingPipeline};
+use crate::{
+    accumulation::{prepare_accumulation, AccumulationCache},
+    buffer::GpuArray,
+    bvh::{BvhNode, BvhSettings},
+    environment::{
+        extract_environments, prepare_environments, EnvironmentCache, EquirectangularEnvironment,
+        ViewEnvironment,
+    },
+    extract::{collect_removed_instances, prepare_meshinfo, RemovedInstances, SceneCache},
+    gltf::{setup_gltf_scenes, RayTracedGltf},
+    light::{extract_lights, EmissiveTriangle, GpuLight, GpuLightInfo},
+    node::{RayTracingPassNode, RestirGiNode, SharedNode},
+    pipeline::RayTracingPipeline,
+    restir::{prepare_reservoirs, GpuRestirGiSettings, ReservoirCache, RestirGiSettings},
+    sampler::{blue_noise, sobol_directions, SamplerKind},
+    sky::PhysicalSky,
+    svgf::{
+        prepare_svgf, prepare_svgf_bind_groups, SvgfBindGroupCache, SvgfBuffers, SvgfCache,
+        SvgfNode, SvgfPipeline, SvgfSettings,
+    },
+    texture::{TextureArray, NO_TEXTURE},
+};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 struct PrepassLabel;
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct RestirGiLabel;
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 struct RayTracingLabel;
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct SvgfLabel;
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderSubGraph)]
 pub struct RayTracingGraph;
 
@@ -28,142 +82,688 @@ pub struct RayTracingPlugin;
 impl Plugin for RayTracingPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Msaa::Off)
-            .insert_resource(RayTracingInfo::default());
+            .init_resource::<BvhSettings>()
+            .init_resource::<RayTracingSettings>()
+            .init_resource::<RestirGiSettings>()
+            .init_resource::<SvgfSettings>()
+            .init_resource::<RemovedInstances>()
+            .register_type::<BvhSettings>()
+            .register_type::<RayTracingSettings>()
+            .register_type::<RestirGiSettings>()
+            .register_type::<SvgfSettings>()
+            .register_type::<EquirectangularEnvironment>()
+            .register_type::<PhysicalSky>()
+            .register_type::<RayTracedGltf>()
+            .add_plugins((
+                ExtractResourcePlugin::<RayTracingSettings>::default(),
+                ExtractResourcePlugin::<RestirGiSettings>::default(),
+                ExtractResourcePlugin::<SvgfSettings>::default(),
+            ))
+            .add_systems(SpawnScene, setup_gltf_scenes.after(scene_spawner_system))
+            .add_systems(PostUpdate, add_prepasses)
+            .add_systems(Last, collect_removed_instances);
         if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app
-                .add_systems(ExtractSchedule, prepare_meshinfo)
+                .init_resource::<SceneCache>()
+                .init_resource::<RayTracingInfo>()
+                .add_systems(
+                    ExtractSchedule,
+                    (prepare_meshinfo, extract_lights, extract_environments),
+                )
+                .init_resource::<ViewBindGroupCache>()
+                .init_resource::<AccumulationCache>()
+                .init_resource::<EnvironmentCache>()
+                .init_resource::<ReservoirCache>()
+                .init_resource::<SvgfBuffers>()
+                .init_resource::<SvgfCache>()
+                .init_resource::<SvgfBindGroupCache>()
+                .add_systems(
+                    Render,
+                    (
+                        (
+                            prepare_accumulation,
+                            prepare_environments,
+                            prepare_buffers,
+                            prepare_svgf,
+                        )
+                            .chain()
+                            .in_set(RenderSet::PrepareResources),
+                        prepare_reservoirs.in_set(RenderSet::PrepareResources),
+                        (
+                            prepare_info_bind_group,
+                            prepare_view_bind_groups,
+                            prepare_svgf_bind_groups,
+                        )
+                            .in_set(RenderSet::PrepareBindGroups),
+                    ),
+                )
                 .add_render_sub_graph(RayTracingGraph)
                 .add_render_graph_node::<ViewNodeRunner<PrepassNode>>(RayTracingGraph, PrepassLabel)
+                .add_render_graph_node::<ViewNodeRunner<RestirGiNode>>(
+                    RayTracingGraph,
+                    RestirGiLabel,
+                )
                 .add_render_graph_node::<ViewNodeRunner<RayTracingPassNode>>(
                     RayTracingGraph,
                     RayTracingLabel,
+                )
+                .add_render_graph_node::<ViewNodeRunner<SvgfNode>>(RayTracingGraph, SvgfLabel)
+                // ReSTIR and accumulation reproject their history with this frame's prepass
+                .add_render_graph_edges(
+                    RayTracingGraph,
+                    (PrepassLabel, RestirGiLabel, RayTracingLabel, SvgfLabel),
                 );
         }
     }
 
     fn finish(&self, app: &mut App) {
-        app.get_sub_app_mut(RenderApp)
-            .unwrap()
-            .init_resource::<RayTracingPipeline>();
+        let render_app = app.get_sub_app_mut(RenderApp).unwrap();
+        render_app
+            .init_resource::<RayTracingPipeline>()
+            .init_resource::<SvgfPipeline>();
+
+        // post processing runs on the main texture the ray tracer rendered to, in the same order
+        // as in Core3d, until upscaling writes it to the view's output
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<TonemappingNode>>(
+                RayTracingGraph,
+                Node3d::Tonemapping,
+            )
+            .add_render_graph_node::<ViewNodeRunner<FxaaNode>>(RayTracingGraph, Node3d::Fxaa)
+            .add_render_graph_node::<ViewNodeRunner<SmaaNode>>(RayTracingGraph, Node3d::Smaa)
+            .add_render_graph_node::<CASNode>(RayTracingGraph, Node3d::ContrastAdaptiveSharpening)
+            .add_render_graph_node::<EmptyNode>(RayTracingGraph, Node3d::EndMainPassPostProcessing)
+            .add_render_graph_node::<ViewNodeRunner<UpscalingNode>>(
+                RayTracingGraph,
+                Node3d::Upscaling,
+            )
+            .add_render_graph_edges(
+                RayTracingGraph,
+                (
+                    SvgfLabel,
+                    Node3d::Tonemapping,
+                    Node3d::Fxaa,
+                    Node3d::Smaa,
+                    Node3d::ContrastAdaptiveSharpening,
+                    Node3d::EndMainPassPostProcessing,
+                    Node3d::Upscaling,
+                ),
+            );
+        let ui_pass = UiPassNode::new(render_app.world_mut());
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        let Some(core_3d) = render_graph.get_sub_graph_mut(Core3d) else {
+            return;
+        };
+        // bevy doesn't export its bloom node, Core3d shares it
+        let bloom = SharedNode::share(core_3d, Node3d::Bloom);
+        let has_ui = core_3d.get_node_state(NodeUi::UiPass).is_ok();
+        let graph = render_graph.sub_graph_mut(RayTracingGraph);
+        if let Some(bloom) = bloom {
+            graph.add_node(Node3d::Bloom, bloom);
+            graph.add_node_edges((SvgfLabel, Node3d::Bloom, Node3d::Tonemapping));
+        }
+        // ui is drawn over the post processed image, before upscaling like in Core3d
+        if has_ui {
+            let mut ui_graph = RenderGraph::default();
+            ui_graph.add_node(NodeUi::UiPass, ui_pass);
+            graph.add_sub_graph(SubGraphUi, ui_graph);
+            graph.add_node(NodeUi::UiPass, RunGraphOnViewNode::new(SubGraphUi));
+            graph.add_node_edges((
+                Node3d::EndMainPassPostProcessing,
+                NodeUi::UiPass,
+                Node3d::Upscaling,
+            ));
+        }
+    }
+}
+
+/// Gives ray traced cameras the prepasses the ray tracer reprojects last frame's samples with
+#[allow(clippy::type_complexity)]
+fn add_prepasses(
+    mut commands: Commands,
+    cameras: Query<
+        (Entity, &CameraRenderGraph),
+        Or<(
+            Without<DepthPrepass>,
+            Without<NormalPrepass>,
+            Without<MotionVectorPrepass>,
+        )>,
+    >,
+) {
+    for (entity, render_graph) in &cameras {
+        if **render_graph == RayTracingGraph.intern() {
+            commands
+                .entity(entity)
+                .insert((DepthPrepass, NormalPrepass, MotionVectorPrepass));
+        }
+    }
+}
+
+/// Controls how paths are traced
+#[derive(Resource, ExtractResource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct RayTracingSettings {
+    /// Most surfaces a path bounces off before it is cut short
+    pub max_bounces: u32,
+    /// Bounces after which paths are randomly terminated, with the survivors weighted up
+    pub russian_roulette_depth: u32,
+    /// Light samples ReSTIR draws per pixel for direct lighting at the first hit, 0 turns ReSTIR
+    /// off so every bounce picks a single light
+    pub restir_candidates: u32,
+    /// Neighbouring pixels whose reservoirs are reused
+    pub restir_spatial_samples: u32,
+    /// Radius in pixels neighbours are picked from
+    pub restir_spatial_radius: f32,
+    /// Caps the samples last frame's reservoir counts for, relative to the new candidates
+    pub restir_max_history: u32,
+    /// Caps the samples a pixel's average counts for once the view or its surface moved, lower
+    /// values leave shorter trails behind but more noise
+    pub reprojected_max_samples: u32,
+    /// Scrambles every sampler, the CPU reference renders the same image for the same seed,
+    /// sampler and number of samples
+    ///
+    /// The shader also seeds from the frame index, so noise keeps changing while the
+    /// accumulation is reset every frame.
+    pub seed: u32,
+    pub sampler: SamplerKind,
+}
+
+impl Default for RayTracingSettings {
+    fn default() -> Self {
+        Self {
+            max_bounces: 8,
+            russian_roulette_depth: 3,
+            restir_candidates: 32,
+            restir_spatial_samples: 4,
+            restir_spatial_radius: 16.0,
+            restir_max_history: 20,
+            reprojected_max_samples: 32,
+            seed: 0,
+            sampler: SamplerKind::default(),
+        }
+    }
+}
+
+#[derive(ShaderType, Clone)]
+pub struct GpuRayTracingSettings {
+    pub max_bounces: u32,
+    pub russian_roulette_depth: u32,
+    pub restir_candidates: u32,
+    pub restir_spatial_samples: u32,
+    pub restir_spatial_radius: f32,
+    pub restir_max_history: u32,
+    pub reprojected_max_samples: u32,
+    pub seed: u32,
+    pub sampler_kind: u32,
+}
+
+impl Default for GpuRayTracingSettings {
+    fn default() -> Self {
+        (&RayTracingSettings::default()).into()
+    }
+}
+
+impl From<&RayTracingSettings> for GpuRayTracingSettings {
+    fn from(settings: &RayTracingSettings) -> Self {
+        Self {
+            max_bounces: settings.max_bounces,
+            russian_roulette_depth: settings.russian_roulette_depth,
+            restir_candidates: settings.restir_candidates,
+            restir_spatial_samples: settings.restir_spatial_samples,
+            restir_spatial_radius: settings.restir_spatial_radius,
+            restir_max_history: settings.restir_max_history,
+            reprojected_max_samples: settings.reprojected_max_samples,
+            seed: settings.seed,
+            sampler_kind: settings.sampler.id(),
+        }
     }
 }
 
 #[derive(Reflect, Default, Debug, Clone, ShaderType)]
 pub struct Triangle {
-    indices: [u32; 3],
+    pub indices: [u32; 3],
 }
 
+#[derive(Reflect, Default, Debug, Clone, Copy, ShaderType)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+    /// Zero when the mesh has no tangents, `w` holds the handedness of the bitangent otherwise
+    pub tangent: Vec4,
+}
+
+/// A mesh asset whose triangles and bottom-level BVH are shared by all of its instances
 #[derive(Reflect, Default, Debug, Clone, ShaderType)]
 pub struct MeshInfo {
-    first_tri: u32,
-    tri_count: u32,
-    material: u32,
-    aabb_min: Vec3,
-    aabb_max: Vec3,
+    pub first_tri: u32,
+    pub tri_count: u32,
+    pub root_node: u32,
+    pub aabb_min: Vec3,
+    pub aabb_max: Vec3,
 }
 
+/// An entity drawing a mesh, referenced by the leaves of the top-level BVH
 #[derive(Reflect, Default, Debug, Clone, ShaderType)]
+pub struct MeshInstance {
+    pub world_from_object: Mat4,
+    pub object_from_world: Mat4,
+    pub mesh: u32,
+    pub material: u32,
+}
+
+/// The subset of [`StandardMaterial`] the ray tracer shades with
+#[derive(Reflect, Debug, Clone, ShaderType)]
 pub struct SimpleMaterial {
     pub color: LinearRgba,
+    /// Alpha holds the emissive exposure weight
+    pub emissive: LinearRgba,
+    pub metallic: f32,
+    pub perceptual_roughness: f32,
+    pub reflectance: f32,
+    pub specular_transmission: f32,
+    pub ior: f32,
+    pub clearcoat: f32,
+    pub clearcoat_perceptual_roughness: f32,
+    /// Hits on texels whose alpha is below this are skipped, zero unless alpha is masked
+    pub alpha_cutoff: f32,
+    /// Absorption coefficient of the volume behind the surface, per world unit
+    pub absorption: Vec3,
+    /// Layers of [`RayTracingInfo::textures`], [`NO_TEXTURE`] where the material has none
+    pub base_color_texture: u32,
+    pub emissive_texture: u32,
+    pub metallic_roughness_texture: u32,
+    pub normal_map_texture: u32,
+    pub flags: u32,
+    pub uv_transform: Mat3,
+}
+
+impl SimpleMaterial {
+    pub const FLIP_NORMAL_MAP_Y: u32 = 1;
+}
+
+impl Default for SimpleMaterial {
+    fn default() -> Self {
+        Self::from(&StandardMaterial::default())
+    }
+}
+
+impl From<&StandardMaterial> for SimpleMaterial {
+    fn from(material: &StandardMaterial) -> Self {
+        Self {
+            color: material.base_color.to_linear(),
+            emissive: material
+                .emissive
+                .with_alpha(material.emissive_exposure_weight),
+            metallic: material.metallic,
+            perceptual_roughness: material.perceptual_roughness,
+            reflectance: material.reflectance,
+            specular_transmission: material.specular_transmission,
+            ior: material.ior,
+            clearcoat: material.clearcoat,
+            clearcoat_perceptual_roughness: material.clearcoat_perceptual_roughness,
+            alpha_cutoff: match material.alpha_mode {
+                AlphaMode::Mask(cutoff) => cutoff,
+                _ => 0.0,
+            },
+            // light keeps the attenuation color after traveling the attenuation distance
+            absorption: Vec3::from_array(
+                material
+                    .attenuation_color
+                    .to_linear()
+                    .to_f32_array_no_alpha()
+                    .map(|c| -c.max(1e-4).ln()),
+            ) / material.attenuation_distance,
+            base_color_texture: NO_TEXTURE,
+            emissive_texture: NO_TEXTURE,
+            metallic_roughness_texture: NO_TEXTURE,
+            normal_map_texture: NO_TEXTURE,
+            flags: if material.flip_normal_map_y {
+                Self::FLIP_NORMAL_MAP_Y
+            } else {
+                0
+            },
+            uv_transform: material.uv_transform.into(),
+        }
+    }
 }
 
 impl From<Srgba> for SimpleMaterial {
     fn from(value: Srgba) -> Self {
         Self {
             color: value.into(),
+            ..default()
         }
     }
 }
 
 impl From<LinearRgba> for SimpleMaterial {
     fn from(value: LinearRgba) -> Self {
-        Self { color: value }
+        Self {
+            color: value,
+            ..default()
+        }
     }
 }
 
-#[derive(Clone, Resource, ExtractResource, AsBindGroup, Default)]
+/// The scene as the shader sees it, kept in the render world and patched as it changes
+#[derive(Resource)]
 pub struct RayTracingInfo {
-    #[uniform(0)]
-    pub count: u32,
-    #[storage(1, read_only)]
-    pub triangles: Vec<Triangle>,
-    #[storage(2, read_only)]
-    pub meshes: Vec<MeshInfo>,
-    #[storage(3, read_only)]
-    pub vertices: Vec<[Vec3; 2]>,
-    #[storage(4, read_only)]
-    pub materials: Vec<SimpleMaterial>,
-}
-
-pub fn prepare_meshinfo(
+    /// Frames accumulated since the scene topology, a material or the settings last changed, moving
+    /// instances are reprojected instead
+    pub count: UniformBuffer<u32>,
+    pub settings: UniformBuffer<GpuRayTracingSettings>,
+    pub gi_settings: UniformBuffer<GpuRestirGiSettings>,
+    pub triangles: GpuArray<Triangle>,
+    pub meshes: GpuArray<MeshInfo>,
+    pub vertices: GpuArray<Vertex>,
+    pub materials: GpuArray<SimpleMaterial>,
+    pub nodes: GpuArray<BvhNode>,
+    pub instances: GpuArray<MeshInstance>,
+    pub tlas_nodes: GpuArray<BvhNode>,
+    pub textures: TextureArray,
+    pub lights: GpuArray<GpuLight>,
+    pub emissive_triangles: GpuArray<EmissiveTriangle>,
+    pub light_info: UniformBuffer<GpuLightInfo>,
+    /// Tables of the Sobol and blue noise samplers
+    pub sobol_directions: GpuArray<u32>,
+    pub blue_noise: GpuArray<u32>,
+    /// Recreated only when one of the buffers is reallocated or resized
+    pub bind_group: Option<BindGroup>,
+    rebind: bool,
+    reset: bool,
+}
+
+impl Default for RayTracingInfo {
+    fn default() -> Self {
+        let mut sobol = GpuArray::new("ray_tracing_sobol_directions");
+        sobol.replace(sobol_directions().to_vec());
+        let mut noise = GpuArray::new("ray_tracing_blue_noise");
+        noise.replace(blue_noise().to_vec());
+        Self {
+            count: UniformBuffer::default(),
+            settings: UniformBuffer::default(),
+            gi_settings: UniformBuffer::default(),
+            triangles: GpuArray::new("ray_tracing_triangles"),
+            meshes: GpuArray::new("ray_tracing_meshes"),
+            vertices: GpuArray::new("ray_tracing_vertices"),
+            materials: GpuArray::new("ray_tracing_materials"),
+            nodes: GpuArray::new("ray_tracing_nodes"),
+            instances: GpuArray::new("ray_tracing_instances"),
+            tlas_nodes: GpuArray::new("ray_tracing_tlas_nodes"),
+            textures: TextureArray::default(),
+            lights: GpuArray::new("ray_tracing_lights"),
+            emissive_triangles: GpuArray::new("ray_tracing_emissive_triangles"),
+            light_info: UniformBuffer::default(),
+            sobol_directions: sobol,
+            blue_noise: noise,
+            bind_group: None,
+            rebind: true,
+            reset: true,
+        }
+    }
+}
+
+impl RayTracingInfo {
+    /// Throws away the accumulated samples of every view at the start of the next frame
+    pub fn reset_accumulation(&mut self) {
+        self.reset = true;
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "ray_tracing_info_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                (
+                    uniform_buffer::<u32>(false),
+                    storage_buffer_read_only::<Triangle>(false),
+                    storage_buffer_read_only::<MeshInfo>(false),
+                    storage_buffer_read_only::<Vertex>(false),
+                    storage_buffer_read_only::<SimpleMaterial>(false),
+                    storage_buffer_read_only::<BvhNode>(false),
+                    storage_buffer_read_only::<MeshInstance>(false),
+                    storage_buffer_read_only::<BvhNode>(false),
+                    texture_2d_array(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    uniform_buffer::<GpuRayTracingSettings>(false),
+                    storage_buffer_read_only::<GpuLight>(false),
+                    storage_buffer_read_only::<EmissiveTriangle>(false),
+                    uniform_buffer::<GpuLightInfo>(false),
+                    uniform_buffer::<GpuRestirGiSettings>(false),
+                    storage_buffer_read_only::<u32>(false),
+                    storage_buffer_read_only::<u32>(false),
+                ),
+            ),
+        )
+    }
+
+    fn create_bind_group(
+        &self,
+        render_device: &RenderDevice,
+        layout: &BindGroupLayout,
+    ) -> Option<BindGroup> {
+        Some(render_device.create_bind_group(
+            "ray_tracing_info_bind_group",
+            layout,
+            &BindGroupEntries::sequential((
+                self.count.binding()?,
+                self.triangles.binding()?,
+                self.meshes.binding()?,
+                self.vertices.binding()?,
+                self.materials.binding()?,
+                self.nodes.binding()?,
+                self.instances.binding()?,
+                self.tlas_nodes.binding()?,
+                self.textures.view()?,
+                self.textures.sampler()?,
+                self.settings.binding()?,
+                self.lights.binding()?,
+                self.emissive_triangles.binding()?,
+                self.light_info.binding()?,
+                self.gi_settings.binding()?,
+                self.sobol_directions.binding()?,
+                self.blue_noise.binding()?,
+            )),
+        ))
+    }
+}
+
+fn prepare_buffers(
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    settings: Res<RayTracingSettings>,
+    gi_settings: Res<RestirGiSettings>,
+    svgf_settings: Res<SvgfSettings>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let info = &mut *ray_tracing_info;
+    let (device, queue) = (&*render_device, &*render_queue);
+    if settings.is_changed() {
+        info.settings.set(settings.as_ref().into());
+        info.reset = true;
+    }
+    if gi_settings.is_changed() {
+        info.gi_settings.set(gi_settings.as_ref().into());
+        info.reset = true;
+    }
+    // the denoiser's history is only kept up to date while it is enabled
+    if svgf_settings.is_changed() {
+        info.reset = true;
+    }
+    let frame_count = if info.reset { 0 } else { info.count.get() + 1 };
+    info.count.set(frame_count);
+    info.reset = false;
+    info.rebind |= info.count.buffer().is_none()
+        || info.settings.buffer().is_none()
+        || info.gi_settings.buffer().is_none()
+        || info.light_info.buffer().is_none();
+    info.count.write_buffer(device, queue);
+    info.settings.write_buffer(device, queue);
+    info.gi_settings.write_buffer(device, queue);
+    info.light_info.write_buffer(device, queue);
+    info.rebind |= info.triangles.write_buffer(device, queue)
+        | info.meshes.write_buffer(device, queue)
+        | info.vertices.write_buffer(device, queue)
+        | info.materials.write_buffer(device, queue)
+        | info.nodes.write_buffer(device, queue)
+        | info.instances.write_buffer(device, queue)
+        | info.tlas_nodes.write_buffer(device, queue)
+        | info.lights.write_buffer(device, queue)
+        | info.emissive_triangles.write_buffer(device, queue)
+        | info.sobol_directions.write_buffer(device, queue)
+        | info.blue_noise.write_buffer(device, queue)
+        | info.textures.write_texture(device, queue);
+}
+
+fn prepare_info_bind_group(
+    mut ray_tracing_info: ResMut<RayTracingInfo>,
+    render_device: Res<RenderDevice>,
+    pipeline: Res<RayTracingPipeline>,
+) {
+    if !ray_tracing_info.rebind {
+        return;
+    }
+    ray_tracing_info.bind_group =
+        ray_tracing_info.create_bind_group(&render_device, &pipeline.info_layout);
+    ray_tracing_info.rebind = false;
+}
+
+/// The per view bind group with the view uniforms, globals, prepass textures and last frame's
+/// accumulated samples, along with the targets the new average and its geometry are written to
+///
+/// The ReSTIR passes get their own bind groups with the reservoir buffers the other way round,
+/// `restir_bind_group` is for both candidate passes and `restir_gi_bind_group` for GI resampling.
+#[derive(Component)]
+pub struct RayTracingViewBindGroup {
+    pub bind_group: BindGroup,
+    pub restir_bind_group: BindGroup,
+    pub restir_gi_bind_group: BindGroup,
+    pub accumulation: TextureView,
+    pub geometry: TextureView,
+    pub radiance: TextureView,
+    pub albedo: TextureView,
+}
+
+type ViewBindGroupKey = (
+    [BufferId; 3],
+    [TextureViewId; 3],
+    TextureViewId,
+    [TextureViewId; 2],
+    [BufferId; 2],
+    [BufferId; 4],
+);
+
+/// View bind groups kept as long as the buffers and textures they reference stay the same, one
+/// for each way round the accumulation textures are used, each for every way the ReSTIR passes
+/// use the reservoirs
+#[derive(Resource, Default)]
+struct ViewBindGroupCache(EntityHashMap<(ViewBindGroupKey, [[BindGroup; 3]; 2])>);
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_view_bind_groups(
     mut commands: Commands,
-    query: Extract<
-        Query<(
-            &Handle<Mesh>,
-            &Handle<StandardMaterial>,
-            &GlobalTransform,
-            &Aabb,
-        )>,
-    >,
-    mesh_assets: Extract<Res<Assets<Mesh>>>,
-    material_assets: Extract<Res<Assets<StandardMaterial>>>,
-    ray_tracing_info: Extract<Res<RayTracingInfo>>,
+    mut cache: ResMut<ViewBindGroupCache>,
+    views: Query<(
+        Entity,
+        &ExtractedCamera,
+        &ViewPrepassTextures,
+        &ViewEnvironment,
+    )>,
+    accumulations: Res<AccumulationCache>,
+    reservoirs: Res<ReservoirCache>,
+    view_uniforms: Res<ViewUniforms>,
+    previous_view_uniforms: Res<PreviousViewUniforms>,
+    globals_buffer: Res<GlobalsBuffer>,
+    pipeline: Res<RayTracingPipeline>,
+    render_device: Res<RenderDevice>,
 ) {
-    let mut ray_tracing_info = ray_tracing_info.clone();
-    let mut vertices = vec![];
-    let mut triangles = vec![];
-    let mut mesh_info = vec![];
-    let mut materials = vec![];
-    // let materials: Vec<(AssetId<StandardMaterial>, &StandardMaterial)> = material_assets.iter().collect()
-    let mut material_index = 0;
-    for (mesh_handle, material_handle, transform, aabb) in query.iter() {
-        let mesh: &Mesh = mesh_assets.get(mesh_handle).unwrap();
-        let material: &StandardMaterial = material_assets.get(material_handle).unwrap();
-        materials.push(material.base_color.to_linear().into());
-        let (Some(pos), Some(norm)) = (
-            mesh.attribute(Mesh::ATTRIBUTE_POSITION),
-            mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
+    let (Some(view_buffer), Some(previous_view_buffer), Some(globals)) = (
+        view_uniforms.uniforms.buffer(),
+        previous_view_uniforms.uniforms.buffer(),
+        globals_buffer.buffer.buffer(),
+    ) else {
+        return;
+    };
+    let mut bind_groups = EntityHashMap::default();
+    for (entity, camera, prepass_textures, environment) in &views {
+        if camera.render_graph != RayTracingGraph.intern() {
+            continue;
+        }
+        let (Some(motion), Some(depth), Some(normal), Some(accumulation), Some(reservoirs)) = (
+            prepass_textures.motion_vectors_view(),
+            prepass_textures.depth_view(),
+            prepass_textures.normal_view(),
+            accumulations.0.get(&entity),
+            reservoirs.0.get(&entity),
         ) else {
-            println!("Mesh missing attribute");
             continue;
         };
-        let vertices_len = vertices.len();
-        let pos = pos.as_float3().unwrap().iter();
-        let norm = norm.as_float3().unwrap().iter();
-        let (_scale, rotate, translate) = transform.to_scale_rotation_translation();
-        vertices.extend(
-            pos.zip(norm)
-                .map(|(a, b)| {
-                    let pos = transform.transform_point(a.to_owned().into());
-                    // let norm = transform.transform_point(b.to_owned().into());
-                    let b: Vec3 = b.to_owned().into();
-                    let norm = rotate * b;
-                    [pos, norm]
-                })
-                .collect::<Vec<[Vec3; 2]>>(),
+        let key = (
+            [view_buffer.id(), previous_view_buffer.id(), globals.id()],
+            [motion.id(), depth.id(), normal.id()],
+            accumulation.views[0].id(),
+            [environment.equirectangular.id(), environment.cube.id()],
+            [environment.uniform.id(), environment.cdf.id()],
+            [
+                reservoirs.initial.id(),
+                reservoirs.shaded.id(),
+                reservoirs.gi_initial.id(),
+                reservoirs.gi_shaded.id(),
+            ],
         );
-        let len = mesh.indices().unwrap().len();
-        let indices: Vec<usize> = mesh.indices().unwrap().iter().collect();
-        let triangle_len = triangles.len();
-        let cube = Cuboid::from_size(Vec3::from(aabb.half_extents) * 2.0);
-        let aabb = cube.aabb_3d(translate, rotate);
-        mesh_info.push(MeshInfo {
-            first_tri: triangle_len as u32,
-            tri_count: len as u32 / 3,
-            material: material_index,
-            aabb_min: aabb.min.into(),
-            aabb_max: aabb.max.into(),
+        let view_bind_groups = match cache.0.remove(&entity) {
+            Some((cached_key, view_bind_groups)) if cached_key == key => view_bind_groups,
+            _ => [1, 0].map(|read| {
+                // the shading passes read the candidates and write the reservoirs they shaded
+                // with, the candidate passes read those back the next frame
+                let (initial, shaded) = (&reservoirs.initial, &reservoirs.shaded);
+                let (gi_initial, gi_shaded) = (&reservoirs.gi_initial, &reservoirs.gi_shaded);
+                [
+                    [initial, shaded, gi_shaded, gi_initial],
+                    [shaded, initial, gi_shaded, gi_initial],
+                    [shaded, initial, gi_initial, gi_shaded],
+                ]
+                .map(|[reservoirs_in, reservoirs_out, gi_in, gi_out]| {
+                    render_device.create_bind_group(
+                        "ray_tracing_bind_group",
+                        &pipeline.layout,
+                        &BindGroupEntries::sequential((
+                            view_uniforms.uniforms.binding().unwrap(),
+                            globals_buffer.buffer.binding().unwrap(),
+                            motion,
+                            &accumulation.views[read],
+                            &environment.equirectangular,
+                            &environment.cube,
+                            environment.uniform.as_entire_binding(),
+                            environment.cdf.as_entire_binding(),
+                            reservoirs_in.as_entire_binding(),
+                            reservoirs_out.as_entire_binding(),
+                            gi_in.as_entire_binding(),
+                            gi_out.as_entire_binding(),
+                            depth,
+                            normal,
+                            &accumulation.geometry_views[read],
+                            previous_view_uniforms.uniforms.binding().unwrap(),
+                        )),
+                    )
+                })
+            }),
+        };
+        let (_, write) = accumulation.read_write();
+        let (_, geometry) = accumulation.geometry_read_write();
+        let [bind_group, restir_bind_group, restir_gi_bind_group] =
+            &view_bind_groups[accumulation.write_index()];
+        commands.entity(entity).insert(RayTracingViewBindGroup {
+            bind_group: bind_group.clone(),
+            restir_bind_group: restir_bind_group.clone(),
+            restir_gi_bind_group: restir_gi_bind_group.clone(),
+            accumulation: write.clone(),
+            geometry: geometry.clone(),
+            radiance: accumulation.radiance.clone(),
+            albedo: accumulation.albedo.clone(),
         });
-        material_index += 1;
-        indices
-            .iter()
-            .map(|i| (i + vertices_len) as u32)
-            .tuples::<(_, _, _)>()
-            .for_each(|(a, b, c)| triangles.push(Triangle { indices: [a, b, c] }));
-    }
-    ray_tracing_info.triangles = triangles;
-    ray_tracing_info.meshes = mesh_info;
-    ray_tracing_info.vertices = vertices;
-    ray_tracing_info.materials = materials;
-    commands.insert_resource(ray_tracing_info);
+        bind_groups.insert(entity, (key, view_bind_groups));
+    }
+    cache.0 = bind_groups;
 }
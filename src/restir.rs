@@ -0,0 +1,157 @@
+use bevy::{
+    ecs::entity::EntityHashMap,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        extract_resource::ExtractResource,
+        render_graph::RenderSubGraph,
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, ShaderSize, ShaderType},
+        renderer::RenderDevice,
+    },
+};
+
+use crate::ray_tracing::RayTracingGraph;
+
+/// Mirrors `Reservoir` in the shader, only used to size and lay out the buffers
+#[derive(ShaderType, Default, Clone)]
+pub struct Reservoir {
+    /// Random numbers the point on the light was sampled with
+    pub uv: Vec2,
+    pub light: u32,
+    pub w_total: f32,
+    pub w: f32,
+    pub num: u32,
+    pub depth: f32,
+    /// Normal of the surface the reservoir was built for, packed as snorm8
+    pub normal: u32,
+}
+
+/// Mirrors `GiReservoir` in the shader, only used to size and lay out the buffers
+#[derive(ShaderType, Default, Clone)]
+pub struct GiReservoir {
+    /// Point a path from the surface bounced off
+    pub sample_position: Vec3,
+    pub w_total: f32,
+    pub sample_normal: Vec3,
+    pub w: f32,
+    /// Radiance leaving the sample point towards the surface it was found from
+    pub radiance: Vec3,
+    pub num: u32,
+    /// Surface the reservoir was built for
+    pub position: Vec3,
+    pub depth: f32,
+    /// Indirect light the resampling pass found for the surface
+    pub indirect: Vec3,
+    pub normal: u32,
+}
+
+/// Controls the ReSTIR GI node, which resamples the first bounce of every pixel's path across
+/// frames and neighbours
+///
+/// Pixels it handles don't trace paths past their first hit in the main pass. Surfaces with
+/// specular transmission are always path traced.
+#[derive(Resource, ExtractResource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct RestirGiSettings {
+    pub enabled: bool,
+    /// Neighbouring pixels whose reservoirs are reused
+    pub spatial_samples: u32,
+    /// Radius in pixels neighbours are picked from
+    pub spatial_radius: f32,
+    /// Caps the samples last frame's reservoir counts for
+    pub max_history: u32,
+}
+
+impl Default for RestirGiSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            spatial_samples: 3,
+            spatial_radius: 24.0,
+            max_history: 30,
+        }
+    }
+}
+
+#[derive(ShaderType, Default, Clone)]
+pub struct GpuRestirGiSettings {
+    pub enabled: u32,
+    pub spatial_samples: u32,
+    pub spatial_radius: f32,
+    pub max_history: u32,
+}
+
+impl From<&RestirGiSettings> for GpuRestirGiSettings {
+    fn from(settings: &RestirGiSettings) -> Self {
+        Self {
+            enabled: settings.enabled.into(),
+            spatial_samples: settings.spatial_samples,
+            spatial_radius: settings.spatial_radius,
+            max_history: settings.max_history,
+        }
+    }
+}
+
+/// Per pixel ReSTIR reservoirs of a view
+///
+/// The candidate passes write `initial` from new samples and last frame's `shaded` reservoirs,
+/// the shading passes then resample `initial` across neighbours into `shaded`.
+pub struct ViewReservoirs {
+    pub initial: Buffer,
+    pub shaded: Buffer,
+    pub gi_initial: Buffer,
+    pub gi_shaded: Buffer,
+    size: UVec2,
+}
+
+impl ViewReservoirs {
+    fn new(render_device: &RenderDevice, size: UVec2) -> Self {
+        let buffer = |label, stride: u64| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: (size.x * size.y).max(1) as u64 * stride,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let stride = Reservoir::SHADER_SIZE.get();
+        let gi_stride = GiReservoir::SHADER_SIZE.get();
+        Self {
+            initial: buffer("ray_tracing_initial_reservoirs", stride),
+            shaded: buffer("ray_tracing_reservoirs", stride),
+            gi_initial: buffer("ray_tracing_initial_gi_reservoirs", gi_stride),
+            gi_shaded: buffer("ray_tracing_gi_reservoirs", gi_stride),
+            size,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ReservoirCache(pub EntityHashMap<ViewReservoirs>);
+
+/// Keeps reservoir buffers the size of every ray traced view's viewport, they persist between
+/// frames so samples can be reused temporally
+///
+/// Unlike the accumulation textures they aren't attachments, so they hold one reservoir per pixel
+/// of the viewport, indexed from its corner.
+pub fn prepare_reservoirs(
+    mut cache: ResMut<ReservoirCache>,
+    views: Query<(Entity, &ExtractedCamera)>,
+    render_device: Res<RenderDevice>,
+) {
+    let mut reservoirs = EntityHashMap::default();
+    for (entity, camera) in &views {
+        if camera.render_graph != RayTracingGraph.intern() {
+            continue;
+        }
+        let Some(size) = camera.physical_viewport_size else {
+            continue;
+        };
+        let view_reservoirs = match cache.0.remove(&entity) {
+            Some(view_reservoirs) if view_reservoirs.size == size => view_reservoirs,
+            _ => ViewReservoirs::new(&render_device, size),
+        };
+        reservoirs.insert(entity, view_reservoirs);
+    }
+    cache.0 = reservoirs;
+}
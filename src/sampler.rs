@@ -0,0 +1,319 @@
+use std::sync::OnceLock;
+
+use bevy::prelude::*;
+
+/// Where the random numbers paths are sampled with come from
+#[derive(Reflect, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Independent random numbers from a PCG generator
+    #[default]
+    Pcg,
+    /// Owen scrambled Sobol points, which converge faster
+    Sobol,
+    /// A tile of blue noise, shifted every frame, which spreads the error of few samples into
+    /// high frequencies that are easier to denoise
+    BlueNoise,
+}
+
+impl SamplerKind {
+    /// Id the shader tells the samplers apart with
+    pub fn id(self) -> u32 {
+        match self {
+            SamplerKind::Pcg => 0,
+            SamplerKind::Sobol => 1,
+            SamplerKind::BlueNoise => 2,
+        }
+    }
+
+    /// Inverse of [`SamplerKind::id`], unknown ids fall back to PCG like in the shader
+    pub fn from_id(id: u32) -> Self {
+        match id {
+            1 => SamplerKind::Sobol,
+            2 => SamplerKind::BlueNoise,
+            _ => SamplerKind::Pcg,
+        }
+    }
+}
+
+/// Width and height of the blue noise tile
+pub const BLUE_NOISE_SIZE: u32 = 64;
+/// Dimensions of the Sobol sequence, more are padded by shuffling each group of them separately
+pub const SOBOL_DIMENSIONS: u32 = 4;
+
+pub fn hash(input: u32) -> u32 {
+    let state = input.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Direction numbers of the first [`SOBOL_DIMENSIONS`], 32 bits each (Joe and Kuo 2008)
+pub fn sobol_directions() -> &'static [u32] {
+    static DIRECTIONS: OnceLock<Vec<u32>> = OnceLock::new();
+    DIRECTIONS.get_or_init(|| {
+        // degree, inner coefficients and initial direction numbers of the primitive polynomials
+        let polynomials: [(usize, u32, &[u32]); 3] =
+            [(1, 0, &[1]), (2, 1, &[1, 3]), (3, 1, &[1, 3, 1])];
+        let mut directions: Vec<u32> = (0..32).map(|bit| 1 << (31 - bit)).collect();
+        for (degree, coefficients, initial) in polynomials {
+            let mut m = initial.to_vec();
+            for k in degree..32 {
+                let mut value = m[k - degree] ^ (m[k - degree] << degree);
+                for i in 1..degree {
+                    if (coefficients >> (degree - 1 - i)) & 1 != 0 {
+                        value ^= m[k - i] << i;
+                    }
+                }
+                m.push(value);
+            }
+            directions.extend(m.iter().enumerate().map(|(k, m)| m << (31 - k)));
+        }
+        directions
+    })
+}
+
+/// Ranks of the texels of a tileable blue noise texture, from void and cluster (Ulichney 1993)
+pub fn blue_noise() -> &'static [u32] {
+    static RANKS: OnceLock<Vec<u32>> = OnceLock::new();
+    RANKS.get_or_init(|| {
+        let size = BLUE_NOISE_SIZE as usize;
+        let count = size * size;
+        // gaussian energy of a texel on every other one, wrapping around
+        let kernel: Vec<f32> = (0..count)
+            .map(|index| {
+                let wrap = |d: usize| d.min(size - d) as f32;
+                let (x, y) = (wrap(index % size), wrap(index / size));
+                (-(x * x + y * y) / (2.0 * 1.5 * 1.5)).exp()
+            })
+            .collect();
+        let mut energy = vec![0.0; count];
+        let toggle = |energy: &mut [f32], index: usize, sign: f32| {
+            let (x, y) = (index % size, index / size);
+            for (other, energy) in energy.iter_mut().enumerate() {
+                let dx = (other % size + size - x) % size;
+                let dy = (other / size + size - y) % size;
+                *energy += sign * kernel[dy * size + dx];
+            }
+        };
+        let extreme = |energy: &[f32], pattern: &[bool], set: bool, max: bool| {
+            let candidates = (0..count).filter(|&index| pattern[index] == set);
+            let key = |&index: &usize| energy[index];
+            if max {
+                candidates.max_by(|a, b| key(a).total_cmp(&key(b)))
+            } else {
+                candidates.min_by(|a, b| key(a).total_cmp(&key(b)))
+            }
+            .unwrap()
+        };
+
+        // a tenth of the texels at random, spread out evenly by moving the tightest cluster into
+        // the largest void until that doesn't change anything
+        let mut pattern = vec![false; count];
+        let mut state = 1;
+        let mut ones = 0;
+        while ones < count / 10 {
+            state = hash(state);
+            let index = state as usize % count;
+            if !pattern[index] {
+                pattern[index] = true;
+                toggle(&mut energy, index, 1.0);
+                ones += 1;
+            }
+        }
+        loop {
+            let cluster = extreme(&energy, &pattern, true, true);
+            pattern[cluster] = false;
+            toggle(&mut energy, cluster, -1.0);
+            let void = extreme(&energy, &pattern, false, false);
+            pattern[void] = true;
+            toggle(&mut energy, void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; count];
+        // the initial texels are ranked by removing the tightest cluster
+        let (mut removed, mut removed_energy) = (pattern.clone(), energy.clone());
+        for rank in (0..ones).rev() {
+            let cluster = extreme(&removed_energy, &removed, true, true);
+            removed[cluster] = false;
+            toggle(&mut removed_energy, cluster, -1.0);
+            ranks[cluster] = rank as u32;
+        }
+        // the rest by filling the largest void, which is also the tightest cluster of the
+        // unset texels once they are the minority
+        for rank in ones..count {
+            let void = extreme(&energy, &pattern, false, false);
+            pattern[void] = true;
+            toggle(&mut energy, void, 1.0);
+            ranks[void] = rank as u32;
+        }
+        ranks
+    })
+}
+
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Random permutation of `x` that keeps its stratification (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    let directions = &sobol_directions()[dimension as usize * 32..];
+    (0..32)
+        .filter(|bit| (index >> bit) & 1 != 0)
+        .fold(0, |result, bit| result ^ directions[bit])
+}
+
+/// Random numbers of one pixel and sample, the same ones the shader draws
+///
+/// Every call of [`PixelSampler::rand`] draws the next dimension.
+pub struct PixelSampler {
+    kind: SamplerKind,
+    pixel: UVec2,
+    index: u32,
+    dimension: u32,
+    /// Shared by every pixel, blue noise tiles are moved by it per dimension
+    stream: u32,
+    seed: u32,
+    state: u32,
+}
+
+impl PixelSampler {
+    /// `pixel_index` is the pixel's index in rows first order, `frame` counts every frame
+    /// rendered and `index` the samples accumulated for the pixel since the last reset
+    ///
+    /// Only `index` walks the low discrepancy sequences, they are scrambled by the frame the
+    /// accumulation restarted on. PCG numbers are seeded from `frame`.
+    pub fn new(
+        kind: SamplerKind,
+        seed: u32,
+        pixel: UVec2,
+        pixel_index: u32,
+        frame: u32,
+        index: u32,
+    ) -> Self {
+        let stream = hash(seed ^ hash(frame.wrapping_sub(index)));
+        let pixel_seed = hash(hash(pixel_index) ^ stream);
+        Self {
+            kind,
+            pixel,
+            index,
+            dimension: 0,
+            stream,
+            seed: pixel_seed,
+            state: hash(pixel_seed ^ frame),
+        }
+    }
+
+    fn next_pcg(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(747796405).wrapping_add(2891336453);
+        let word = ((self.state >> ((self.state >> 28) + 4)) ^ self.state).wrapping_mul(277803737);
+        (word >> 22) ^ word
+    }
+
+    fn sobol_sample(&self, dimension: u32) -> u32 {
+        let group_seed = hash(self.seed ^ (dimension / SOBOL_DIMENSIONS));
+        let index = nested_uniform_scramble(self.index, group_seed);
+        nested_uniform_scramble(
+            sobol(index, dimension % SOBOL_DIMENSIONS),
+            hash(group_seed ^ dimension),
+        )
+    }
+
+    fn blue_noise_sample(&self, dimension: u32) -> u32 {
+        let offset = hash(self.stream ^ dimension);
+        let texel = (self.pixel + UVec2::new(offset, offset >> 16)) % BLUE_NOISE_SIZE;
+        let rank = blue_noise()[(texel.y * BLUE_NOISE_SIZE + texel.x) as usize];
+        // ranks are spread over [0, 1) in 32 bit fixed point, then shifted by the golden ratio
+        // every sample
+        let bits = 32 - (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE).trailing_zeros();
+        let value = (rank << bits) + (1 << (bits - 1));
+        value.wrapping_add(self.index.wrapping_mul(2654435769))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match self.kind {
+            SamplerKind::Pcg => self.next_pcg(),
+            SamplerKind::Sobol => self.sobol_sample(dimension),
+            SamplerKind::BlueNoise => self.blue_noise_sample(dimension),
+        }
+    }
+
+    /// Uniform in [0, 1)
+    pub fn rand(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / 16777216.0
+    }
+
+    pub fn rand2(&mut self) -> Vec2 {
+        Vec2::new(self.rand(), self.rand())
+    }
+
+    pub fn randint(&mut self, max: u32) -> u32 {
+        ((self.rand() * max as f32) as u32).min(max - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(
+        kind: SamplerKind,
+        seed: u32,
+        frame: u32,
+        count: u32,
+        dimensions: u32,
+    ) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|index| {
+                let mut sampler =
+                    PixelSampler::new(kind, seed, UVec2::new(3, 5), 42, frame + index, index);
+                (0..dimensions).map(|_| sampler.rand()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sobol_points_are_stratified() {
+        let points = samples(SamplerKind::Sobol, 7, 100, 16, 6);
+        // every pair of dimensions in a group is a (0, 4, 2)-net, each elementary interval of
+        // area 1/16 holds exactly one point
+        for (a, b) in [(0, 1), (2, 3), (4, 5)] {
+            for x_bits in 0..=4 {
+                let y_bits = 4 - x_bits;
+                let mut counts = [0; 16];
+                for point in &points {
+                    let x = (point[a] * (1 << x_bits) as f32) as usize;
+                    let y = (point[b] * (1 << y_bits) as f32) as usize;
+                    counts[x << y_bits | y] += 1;
+                }
+                assert_eq!(
+                    counts, [1; 16],
+                    "dimensions {a} and {b}, {x_bits} bits of x"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_sampler_is_deterministic() {
+        for kind in [SamplerKind::Pcg, SamplerKind::Sobol, SamplerKind::BlueNoise] {
+            let points = samples(kind, 7, 100, 8, 8);
+            assert_eq!(points, samples(kind, 7, 100, 8, 8));
+            assert_ne!(points, samples(kind, 8, 100, 8, 8));
+            // a restarted accumulation draws from a differently scrambled sequence
+            assert_ne!(points, samples(kind, 7, 200, 8, 8));
+        }
+    }
+}
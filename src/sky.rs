@@ -0,0 +1,129 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use bevy::{
+    pbr::light_consts::lux::DIRECT_SUNLIGHT, prelude::*, render::render_resource::ShaderType,
+};
+
+/// Analytic daylight sky lighting a ray traced camera, lit by the scene's first visible
+/// [`DirectionalLight`]
+///
+/// Uses the Preetham model. The sun's direction and illuminance come from the light, the light
+/// itself still provides the direct sunlight. Takes precedence over the camera's [`Skybox`] and
+/// [`EnvironmentMapLight`].
+///
+/// [`Skybox`]: bevy::core_pipeline::Skybox
+/// [`EnvironmentMapLight`]: bevy::pbr::environment_map::EnvironmentMapLight
+#[derive(Component, Reflect, Clone)]
+#[reflect(Component)]
+pub struct PhysicalSky {
+    /// Haziness of the atmosphere, from 2 for a clear sky to 10 for a hazy one
+    pub turbidity: f32,
+    /// Color of the ground seen below the horizon
+    pub ground_albedo: Color,
+}
+
+impl Default for PhysicalSky {
+    fn default() -> Self {
+        Self {
+            turbidity: 3.0,
+            ground_albedo: Color::srgb(0.3, 0.3, 0.3),
+        }
+    }
+}
+
+/// The Preetham sky for one sun position, relative to a sun of [`DIRECT_SUNLIGHT`]
+///
+/// Radiance is in kcd/m², the shader evaluates it with `sky_radiance`.
+#[derive(ShaderType, Default, Clone, PartialEq, Debug)]
+pub struct PreethamSky {
+    /// Perez coefficients A to E, each for luminance and both chromaticities
+    pub perez: [Vec3; 5],
+    /// Luminance and chromaticity of the zenith over the Perez function at the zenith
+    pub zenith: Vec3,
+    pub sun_direction: Vec3,
+    /// Radiance of the ground, lit by the sun alone
+    pub ground: Vec3,
+}
+
+impl PreethamSky {
+    /// `sun_direction` points towards the sun, it is kept above the horizon
+    pub fn new(sky: &PhysicalSky, sun_direction: Vec3) -> Self {
+        let t = sky.turbidity.clamp(1.7, 10.0);
+        let sun_direction = sun_direction.with_y(sun_direction.y.max(1e-3)).normalize();
+        let theta = sun_direction.y.acos().min(FRAC_PI_2);
+        let perez = [
+            Vec3::new(
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ),
+            Vec3::new(
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ),
+            Vec3::new(
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ),
+            Vec3::new(
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ),
+            Vec3::new(
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ),
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = Vec3::new(theta.powi(3), theta.powi(2), theta);
+        let x = Vec3::new(0.00166, -0.00375, 0.00209).dot(theta) * t * t
+            + (Vec3::new(-0.02903, 0.06377, -0.03202).dot(theta) + 0.00394) * t
+            + Vec3::new(0.11693, -0.21196, 0.06052).dot(theta)
+            + 0.25886;
+        let y = Vec3::new(0.00275, -0.00610, 0.00317).dot(theta) * t * t
+            + (Vec3::new(-0.04214, 0.08970, -0.04153).dot(theta) + 0.00516) * t
+            + Vec3::new(0.15346, -0.26756, 0.06670).dot(theta)
+            + 0.26688;
+        let mut model = Self {
+            perez,
+            sun_direction,
+            ..default()
+        };
+        model.zenith = Vec3::new(luminance.max(0.0), x, y) / model.perez(Vec3::Y);
+        model.ground = LinearRgba::from(sky.ground_albedo).to_vec3() * DIRECT_SUNLIGHT / 1000.0
+            * sun_direction.y
+            / PI;
+        model
+    }
+
+    /// Perez distribution of luminance and chromaticity towards `direction`
+    fn perez(&self, direction: Vec3) -> Vec3 {
+        let [a, b, c, d, e] = &self.perez;
+        let cos_theta = direction.y.max(1e-3);
+        let cos_gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        (Vec3::ONE + *a * (*b / cos_theta).exp())
+            * (Vec3::ONE + *c * (*d * gamma).exp() + *e * cos_gamma * cos_gamma)
+    }
+
+    /// Linear rgb radiance towards `direction`, same as `sky_radiance` in the shader
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        if direction.y < 0.0 {
+            return self.ground;
+        }
+        let xy_luminance = self.zenith * self.perez(direction);
+        let (luminance, x, y) = (xy_luminance.x, xy_luminance.y, xy_luminance.z);
+        let xyz = Vec3::new(x / y, 1.0, (1.0 - x - y) / y) * luminance;
+        Vec3::new(
+            Vec3::new(3.2406, -1.5372, -0.4986).dot(xyz),
+            Vec3::new(-0.9689, 1.8758, 0.0415).dot(xyz),
+            Vec3::new(0.0557, -0.2040, 1.0570).dot(xyz),
+        )
+        .max(Vec3::ZERO)
+    }
+}
@@ -0,0 +1,532 @@
+use bevy::{
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state, prepass::ViewPrepassTextures,
+    },
+    ecs::{entity::EntityHashMap, query::QueryItem},
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        extract_resource::ExtractResource,
+        render_graph::{NodeRunError, RenderGraphContext, RenderSubGraph, ViewNode},
+        render_resource::{
+            binding_types::{texture_2d, texture_storage_2d, uniform_buffer},
+            BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BufferId,
+            CachedComputePipelineId, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            ComputePassDescriptor, ComputePipelineDescriptor, DynamicUniformBuffer, Extent3d,
+            FragmentState, MultisampleState, PipelineCache, PrimitiveState, RenderPassDescriptor,
+            RenderPipelineDescriptor, ShaderStages, ShaderType, StorageTextureAccess,
+            TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+            TextureView, TextureViewDescriptor, TextureViewId, UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::BevyDefault,
+        view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
+    },
+};
+
+use crate::{accumulation::AccumulationCache, ray_tracing::RayTracingGraph};
+
+/// Format of every texture the denoiser filters through
+const SVGF_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+
+/// Pixels along each side of a workgroup of the denoiser's compute passes
+const SVGF_WORKGROUP_SIZE: u32 = 8;
+
+/// Controls the spatiotemporal variance-guided filter denoising ray traced views
+///
+/// Filters the illumination of each frame's sample, with the albedo of the first surface divided
+/// out so textures stay sharp, then multiplies the albedo back in. While it is enabled the view
+/// shows the filtered sample instead of the accumulated average.
+#[derive(Resource, ExtractResource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct SvgfSettings {
+    pub enabled: bool,
+    /// Reprojects last frame's illumination and moments, each frame is filtered on its own
+    /// otherwise
+    pub temporal: bool,
+    /// Estimates the variance of pixels with little history from their neighbours
+    pub variance: bool,
+    /// Runs the edge-avoiding à-trous filter
+    pub filter: bool,
+    /// Least weight of the new sample in the temporal average of the illumination
+    pub alpha: f32,
+    /// Least weight of the new sample in the temporal average of the moments
+    pub moments_alpha: f32,
+    /// À-trous passes, each doubles the distance between the pixels it blends
+    pub iterations: u32,
+    /// How far luminance may differ between blended pixels, relative to its standard deviation
+    pub phi_color: f32,
+    /// Exponent of the normal similarity of blended pixels
+    pub phi_normal: f32,
+    /// How far depth may differ between blended pixels, relative to its gradient
+    pub phi_depth: f32,
+}
+
+impl Default for SvgfSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            temporal: true,
+            variance: true,
+            filter: true,
+            alpha: 0.2,
+            moments_alpha: 0.2,
+            iterations: 5,
+            phi_color: 4.0,
+            phi_normal: 128.0,
+            phi_depth: 1.0,
+        }
+    }
+}
+
+#[derive(ShaderType, Default, Clone)]
+pub struct GpuSvgfSettings {
+    pub temporal: u32,
+    pub variance: u32,
+    pub alpha: f32,
+    pub moments_alpha: f32,
+    pub phi_color: f32,
+    pub phi_normal: f32,
+    pub phi_depth: f32,
+}
+
+/// Uniforms of the denoiser shared by every view
+#[derive(Resource, Default)]
+pub struct SvgfBuffers {
+    pub settings: UniformBuffer<GpuSvgfSettings>,
+    /// Distance between the pixels each à-trous pass blends, at `step_offsets`
+    pub steps: DynamicUniformBuffer<u32>,
+    pub step_offsets: Vec<u32>,
+}
+
+/// The history and filter targets of a view, with the bind groups the passes use them through
+///
+/// `history` holds the temporally averaged illumination and its length in alpha, `moments` the
+/// averaged first and second moments of its luminance. Both swap every frame like the
+/// accumulation textures. The variance pass and the à-trous passes write illumination and
+/// variance to `filter`, back and forth.
+pub struct SvgfTextures {
+    filter: [TextureView; 2],
+    /// Temporal pass for each way round the history is used
+    temporal: [BindGroup; 2],
+    /// Variance pass for each way round the history is used
+    variance: [BindGroup; 2],
+    /// À-trous passes reading from each filter texture
+    atrous: [BindGroup; 2],
+    /// Resolve pass reading from each filter texture
+    resolve: [BindGroup; 2],
+    size: UVec2,
+}
+
+impl SvgfTextures {
+    fn new(render_device: &RenderDevice, pipeline: &SvgfPipeline, size: UVec2) -> Self {
+        let view = |label| {
+            render_device
+                .create_texture(&TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: SVGF_FORMAT,
+                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                })
+                .create_view(&TextureViewDescriptor::default())
+        };
+        let history = [0, 1].map(|_| view("svgf_history"));
+        let moments = [0, 1].map(|_| view("svgf_moments"));
+        let filter = [0, 1].map(|_| view("svgf_filter"));
+        let temporal = [0, 1].map(|write| {
+            render_device.create_bind_group(
+                "svgf_temporal_bind_group",
+                &pipeline.temporal_layout,
+                &BindGroupEntries::sequential((
+                    &history[1 - write],
+                    &moments[1 - write],
+                    &history[write],
+                    &moments[write],
+                )),
+            )
+        });
+        let filter_bind_group = |input, moments, output| {
+            render_device.create_bind_group(
+                "svgf_filter_bind_group",
+                &pipeline.filter_layout,
+                &BindGroupEntries::with_indices(((4, input), (5, moments), (6, output))),
+            )
+        };
+        let variance =
+            [0, 1].map(|write| filter_bind_group(&history[write], &moments[write], &filter[0]));
+        // the à-trous passes don't read the moments, any texture but their output does
+        let atrous =
+            [0, 1].map(|read| filter_bind_group(&filter[read], &moments[0], &filter[1 - read]));
+        let resolve = [0, 1].map(|read| {
+            render_device.create_bind_group(
+                "svgf_resolve_bind_group",
+                &pipeline.resolve_layout,
+                &BindGroupEntries::with_indices(((7, &filter[read]),)),
+            )
+        });
+        Self {
+            filter,
+            temporal,
+            variance,
+            atrous,
+            resolve,
+            size,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct SvgfCache(pub EntityHashMap<SvgfTextures>);
+
+/// Writes the denoiser's uniforms and keeps its textures the size of every ray traced view's
+/// render target, like the accumulation textures it reads, they are dropped while it is disabled
+///
+/// There is no explicit reset of the history. Resized views start from freshly zeroed textures,
+/// and the temporal pass drops history wherever its geometry tests find the surface disoccluded.
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_svgf(
+    mut cache: ResMut<SvgfCache>,
+    mut buffers: ResMut<SvgfBuffers>,
+    settings: Res<SvgfSettings>,
+    pipeline: Res<SvgfPipeline>,
+    views: Query<(Entity, &ExtractedCamera)>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if !settings.enabled {
+        cache.0.clear();
+        return;
+    }
+    let buffers = &mut *buffers;
+    buffers.settings.set(GpuSvgfSettings {
+        temporal: settings.temporal.into(),
+        variance: settings.variance.into(),
+        alpha: settings.alpha,
+        moments_alpha: settings.moments_alpha,
+        phi_color: settings.phi_color,
+        phi_normal: settings.phi_normal,
+        phi_depth: settings.phi_depth,
+    });
+    buffers.settings.write_buffer(&render_device, &render_queue);
+    buffers.steps.clear();
+    buffers.step_offsets = (0..settings.iterations.max(1))
+        .map(|iteration| buffers.steps.push(&(1 << iteration.min(16))))
+        .collect();
+    buffers.steps.write_buffer(&render_device, &render_queue);
+
+    let mut textures = EntityHashMap::default();
+    for (entity, camera) in &views {
+        if camera.render_graph != RayTracingGraph.intern() {
+            continue;
+        }
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        let view_textures = match cache.0.remove(&entity) {
+            Some(view_textures) if view_textures.size == size => view_textures,
+            _ => SvgfTextures::new(&render_device, &pipeline, size),
+        };
+        textures.insert(entity, view_textures);
+    }
+    cache.0 = textures;
+}
+
+/// The denoiser's inputs for a view this frame, along with the bind groups of its passes
+///
+/// `inputs` is bound with the offsets of the view uniform and of the step of the pass.
+#[derive(Component)]
+pub struct SvgfViewBindGroups {
+    pub inputs: BindGroup,
+    pub temporal: BindGroup,
+    pub variance: BindGroup,
+    pub atrous: [BindGroup; 2],
+    pub resolve: [BindGroup; 2],
+}
+
+type SvgfBindGroupKey = ([BufferId; 3], TextureViewId, TextureViewId, TextureViewId);
+
+/// Input bind groups kept as long as the buffers and textures they reference stay the same, one
+/// for each way round the geometry textures are used
+#[derive(Resource, Default)]
+pub struct SvgfBindGroupCache(EntityHashMap<(SvgfBindGroupKey, [BindGroup; 2])>);
+
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_svgf_bind_groups(
+    mut commands: Commands,
+    mut bind_group_cache: ResMut<SvgfBindGroupCache>,
+    cache: Res<SvgfCache>,
+    buffers: Res<SvgfBuffers>,
+    accumulations: Res<AccumulationCache>,
+    views: Query<(Entity, &ViewPrepassTextures)>,
+    view_uniforms: Res<ViewUniforms>,
+    pipeline: Res<SvgfPipeline>,
+    render_device: Res<RenderDevice>,
+) {
+    let (Some(view_buffer), Some(settings_buffer), Some(steps_buffer)) = (
+        view_uniforms.uniforms.buffer(),
+        buffers.settings.buffer(),
+        buffers.steps.buffer(),
+    ) else {
+        return;
+    };
+    let mut bind_groups = EntityHashMap::default();
+    for (entity, prepass_textures) in &views {
+        let (Some(textures), Some(accumulation), Some(motion)) = (
+            cache.0.get(&entity),
+            accumulations.0.get(&entity),
+            prepass_textures.motion_vectors_view(),
+        ) else {
+            continue;
+        };
+        let key = (
+            [view_buffer.id(), settings_buffer.id(), steps_buffer.id()],
+            motion.id(),
+            accumulation.views[0].id(),
+            textures.filter[0].id(),
+        );
+        let inputs = match bind_group_cache.0.remove(&entity) {
+            Some((cached_key, inputs)) if cached_key == key => inputs,
+            _ => [1, 0].map(|read| {
+                render_device.create_bind_group(
+                    "svgf_inputs_bind_group",
+                    &pipeline.inputs_layout,
+                    &BindGroupEntries::sequential((
+                        view_uniforms.uniforms.binding().unwrap(),
+                        buffers.settings.binding().unwrap(),
+                        motion,
+                        &accumulation.radiance,
+                        &accumulation.albedo,
+                        &accumulation.geometry_views[read],
+                        &accumulation.geometry_views[1 - read],
+                        buffers.steps.binding().unwrap(),
+                    )),
+                )
+            }),
+        };
+        let write = accumulation.write_index();
+        commands.entity(entity).insert(SvgfViewBindGroups {
+            inputs: inputs[write].clone(),
+            temporal: textures.temporal[write].clone(),
+            variance: textures.variance[write].clone(),
+            atrous: textures.atrous.clone(),
+            resolve: textures.resolve.clone(),
+        });
+        bind_groups.insert(entity, (key, inputs));
+    }
+    bind_group_cache.0 = bind_groups;
+}
+
+#[derive(Resource)]
+pub struct SvgfPipeline {
+    pub inputs_layout: BindGroupLayout,
+    pub temporal_layout: BindGroupLayout,
+    pub filter_layout: BindGroupLayout,
+    pub resolve_layout: BindGroupLayout,
+    pub temporal_pipeline_id: CachedComputePipelineId,
+    pub variance_pipeline_id: CachedComputePipelineId,
+    pub atrous_pipeline_id: CachedComputePipelineId,
+    /// Resolves to the main texture of LDR and HDR views
+    pub resolve_pipeline_ids: [CachedRenderPipelineId; 2],
+}
+
+impl FromWorld for SvgfPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let shader = world.resource::<AssetServer>().load("shaders/svgf.wgsl");
+        let float = |filterable| texture_2d(TextureSampleType::Float { filterable });
+        let storage = || texture_storage_2d(SVGF_FORMAT, StorageTextureAccess::WriteOnly);
+        let inputs_layout = render_device.create_bind_group_layout(
+            "svgf_inputs_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                (
+                    uniform_buffer::<ViewUniform>(true),
+                    uniform_buffer::<GpuSvgfSettings>(false),
+                    float(true),
+                    float(true),
+                    float(true),
+                    float(true),
+                    float(true),
+                    uniform_buffer::<u32>(true),
+                ),
+            ),
+        );
+        // the passes use different bindings of the second group so they can share a shader
+        let temporal_layout = render_device.create_bind_group_layout(
+            "svgf_temporal_layout",
+            &BindGroupLayoutEntries::with_indices(
+                ShaderStages::COMPUTE,
+                (
+                    (0, float(false)),
+                    (1, float(false)),
+                    (2, storage()),
+                    (3, storage()),
+                ),
+            ),
+        );
+        let filter_layout = render_device.create_bind_group_layout(
+            "svgf_filter_layout",
+            &BindGroupLayoutEntries::with_indices(
+                ShaderStages::COMPUTE,
+                ((4, float(false)), (5, float(false)), (6, storage())),
+            ),
+        );
+        let resolve_layout = render_device.create_bind_group_layout(
+            "svgf_resolve_layout",
+            &BindGroupLayoutEntries::with_indices(ShaderStages::FRAGMENT, ((7, float(false)),)),
+        );
+
+        let pipeline_cache = world.resource_mut::<PipelineCache>();
+        let [temporal_pipeline_id, variance_pipeline_id, atrous_pipeline_id] = [
+            ("temporal", &temporal_layout),
+            ("variance", &filter_layout),
+            ("atrous", &filter_layout),
+        ]
+        .map(|(entry_point, layout)| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("svgf_{entry_point}_pipeline").into()),
+                layout: vec![inputs_layout.clone(), layout.clone()],
+                push_constant_ranges: vec![],
+                shader: shader.clone(),
+                shader_defs: vec![],
+                entry_point: entry_point.into(),
+            })
+        });
+        let resolve_pipeline_ids = [
+            TextureFormat::bevy_default(),
+            ViewTarget::TEXTURE_FORMAT_HDR,
+        ]
+        .map(|format| {
+            pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+                label: Some("svgf_resolve_pipeline".into()),
+                layout: vec![inputs_layout.clone(), resolve_layout.clone()],
+                vertex: fullscreen_shader_vertex_state(),
+                fragment: Some(FragmentState {
+                    shader: shader.clone(),
+                    shader_defs: vec![],
+                    entry_point: "resolve".into(),
+                    targets: vec![Some(ColorTargetState {
+                        format,
+                        blend: None,
+                        write_mask: ColorWrites::ALL,
+                    })],
+                }),
+                push_constant_ranges: vec![],
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+            })
+        });
+        Self {
+            inputs_layout,
+            temporal_layout,
+            filter_layout,
+            resolve_layout,
+            temporal_pipeline_id,
+            variance_pipeline_id,
+            atrous_pipeline_id,
+            resolve_pipeline_ids,
+        }
+    }
+}
+
+/// Denoises the sample the main pass traced this frame and draws it over the view's main texture
+#[derive(Default)]
+pub struct SvgfNode;
+
+impl ViewNode for SvgfNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static SvgfViewBindGroups,
+        &'static ViewUniformOffset,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (camera, target, bind_groups, view_uniform_offset): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let settings = world.resource::<SvgfSettings>();
+        if !settings.enabled {
+            return Ok(());
+        }
+        let svgf_pipeline = world.resource::<SvgfPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let step_offsets = &world.resource::<SvgfBuffers>().step_offsets;
+        let (
+            Some(temporal),
+            Some(variance),
+            Some(atrous),
+            Some(resolve),
+            Some(size),
+            Some(&first_step),
+        ) = (
+            pipeline_cache.get_compute_pipeline(svgf_pipeline.temporal_pipeline_id),
+            pipeline_cache.get_compute_pipeline(svgf_pipeline.variance_pipeline_id),
+            pipeline_cache.get_compute_pipeline(svgf_pipeline.atrous_pipeline_id),
+            pipeline_cache
+                .get_render_pipeline(svgf_pipeline.resolve_pipeline_ids[target.is_hdr() as usize]),
+            camera.physical_viewport_size,
+            step_offsets.first(),
+        )
+        else {
+            return Ok(());
+        };
+
+        let workgroups = (size + SVGF_WORKGROUP_SIZE - 1) / SVGF_WORKGROUP_SIZE;
+        let mut compute_pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("svgf_pass"),
+                    timestamp_writes: None,
+                });
+        let inputs = &bind_groups.inputs;
+        compute_pass.set_pipeline(temporal);
+        compute_pass.set_bind_group(0, inputs, &[view_uniform_offset.offset, first_step]);
+        compute_pass.set_bind_group(1, &bind_groups.temporal, &[]);
+        compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+        compute_pass.set_pipeline(variance);
+        compute_pass.set_bind_group(1, &bind_groups.variance, &[]);
+        compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+        // the variance pass wrote the first filter texture, every à-trous pass the other one
+        let mut read = 0;
+        if settings.filter {
+            compute_pass.set_pipeline(atrous);
+            for &step in step_offsets.iter().take(settings.iterations as usize) {
+                compute_pass.set_bind_group(0, inputs, &[view_uniform_offset.offset, step]);
+                compute_pass.set_bind_group(1, &bind_groups.atrous[read], &[]);
+                compute_pass.dispatch_workgroups(workgroups.x, workgroups.y, 1);
+                read = 1 - read;
+            }
+        }
+        drop(compute_pass);
+
+        let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("svgf_resolve_pass"),
+            color_attachments: &[Some(target.get_color_attachment())],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            render_pass.set_camera_viewport(viewport);
+        }
+        render_pass.set_render_pipeline(resolve);
+        render_pass.set_bind_group(0, inputs, &[view_uniform_offset.offset, first_step]);
+        render_pass.set_bind_group(1, &bind_groups.resolve[read], &[]);
+        render_pass.draw(0..3, 0..1);
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            AddressMode, Extent3d, FilterMode, ImageCopyTexture, ImageDataLayout, Origin3d,
+            Sampler, SamplerDescriptor, Texture, TextureAspect, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureUsages, TextureView, TextureViewDescriptor,
+            TextureViewDimension,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::{HashMap, HashSet},
+};
+use image::{imageops::FilterType, ImageBuffer, Rgba};
+
+/// Width and height every texture is resampled to so they fit into a single array
+pub const TEXTURE_SIZE: u32 = 1024;
+/// Most layers an array texture is guaranteed to support
+///
+/// Layers have a single mip, rays have no screen space footprint the shader could pick a coarser
+/// one by, so a full array takes `TEXTURE_SIZE² × 4` bytes per layer, 1 GiB in total.
+pub const MAX_TEXTURES: usize = 256;
+/// Texture index of a material slot without a texture
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// Images packed as the layers of one array texture that persists between frames
+///
+/// Layers keep the bytes of their image as is, the shader knows which slots hold sRGB colors.
+/// Images are resampled to layers when they are uploaded, in the render world's prepare stage
+/// rather than while extracting.
+#[derive(Default)]
+pub struct TextureArray {
+    layers: Vec<Vec<u8>>,
+    /// Images of layers that haven't been resampled yet
+    pending: HashMap<usize, Image>,
+    dirty: HashSet<usize>,
+    texture: Option<Texture>,
+    view: Option<TextureView>,
+    sampler: Option<Sampler>,
+    capacity: usize,
+}
+
+impl TextureArray {
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Sets the image of the layer at `index`, which may be one past the end to push it
+    ///
+    /// Its format has to be one [`can_resample`] accepts.
+    pub fn set(&mut self, index: usize, image: Image) {
+        if index == self.layers.len() {
+            self.layers.push(Vec::new());
+        }
+        self.pending.insert(index, image);
+    }
+
+    /// Resamples the images set since the last call
+    pub fn resample(&mut self) {
+        for (index, image) in self.pending.drain() {
+            self.layers[index] = texture_layer(&image).unwrap_or_else(|| {
+                warn!("Texture data doesn't match its format, it is left white");
+                vec![u8::MAX; (TEXTURE_SIZE * TEXTURE_SIZE * 4) as usize]
+            });
+            self.dirty.insert(index);
+        }
+    }
+
+    /// RGBA8 texels of a layer, rows first, once it has been resampled
+    pub fn layer(&self, index: usize) -> Option<&[u8]> {
+        self.layers
+            .get(index)
+            .map(Vec::as_slice)
+            .filter(|layer| !layer.is_empty())
+    }
+
+    /// Resamples and uploads the changed layers, returns true if the texture was reallocated
+    pub fn write_texture(&mut self, device: &RenderDevice, queue: &RenderQueue) -> bool {
+        self.resample();
+        let reallocated = self.texture.is_none() || self.layers.len() > self.capacity;
+        if reallocated {
+            self.capacity = self.layers.len().max(1).next_power_of_two();
+            let texture = device.create_texture(&TextureDescriptor {
+                label: Some("ray_tracing_textures"),
+                size: Extent3d {
+                    width: TEXTURE_SIZE,
+                    height: TEXTURE_SIZE,
+                    depth_or_array_layers: self.capacity as u32,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            self.view = Some(texture.create_view(&TextureViewDescriptor {
+                label: Some("ray_tracing_textures_view"),
+                dimension: Some(TextureViewDimension::D2Array),
+                ..default()
+            }));
+            self.texture = Some(texture);
+            self.dirty.extend(0..self.layers.len());
+        }
+        if self.sampler.is_none() {
+            self.sampler = Some(device.create_sampler(&SamplerDescriptor {
+                label: Some("ray_tracing_texture_sampler"),
+                address_mode_u: AddressMode::Repeat,
+                address_mode_v: AddressMode::Repeat,
+                mag_filter: FilterMode::Linear,
+                min_filter: FilterMode::Linear,
+                ..default()
+            }));
+        }
+        if let Some(texture) = &self.texture {
+            for index in self.dirty.drain() {
+                queue.write_texture(
+                    ImageCopyTexture {
+                        texture,
+                        mip_level: 0,
+                        origin: Origin3d {
+                            x: 0,
+                            y: 0,
+                            z: index as u32,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    &self.layers[index],
+                    ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(TEXTURE_SIZE * 4),
+                        rows_per_image: Some(TEXTURE_SIZE),
+                    },
+                    Extent3d {
+                        width: TEXTURE_SIZE,
+                        height: TEXTURE_SIZE,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+        reallocated
+    }
+
+    pub fn view(&self) -> Option<&TextureView> {
+        self.view.as_ref()
+    }
+
+    pub fn sampler(&self) -> Option<&Sampler> {
+        self.sampler.as_ref()
+    }
+}
+
+/// Whether images of a format can be resampled to layers
+pub fn can_resample(format: TextureFormat) -> bool {
+    matches!(
+        format,
+        TextureFormat::R8Unorm
+            | TextureFormat::Rg8Unorm
+            | TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+            | TextureFormat::Bgra8Unorm
+            | TextureFormat::Bgra8UnormSrgb
+    )
+}
+
+/// Resamples the first mip of an image to a layer of [`TEXTURE_SIZE`] RGBA8 texels
+fn texture_layer(image: &Image) -> Option<Vec<u8>> {
+    let size = image.size();
+    let texels = (size.x * size.y) as usize;
+    let rgba = match image.texture_descriptor.format {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            ImageBuffer::<Rgba<u8>, _>::from_raw(
+                size.x,
+                size.y,
+                image.data.get(..texels * 4)?.to_vec(),
+            )?
+        }
+        _ => image.clone().try_into_dynamic().ok()?.into_rgba8(),
+    };
+    if size == UVec2::splat(TEXTURE_SIZE) {
+        return Some(rgba.into_raw());
+    }
+    let resized = image::imageops::resize(&rgba, TEXTURE_SIZE, TEXTURE_SIZE, FilterType::Triangle);
+    Some(resized.into_raw())
+}
@@ -0,0 +1,443 @@
+//! Ray traces canonical scenes on the GPU and compares them to the references in `tests/golden`
+//!
+//! The references are rendered by the CPU reference tracer in [`ray_tracing::cpu_raytracing`],
+//! not by an adapter, so every test checks the GPU against it. The checked in ones were rendered
+//! on x86_64 Linux. Run with `GOLDEN_BLESS=1`, preferably with `--release`, to render them again
+//! from the current CPU tracer, which needs no GPU.
+//!
+//! Renders that don't match are written next to an image of where they differ in
+//! `target/tmp/golden`. Adapters that can't run the ray tracing pass, like llvmpipe's OpenGL,
+//! fail the tests unless `GOLDEN_ALLOW_SKIP` is set.
+
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use bevy::{
+    app::PluginsState,
+    core_pipeline::tonemapping::{DebandDither, Tonemapping},
+    log::LogPlugin,
+    prelude::*,
+    render::{
+        camera::{CameraRenderGraph, RenderTarget},
+        renderer::{RenderAdapter, RenderAdapterInfo},
+        view::VisibilityPlugin,
+    },
+};
+use image::{GrayImage, Luma, Rgb, Rgb32FImage, RgbImage};
+use ray_tracing::{
+    cpu_raytracing::{self, CpuView},
+    demo::spawn_demo_scene,
+    offline::{headless_plugins, OfflineRender, OfflineRenderPlugin},
+    ray_tracing::{RayTracingGraph, RayTracingPlugin},
+    sky::PhysicalSky,
+};
+
+const SIZE: UVec2 = UVec2::new(256, 144);
+/// Samples per pixel of both the GPU renders and the references, enough that little noise is
+/// left to tell them apart
+const SAMPLES: u32 = 1024;
+/// Lowest mean SSIM a render may have against its reference, leaving room for the noise left in
+/// both and for drivers rounding differently
+const MIN_SSIM: f32 = 0.98;
+/// Bytes per sample of the main pass's color attachments, more than OpenGL allows
+const ATTACHMENT_BYTES_PER_SAMPLE: u32 = 48;
+/// Renders that haven't finished by then fail
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Apps are rendered one at a time, they would only compete for the GPU
+static GPU: Mutex<()> = Mutex::new(());
+
+#[test]
+fn demo() {
+    golden(
+        "demo",
+        |mut commands, mut meshes, mut materials, request| {
+            commands.spawn(camera(&request, Vec3::new(0.0, 3.0, 5.0), Vec3::ZERO));
+            spawn_demo_scene(&mut commands, &mut meshes, &mut materials);
+        },
+    );
+}
+
+#[test]
+fn emissive_box() {
+    golden(
+        "emissive_box",
+        |mut commands, mut meshes, mut materials, request| {
+            commands.insert_resource(AmbientLight::NONE);
+            commands.spawn(camera(&request, Vec3::new(0.0, 1.0, 3.5), Vec3::Y));
+            let white = materials.add(Color::srgb(0.73, 0.73, 0.73));
+            let red = materials.add(Color::srgb(0.65, 0.05, 0.05));
+            let green = materials.add(Color::srgb(0.12, 0.45, 0.15));
+            let wall = meshes.add(Plane3d::new(Vec3::Y, Vec2::ONE));
+            let walls = [
+                (Vec3::ZERO, Quat::IDENTITY, white.clone()),
+                (Vec3::Y * 2.0, Quat::from_rotation_x(PI), white.clone()),
+                (
+                    Vec3::new(0.0, 1.0, -1.0),
+                    Quat::from_rotation_x(FRAC_PI_2),
+                    white,
+                ),
+                (
+                    Vec3::new(-1.0, 1.0, 0.0),
+                    Quat::from_rotation_z(-FRAC_PI_2),
+                    red,
+                ),
+                (
+                    Vec3::new(1.0, 1.0, 0.0),
+                    Quat::from_rotation_z(FRAC_PI_2),
+                    green,
+                ),
+            ];
+            for (translation, rotation, material) in walls {
+                commands.spawn(PbrBundle {
+                    mesh: wall.clone(),
+                    material,
+                    transform: Transform::from_translation(translation).with_rotation(rotation),
+                    ..default()
+                });
+            }
+            commands.spawn(PbrBundle {
+                mesh: meshes.add(Plane3d::new(Vec3::NEG_Y, Vec2::splat(0.25))),
+                material: materials.add(StandardMaterial {
+                    base_color: Color::BLACK,
+                    emissive: LinearRgba::rgb(17.0, 12.0, 4.0),
+                    ..default()
+                }),
+                transform: Transform::from_xyz(0.0, 1.99, 0.0),
+                ..default()
+            });
+            commands.spawn(PbrBundle {
+                mesh: meshes.add(Cuboid::new(0.6, 1.2, 0.6)),
+                material: materials.add(Color::srgb(0.73, 0.73, 0.73)),
+                transform: Transform::from_xyz(-0.35, 0.6, -0.3)
+                    .with_rotation(Quat::from_rotation_y(0.3)),
+                ..default()
+            });
+        },
+    );
+}
+
+#[test]
+fn materials() {
+    golden(
+        "materials",
+        |mut commands, mut meshes, mut materials, request| {
+            commands.spawn((
+                camera(&request, Vec3::new(0.0, 1.5, 4.0), Vec3::new(0.0, 0.5, 0.0)),
+                PhysicalSky::default(),
+            ));
+            commands.spawn(PbrBundle {
+                mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(5.0))),
+                material: materials.add(Color::srgb(0.5, 0.5, 0.5)),
+                ..default()
+            });
+            let sphere = meshes.add(Sphere::new(0.5).mesh());
+            let spheres = [
+                StandardMaterial {
+                    base_color: Color::WHITE,
+                    specular_transmission: 1.0,
+                    ior: 1.5,
+                    perceptual_roughness: 0.0,
+                    ..default()
+                },
+                StandardMaterial {
+                    base_color: Color::srgb(1.0, 0.8, 0.4),
+                    metallic: 1.0,
+                    perceptual_roughness: 0.3,
+                    ..default()
+                },
+                StandardMaterial {
+                    base_color: Color::srgb(0.2, 0.3, 0.8),
+                    perceptual_roughness: 0.6,
+                    ..default()
+                },
+            ];
+            for (x, material) in [-1.2, 0.0, 1.2].into_iter().zip(spheres) {
+                commands.spawn(PbrBundle {
+                    mesh: sphere.clone(),
+                    material: materials.add(material),
+                    transform: Transform::from_xyz(x, 0.5, 0.0),
+                    ..default()
+                });
+            }
+            commands.spawn(DirectionalLightBundle {
+                transform: Transform::from_xyz(1.0, 2.0, 1.0).looking_at(Vec3::ZERO, Vec3::Y),
+                ..default()
+            });
+            commands.spawn(SpotLightBundle {
+                transform: Transform::from_xyz(-2.0, 3.0, 2.0).looking_at(Vec3::ZERO, Vec3::Y),
+                spot_light: SpotLight {
+                    intensity: 4_000_000.0,
+                    radius: 0.2,
+                    ..default()
+                },
+                ..default()
+            });
+        },
+    );
+}
+
+type Scene =
+    fn(Commands, ResMut<Assets<Mesh>>, ResMut<Assets<StandardMaterial>>, Res<OfflineRender>);
+
+/// Ray traced camera at `position` rendering linear radiance to the test's image
+fn camera(request: &OfflineRender, position: Vec3, look_at: Vec3) -> Camera3dBundle {
+    Camera3dBundle {
+        camera: Camera {
+            hdr: true,
+            target: RenderTarget::Image(request.image.clone()),
+            ..default()
+        },
+        camera_render_graph: CameraRenderGraph::new(RayTracingGraph),
+        transform: Transform::from_translation(position).looking_at(look_at, Vec3::Y),
+        tonemapping: Tonemapping::None,
+        deband_dither: DebandDither::Disabled,
+        ..default()
+    }
+}
+
+fn golden(name: &str, scene: Scene) {
+    let reference_path = reference(name);
+    if std::env::var_os("GOLDEN_BLESS").is_some() {
+        std::fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        tonemap(&cpu_render(scene)).save(&reference_path).unwrap();
+        return;
+    }
+    let Ok(reference) = image::open(&reference_path) else {
+        panic!(
+            "{} is missing, render it with GOLDEN_BLESS=1",
+            reference_path.display()
+        );
+    };
+
+    let _gpu = GPU.lock().unwrap_or_else(PoisonError::into_inner);
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let output = output_dir.join(format!("{name}.exr"));
+
+    let mut app = App::new();
+    app.add_plugins((
+        headless_plugins().disable::<LogPlugin>(),
+        RayTracingPlugin,
+        OfflineRenderPlugin,
+    ));
+    let image = app
+        .world_mut()
+        .resource_mut::<Assets<Image>>()
+        .add(OfflineRender::target(SIZE, true));
+    // the scenes only use meshes built in place, so nothing has to load first
+    app.insert_resource(OfflineRender {
+        image,
+        samples: SAMPLES,
+        output: output.clone(),
+        ready: true,
+    })
+    .add_systems(Startup, scene)
+    .add_systems(Update, time_out);
+
+    while app.plugins_state() == PluginsState::Adding {
+        bevy::tasks::tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+    let limits = app.world().resource::<RenderAdapter>().limits();
+    if limits.max_color_attachment_bytes_per_sample < ATTACHMENT_BYTES_PER_SAMPLE {
+        let adapter = &app.world().resource::<RenderAdapterInfo>().name;
+        assert!(
+            std::env::var_os("GOLDEN_ALLOW_SKIP").is_some(),
+            "{adapter} can't run the ray tracing pass, set GOLDEN_ALLOW_SKIP=1 to skip {name}"
+        );
+        eprintln!("skipping {name}, {adapter} can't run the ray tracing pass");
+        return;
+    }
+    assert!(app.run().is_success(), "rendering {name} failed");
+
+    let actual = tonemap(&image::open(&output).unwrap().to_rgb32f());
+    let actual_path = output_dir.join(format!("{name}.png"));
+    actual.save(&actual_path).unwrap();
+    let reference = reference.to_rgb8();
+    assert_eq!(
+        actual.dimensions(),
+        reference.dimensions(),
+        "{name} changed size"
+    );
+    let (score, map) = ssim(&luma(&actual), &luma(&reference));
+    if score < MIN_SSIM {
+        let diff_path = output_dir.join(format!("{name}.diff.png"));
+        diff_image(&map, actual.width()).save(&diff_path).unwrap();
+        panic!(
+            "{name} has a SSIM of {score:.4} against its reference, below {MIN_SSIM}, see {} and {}",
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+/// Traces a scene with the CPU reference tracer, returning its exposed linear radiance
+fn cpu_render(scene: Scene) -> Rgb32FImage {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        TransformPlugin,
+        HierarchyPlugin,
+        VisibilityPlugin,
+    ))
+    .init_asset::<Mesh>()
+    .init_asset::<StandardMaterial>()
+    .init_asset::<Image>()
+    .insert_resource(OfflineRender {
+        image: Handle::default(),
+        samples: SAMPLES,
+        output: PathBuf::new(),
+        ready: true,
+    })
+    .add_systems(Startup, scene);
+    app.finish();
+    app.cleanup();
+    // spawns the scene and propagates its transforms and visibility
+    app.update();
+
+    let world = app.world_mut();
+    let camera = world.query_filtered::<Entity, With<Camera>>().single(world);
+    let info = cpu_raytracing::extract_scene(world);
+    let view = CpuView::extract(world, camera, SIZE).unwrap();
+    let image = cpu_raytracing::render(&info, &view, SAMPLES);
+    let texel = |x: u32, y: u32, channel: u32| {
+        let offset = (((y * SIZE.x + x) * 4 + channel) * 4) as usize;
+        f32::from_le_bytes(image.data[offset..offset + 4].try_into().unwrap())
+    };
+    Rgb32FImage::from_fn(SIZE.x, SIZE.y, |x, y| {
+        Rgb([0, 1, 2].map(|channel| texel(x, y, channel)))
+    })
+}
+
+/// Maps exposed radiance to sRGB with a Reinhard curve, the same for the renders and the
+/// references
+fn tonemap(image: &Rgb32FImage) -> RgbImage {
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b] = image
+            .get_pixel(x, y)
+            .0
+            .map(|c| c.max(0.0) / (1.0 + c.max(0.0)));
+        let [r, g, b, _] = Srgba::from(LinearRgba::rgb(r, g, b)).to_u8_array();
+        Rgb([r, g, b])
+    })
+}
+
+fn reference(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.png"))
+}
+
+fn time_out(time: Res<Time<Real>>, mut app_exit: EventWriter<AppExit>) {
+    if time.elapsed() > TIMEOUT {
+        app_exit.send(AppExit::error());
+    }
+}
+
+/// Gamma encoded luma in [0, 1], which SSIM is usually measured on
+fn luma(image: &RgbImage) -> GrayImage32 {
+    GrayImage32 {
+        width: image.width() as usize,
+        data: image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0.map(|c| c as f32 / 255.0);
+                0.299 * r + 0.587 * g + 0.114 * b
+            })
+            .collect(),
+    }
+}
+
+struct GrayImage32 {
+    width: usize,
+    data: Vec<f32>,
+}
+
+impl GrayImage32 {
+    fn map(&self, f: impl Fn(f32) -> f32) -> Self {
+        Self {
+            width: self.width,
+            data: self.data.iter().copied().map(f).collect(),
+        }
+    }
+
+    fn zip(&self, other: &Self, f: impl Fn(f32, f32) -> f32) -> Self {
+        Self {
+            width: self.width,
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+        }
+    }
+
+    /// Gaussian blur with a standard deviation of 1.5 pixels, clamping at the borders
+    fn blur(&self) -> Self {
+        const RADIUS: isize = 5;
+        let weights: Vec<f32> = (-RADIUS..=RADIUS)
+            .map(|x| (-(x * x) as f32 / (2.0 * 1.5 * 1.5)).exp())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        let height = self.data.len() / self.width;
+        let pass = |data: &[f32], step: (isize, isize)| -> Vec<f32> {
+            (0..data.len())
+                .map(|index| {
+                    let (x, y) = ((index % self.width) as isize, (index / self.width) as isize);
+                    (-RADIUS..=RADIUS)
+                        .zip(&weights)
+                        .map(|(offset, weight)| {
+                            let sx = (x + offset * step.0).clamp(0, self.width as isize - 1);
+                            let sy = (y + offset * step.1).clamp(0, height as isize - 1);
+                            data[sy as usize * self.width + sx as usize] * weight
+                        })
+                        .sum::<f32>()
+                        / total
+                })
+                .collect()
+        };
+        Self {
+            width: self.width,
+            data: pass(&pass(&self.data, (1, 0)), (0, 1)),
+        }
+    }
+}
+
+/// Mean structural similarity of two images and its value at every pixel (Wang et al. 2004)
+fn ssim(a: &GrayImage32, b: &GrayImage32) -> (f32, Vec<f32>) {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let mean_a = a.blur();
+    let mean_b = b.blur();
+    let square_a = a.map(|x| x * x).blur();
+    let square_b = b.map(|x| x * x).blur();
+    let product = a.zip(b, |x, y| x * y).blur();
+    let map: Vec<f32> = (0..a.data.len())
+        .map(|i| {
+            let (mu_a, mu_b) = (mean_a.data[i], mean_b.data[i]);
+            let variance_a = square_a.data[i] - mu_a * mu_a;
+            let variance_b = square_b.data[i] - mu_b * mu_b;
+            let covariance = product.data[i] - mu_a * mu_b;
+            (2.0 * mu_a * mu_b + C1) * (2.0 * covariance + C2)
+                / ((mu_a * mu_a + mu_b * mu_b + C1) * (variance_a + variance_b + C2))
+        })
+        .collect();
+    let mean = map.iter().sum::<f32>() / map.len() as f32;
+    (mean, map)
+}
+
+/// White where the render differs from its reference
+fn diff_image(map: &[f32], width: u32) -> GrayImage {
+    GrayImage::from_fn(width, map.len() as u32 / width, |x, y| {
+        let similarity = map[(y * width + x) as usize];
+        Luma([((1.0 - similarity).clamp(0.0, 1.0) * 255.0) as u8])
+    })
+}